@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A problem with the source, and the line it is on counting from 1
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// An assembled program
+#[derive(Clone, Debug)]
+pub struct Assembly {
+    /// Where the first byte of binary is loaded
+    pub origin: u16,
+    pub binary: Vec<u8>,
+    /// The address of every label
+    pub labels: BTreeMap<String, u16>,
+    /// The address each source line was assembled to, as (address, line)
+    pub lines: Vec<(u16, usize)>,
+}
+
+impl Assembly {
+    /// The first source line assembled to address
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.lines.iter().find(|&&(line_address, _)| line_address == address).map(|&(_, line)| line)
+    }
+    /// The address the code of a source line was assembled to
+    pub fn address_of_line(&self, line: usize) -> Option<u16> {
+        self.lines.iter().find(|&&(_, source_line)| source_line == line).map(|&(address, _)| address)
+    }
+}
+
+struct Statement<'a> {
+    line: usize,
+    address: u16,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, AssembleError> {
+    Err(AssembleError {
+        line: line,
+        message: message,
+    })
+}
+
+/// Splits off a comment and a leading label, returning (label, rest)
+fn split_line(text: &str) -> (Option<&str>, &str) {
+    let text = match text.find(';') {
+        Some(index) => &text[..index],
+        None => text,
+    };
+    let text = text.trim();
+    if let Some(index) = text.find(':') {
+        let label = text[..index].trim();
+        if !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return (Some(label), text[index + 1..].trim());
+        }
+    }
+    (None, text)
+}
+
+fn split_statement(text: &str) -> (&str, Vec<&str>) {
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(index) => (&text[..index], text[index..].trim()),
+        None => (text, ""),
+    };
+    let operands = if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(|operand| operand.trim()).collect()
+    };
+    (mnemonic, operands)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (digits, radix) = if text.starts_with('#') || text.starts_with('$') {
+        (&text[1..], 16)
+    } else if text.starts_with("0x") || text.starts_with("0X") {
+        (&text[2..], 16)
+    } else if text.starts_with('%') {
+        (&text[1..], 2)
+    } else if text.starts_with("0b") {
+        (&text[2..], 2)
+    } else {
+        (text, 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// Evaluates sums and differences of numbers and symbols
+fn evaluate(text: &str, symbols: &BTreeMap<String, i64>) -> Result<i64, String> {
+    let mut total: i64 = 0;
+    let mut sign: i64 = 1;
+    let mut term = String::new();
+    for c in text.chars().chain(Some('+')) {
+        if (c == '+' || c == '-') && !term.trim().is_empty() {
+            let name = term.trim();
+            let value = match parse_number(name) {
+                Some(value) => value,
+                None => {
+                    match symbols.get(&name.to_uppercase()) {
+                        Some(value) => *value,
+                        None => return Err(format!("unknown symbol {}", name)),
+                    }
+                }
+            };
+            total = match sign.checked_mul(value).and_then(|value| total.checked_add(value)) {
+                Some(total) => total,
+                None => return Err(format!("{} is too large", text.trim())),
+            };
+            sign = if c == '-' { -1 } else { 1 };
+            term.clear();
+        } else if c == '-' {
+            sign = -sign;
+        } else if c != '+' {
+            term.push(c);
+        }
+    }
+    Ok(total)
+}
+
+fn register(operand: &str) -> Option<u8> {
+    let operand = operand.to_uppercase();
+    if operand.len() == 2 && operand.starts_with('V') {
+        u8::from_str_radix(&operand[1..], 16).ok()
+    } else {
+        None
+    }
+}
+
+/// How many bytes a statement takes up
+fn size(statement: &Statement) -> Result<u16, AssembleError> {
+    match &statement.mnemonic[..] {
+        "DB" => Ok(statement.operands.len() as u16),
+        "DW" => Ok(statement.operands.len() as u16 * 2),
+        "LDL" => Ok(4),
+        _ => Ok(2),
+    }
+}
+
+fn encode(statement: &Statement, symbols: &BTreeMap<String, i64>)
+    -> Result<Vec<u8>, AssembleError> {
+    use Instruction::*;
+    let line = statement.line;
+    let operands = &statement.operands;
+    let value = |index: usize, max: i64| -> Result<u16, AssembleError> {
+        let text = match operands.get(index) {
+            Some(text) => text,
+            None => return error(line, format!("{} is missing an operand", statement.mnemonic)),
+        };
+        match evaluate(text, symbols) {
+            Ok(value) if value >= 0 && value <= max => Ok(value as u16),
+            Ok(value) => error(line, format!("{} doesn't fit in {} bits", value, bits(max))),
+            Err(message) => error(line, message),
+        }
+    };
+    let reg = |index: usize| -> Result<u8, AssembleError> {
+        match operands.get(index).and_then(|operand| register(operand)) {
+            Some(x) => Ok(x),
+            None => error(line, format!("{} expects a register", statement.mnemonic)),
+        }
+    };
+    let word = |index: usize| -> Option<String> {
+        operands.get(index).map(|operand| operand.to_uppercase())
+    };
+    let expect_operands = |count: usize| -> Result<(), AssembleError> {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            error(line, format!("{} takes {} operands", statement.mnemonic, count))
+        }
+    };
+    let instruction = match &statement.mnemonic[..] {
+        "DB" => {
+            let mut bytes = Vec::new();
+            for index in 0..operands.len() {
+                bytes.push(try!(value(index, 0xFF)) as u8);
+            }
+            return Ok(bytes);
+        }
+        "DW" => {
+            let mut bytes = Vec::new();
+            for index in 0..operands.len() {
+                let word = try!(value(index, 0xFFFF));
+                bytes.push((word >> 8) as u8);
+                bytes.push(word as u8);
+            }
+            return Ok(bytes);
+        }
+        "SYS" => Sys(try!(value(0, 0xFFF))),
+        "CLS" => Cls,
+        "RET" => Ret,
+        "SCD" => ScrollDown(try!(value(0, 0xF)) as u8),
+        "SCU" => ScrollUp(try!(value(0, 0xF)) as u8),
+        "SCR" => ScrollRight,
+        "SCL" => ScrollLeft,
+        "EXIT" => Exit,
+        "LOW" => Lores,
+        "HIGH" => Hires,
+        "JP" if operands.len() == 2 => {
+            if word(0) != Some("V0".to_string()) {
+                return error(line, "JP can only add V0".to_string());
+            }
+            JpV0(try!(value(1, 0xFFF)))
+        }
+        "JP" => Jp(try!(value(0, 0xFFF))),
+        "CALL" => Call(try!(value(0, 0xFFF))),
+        "SE" | "SNE" => {
+            try!(expect_operands(2));
+            let x = try!(reg(0));
+            match (register(operands[1]), &statement.mnemonic[..]) {
+                (Some(y), "SE") => SeReg { x: x, y: y },
+                (Some(y), _) => SneReg { x: x, y: y },
+                (None, "SE") => SeByte { x: x, byte: try!(value(1, 0xFF)) as u8 },
+                (None, _) => SneByte { x: x, byte: try!(value(1, 0xFF)) as u8 },
+            }
+        }
+        "SAVE" => SaveRange { x: try!(reg(0)), y: try!(reg(1)) },
+        "LOAD" => LoadRange { x: try!(reg(0)), y: try!(reg(1)) },
+        "OR" => Or { x: try!(reg(0)), y: try!(reg(1)) },
+        "AND" => And { x: try!(reg(0)), y: try!(reg(1)) },
+        "XOR" => Xor { x: try!(reg(0)), y: try!(reg(1)) },
+        "SUB" => Sub { x: try!(reg(0)), y: try!(reg(1)) },
+        "SUBN" => Subn { x: try!(reg(0)), y: try!(reg(1)) },
+        "SHR" | "SHL" => {
+            let x = try!(reg(0));
+            let y = if operands.len() > 1 { try!(reg(1)) } else { 0 };
+            if statement.mnemonic == "SHR" {
+                Shr { x: x, y: y }
+            } else {
+                Shl { x: x, y: y }
+            }
+        }
+        "ADD" => {
+            try!(expect_operands(2));
+            if word(0) == Some("I".to_string()) {
+                AddI(try!(reg(1)))
+            } else {
+                let x = try!(reg(0));
+                match register(operands[1]) {
+                    Some(y) => Add { x: x, y: y },
+                    None => AddByte { x: x, byte: try!(value(1, 0xFF)) as u8 },
+                }
+            }
+        }
+        "RND" => Rnd { x: try!(reg(0)), byte: try!(value(1, 0xFF)) as u8 },
+        "DRW" => Drw { x: try!(reg(0)), y: try!(reg(1)), n: try!(value(2, 0xF)) as u8 },
+        "SKP" => Skp(try!(reg(0))),
+        "SKNP" => Sknp(try!(reg(0))),
+        "LDL" => {
+            if word(0) != Some("I".to_string()) {
+                return error(line, "LDL can only load I".to_string());
+            }
+            LdLongI(try!(value(1, 0xFFFF)))
+        }
+        "PLANE" => Plane(try!(value(0, 3)) as u8),
+        "AUDIO" => Audio,
+        "PITCH" => Pitch(try!(reg(0))),
+        "LD" => {
+            try!(expect_operands(2));
+            let (destination, source) = (word(0).unwrap(), word(1).unwrap());
+            match (&destination[..], &source[..]) {
+                ("I", _) => LdI(try!(value(1, 0xFFF))),
+                ("DT", _) => LdDtVx(try!(reg(1))),
+                ("ST", _) => LdStVx(try!(reg(1))),
+                ("F", _) => LdF(try!(reg(1))),
+                ("HF", _) => LdHf(try!(reg(1))),
+                ("B", _) => LdB(try!(reg(1))),
+                ("[I]", _) => LdIVx(try!(reg(1))),
+                ("R", _) => LdRVx(try!(reg(1))),
+                (_, "DT") => LdVxDt(try!(reg(0))),
+                (_, "K") => LdVxK(try!(reg(0))),
+                (_, "[I]") => LdVxI(try!(reg(0))),
+                (_, "R") => LdVxR(try!(reg(0))),
+                _ => {
+                    let x = try!(reg(0));
+                    match register(&source) {
+                        Some(y) => LdReg { x: x, y: y },
+                        None => LdByte { x: x, byte: try!(value(1, 0xFF)) as u8 },
+                    }
+                }
+            }
+        }
+        mnemonic => return error(line, format!("unknown mnemonic {}", mnemonic)),
+    };
+    Ok(instruction.to_bytes())
+}
+
+fn bits(max: i64) -> u32 {
+    64 - max.leading_zeros()
+}
+
+/// Assembles Cowgod style source, the syntax disassembler::Syntax::Cowgod writes
+///
+/// Besides instructions the source can hold `label:` definitions, `NAME EQU value` constants,
+/// `ORG address`, `DB` and `DW` data and comments starting with `;`.
+pub fn assemble(source: &str) -> Result<Assembly, AssembleError> {
+    let mut symbols = BTreeMap::new();
+    let mut labels = BTreeMap::new();
+    let mut statements = Vec::new();
+    let mut origin = None;
+    let mut address: i64 = 0x200;
+    // The first pass finds the address of everything
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let (label, rest) = split_line(text);
+        if let Some(label) = label {
+            if symbols.insert(label.to_uppercase(), address).is_some() {
+                return error(line, format!("{} is already defined", label));
+            }
+            labels.insert(label.to_string(), address as u16);
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = split_statement(rest);
+        let mut words = rest.split_whitespace();
+        let name = words.next().unwrap();
+        if words.next().map(|word| word.to_uppercase()) == Some("EQU".to_string()) {
+            let expression = rest.splitn(2, char::is_whitespace).nth(1).unwrap().trim()[3..].trim();
+            let value = match evaluate(expression, &symbols) {
+                Ok(value) => value,
+                Err(message) => return error(line, message),
+            };
+            if symbols.insert(name.to_uppercase(), value).is_some() {
+                return error(line, format!("{} is already defined", name));
+            }
+            continue;
+        }
+        let mnemonic = mnemonic.to_uppercase();
+        if mnemonic == "ORG" {
+            let new_address = match operands.get(0).map(|text| evaluate(text, &symbols)) {
+                Some(Ok(value)) => value,
+                Some(Err(message)) => return error(line, message),
+                None => return error(line, "ORG needs an address".to_string()),
+            };
+            if new_address < 0 || new_address > 0xFFFF {
+                return error(line, format!("ORG {} is outside memory", new_address));
+            }
+            if new_address < address && origin.is_some() {
+                return error(line, format!("ORG #{:X} is behind the code before it", new_address));
+            }
+            address = new_address;
+            continue;
+        }
+        if origin.is_none() {
+            origin = Some(address);
+        }
+        let statement = Statement {
+            line: line,
+            address: address as u16,
+            mnemonic: mnemonic,
+            operands: operands,
+        };
+        address += try!(size(&statement)) as i64;
+        if address > 0x10000 {
+            return error(line, "the program doesn't fit in memory".to_string());
+        }
+        statements.push(statement);
+    }
+    let origin = origin.unwrap_or(address) as u16;
+    let mut binary = Vec::new();
+    let mut lines = Vec::new();
+    for statement in &statements {
+        let bytes = try!(encode(statement, &symbols));
+        let offset = (statement.address - origin) as usize;
+        binary.resize(offset, 0);
+        binary.extend_from_slice(&bytes);
+        lines.push((statement.address, statement.line));
+    }
+    Ok(Assembly {
+        origin: origin,
+        binary: binary,
+        labels: labels,
+        lines: lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use Mode;
+    use disassembler::{self, Syntax};
+    use octo;
+    use super::assemble;
+
+    /// Every chip8 instruction, with the jumps and calls landing on instructions
+    const CHIP8: &'static [u16] = &[
+        0x00E0, 0x2206, 0x1248, 0x00EE, 0x3A12, 0x4B34, 0x5AB0, 0x6C56, 0x7D78, 0x8AB0, 0x8AB1, 0x8AB2,
+        0x8AB3, 0x8AB4, 0x8AB5, 0x8AB6, 0x8AB7, 0x8ABE, 0x9AB0, 0xA123, 0xB204, 0xC0FF, 0xD125, 0xE19E,
+        0xE2A1, 0xF307, 0xF40A, 0xF515, 0xF618, 0xF71E, 0xF829, 0xF933, 0xFA55, 0xFB65, 0x1FF0, 0xFFFF,
+        0x1203,
+    ];
+    /// The SUPER-CHIP and XO-CHIP additions
+    const XO_CHIP: &'static [u16] = &[
+        0x00C4, 0x00D3, 0x00FB, 0x00FC, 0x00FE, 0x00FF, 0xD120, 0xF130, 0xF275, 0xF385, 0x5122, 0x5123,
+        0xF000, 0x1234, 0xF201, 0xF002, 0xF43A, 0x00FD,
+    ];
+
+    fn bytes(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|&word| vec![(word >> 8) as u8, word as u8]).collect()
+    }
+
+    /// Disassembles program in both syntaxes and checks each listing assembles back to it
+    fn round_trip(program: &[u8], mode: Mode) {
+        let disassembly = disassembler::disassemble(program, 0x200, mode);
+        let mut cowgod = Vec::new();
+        disassembly.write(&mut cowgod, Syntax::Cowgod).unwrap();
+        let cowgod = String::from_utf8(cowgod).unwrap();
+        assert_eq!(assemble(&cowgod).unwrap().binary, program, "{}", cowgod);
+        let mut listing = Vec::new();
+        disassembly.write(&mut listing, Syntax::Octo).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert_eq!(octo::compile(&listing).unwrap().binary, program, "{}", listing);
+    }
+
+    #[test]
+    fn chip8_round_trip() {
+        round_trip(&bytes(CHIP8), Mode::Chip8);
+    }
+
+    #[test]
+    fn xo_chip_round_trip() {
+        let mut program = bytes(XO_CHIP);
+        program.extend_from_slice(&bytes(CHIP8));
+        round_trip(&program, Mode::XoChip);
+    }
+
+    #[test]
+    fn odd_length_round_trip() {
+        round_trip(&[0x12, 0x00, 0xAB], Mode::Chip8);
+    }
+
+    #[test]
+    fn org_outside_memory() {
+        assert!(assemble("    ORG #10000\n    CLS").is_err());
+        assert!(assemble("    ORG -2\n    CLS").is_err());
+    }
+
+    #[test]
+    fn overflowing_sums() {
+        assert!(assemble("    DW #7FFFFFFFFFFFFFFF + 1").is_err());
+        assert!(assemble("    DW 0 - #7FFFFFFFFFFFFFFF - 2").is_err());
+        assert!(assemble("    DB 99999999999999999999").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+extern crate chip_8_core;
+
+use chip_8_core::assembler;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+const USAGE: &'static str = "Usage: chip8-as SOURCE [-o OUTPUT]";
+
+fn usage() -> ! {
+    writeln!(io::stderr(), "{}", USAGE).unwrap();
+    process::exit(2);
+}
+
+fn main() {
+    let mut source_path = None;
+    let mut output_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-o" if output_path.is_none() => output_path = Some(args.next().unwrap_or_else(|| usage())),
+            _ if source_path.is_none() && arg != "-o" => source_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let source_path = source_path.unwrap_or_else(|| usage());
+    let output_path = output_path.unwrap_or_else(|| {
+        Path::new(&source_path).with_extension("ch8").to_string_lossy().into_owned()
+    });
+    let mut source = String::new();
+    if let Err(err) = File::open(&source_path).and_then(|mut file| file.read_to_string(&mut source)) {
+        writeln!(io::stderr(), "chip8-as: {}: {}", source_path, err).unwrap();
+        process::exit(1);
+    }
+    let assembly = match assembler::assemble(&source) {
+        Ok(assembly) => assembly,
+        Err(err) => {
+            writeln!(io::stderr(), "{}:{}", source_path, err).unwrap();
+            process::exit(1);
+        }
+    };
+    // ROMs are loaded at 0x200, so code further on is padded out to where it belongs
+    if assembly.origin < 0x200 {
+        writeln!(io::stderr(),
+                 "{}: the program starts at #{:03X}, before ROMs are loaded at #200",
+                 source_path,
+                 assembly.origin)
+            .unwrap();
+        process::exit(1);
+    }
+    let mut binary = vec![0; assembly.origin as usize - 0x200];
+    binary.extend_from_slice(&assembly.binary);
+    if let Err(err) = File::create(&output_path).and_then(|mut file| file.write_all(&binary)) {
+        writeln!(io::stderr(), "chip8-as: {}: {}", output_path, err).unwrap();
+        process::exit(1);
+    }
+}
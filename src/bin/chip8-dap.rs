@@ -0,0 +1,85 @@
+extern crate chip_8_core;
+
+use chip_8_core::{AudioWrapper, Chip8, KeyWrapper};
+use chip_8_core::dap::{self, DapServer};
+use std::io;
+use std::io::prelude::*;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// No keys are ever pressed
+struct NoKeys;
+
+impl KeyWrapper for NoKeys {
+    fn is_pushed(&self, _: u8) -> bool {
+        false
+    }
+    fn get_key(&self) -> Option<u8> {
+        None
+    }
+}
+
+struct Silent;
+
+impl AudioWrapper for Silent {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+fn main() {
+    // Requests are read on another thread so a running program can be paused
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            match dap::read_message(&mut stdin) {
+                Ok(Some(message)) => {
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    writeln!(io::stderr(), "chip8-dap: {}", err).unwrap();
+                    break;
+                }
+            }
+        }
+    });
+    let stdout = io::stdout();
+    let mut server = DapServer::new(Chip8::new(NoKeys, Silent), stdout.lock());
+    loop {
+        let result = if server.is_running() {
+            let frame = Duration::new(0, 1000000000 / server.chip8.timer_hz);
+            let start = Instant::now();
+            let mut result = server.run_frame().map(|_| true);
+            while let Ok(true) = result {
+                match receiver.try_recv() {
+                    Ok(message) => result = server.handle(&message),
+                    Err(_) => break,
+                }
+            }
+            let elapsed = start.elapsed();
+            if elapsed < frame {
+                thread::sleep(frame - elapsed);
+            }
+            result
+        } else {
+            match receiver.recv() {
+                Ok(message) => server.handle(&message),
+                Err(_) => Ok(false),
+            }
+        };
+        match result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                writeln!(io::stderr(), "chip8-dap: {}", err).unwrap();
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+extern crate chip_8_core;
+
+use chip_8_core::Mode;
+use chip_8_core::disassembler::{self, Syntax};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+const USAGE: &'static str = "Usage: chip8-dis [--octo] [--schip | --xochip] [--origin ADDRESS] ROM";
+
+fn parse_address(text: &str) -> Option<u16> {
+    if text.starts_with("0x") {
+        u16::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn main() {
+    let mut syntax = Syntax::Cowgod;
+    let mut mode = Mode::Chip8;
+    let mut origin = 0x200;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--octo" => syntax = Syntax::Octo,
+            "--schip" => mode = Mode::SuperChip,
+            "--xochip" => mode = Mode::XoChip,
+            "--origin" => {
+                match args.next().as_ref().and_then(|text| parse_address(text)) {
+                    Some(address) => origin = address,
+                    None => {
+                        writeln!(io::stderr(), "{}", USAGE).unwrap();
+                        process::exit(2);
+                    }
+                }
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                writeln!(io::stderr(), "{}", USAGE).unwrap();
+                process::exit(2);
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            writeln!(io::stderr(), "{}", USAGE).unwrap();
+            process::exit(2);
+        }
+    };
+    let mut program = Vec::new();
+    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut program)) {
+        writeln!(io::stderr(), "chip8-dis: {}: {}", path, err).unwrap();
+        process::exit(1);
+    }
+    let disassembly = disassembler::disassemble(&program, origin, mode);
+    let stdout = io::stdout();
+    if let Err(err) = disassembly.write(&mut stdout.lock(), syntax) {
+        writeln!(io::stderr(), "chip8-dis: {}", err).unwrap();
+        process::exit(1);
+    }
+}
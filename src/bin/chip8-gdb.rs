@@ -0,0 +1,102 @@
+extern crate chip_8_core;
+
+use chip_8_core::{AudioWrapper, Chip8, KeyWrapper, Mode};
+use chip_8_core::gdb::GdbServer;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::process;
+
+const USAGE: &'static str = "Usage: chip8-gdb [--schip | --xochip] [--port PORT | --stdio] ROM";
+
+/// No keys are ever pressed
+struct NoKeys;
+
+impl KeyWrapper for NoKeys {
+    fn is_pushed(&self, _: u8) -> bool {
+        false
+    }
+    fn get_key(&self) -> Option<u8> {
+        None
+    }
+}
+
+struct Silent;
+
+impl AudioWrapper for Silent {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+fn fail(err: io::Error) -> ! {
+    writeln!(io::stderr(), "chip8-gdb: {}", err).unwrap();
+    process::exit(1);
+}
+
+fn main() {
+    let mut mode = Mode::Chip8;
+    let mut port = 1234;
+    let mut stdio = false;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--schip" => mode = Mode::SuperChip,
+            "--xochip" => mode = Mode::XoChip,
+            "--stdio" => stdio = true,
+            "--port" => {
+                match args.next().and_then(|text| text.parse().ok()) {
+                    Some(number) => port = number,
+                    None => {
+                        writeln!(io::stderr(), "{}", USAGE).unwrap();
+                        process::exit(2);
+                    }
+                }
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                writeln!(io::stderr(), "{}", USAGE).unwrap();
+                process::exit(2);
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            writeln!(io::stderr(), "{}", USAGE).unwrap();
+            process::exit(2);
+        }
+    };
+    let mut chip8 = Chip8::new(NoKeys, Silent);
+    if let Err(err) = File::open(&path).and_then(|mut file| chip8.load_prog_with_mode(&mut file, mode)) {
+        writeln!(io::stderr(), "chip8-gdb: {}: {}", path, err).unwrap();
+        process::exit(1);
+    }
+    if stdio {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let mut server = GdbServer::new(stdin.lock(), stdout.lock());
+        if let Err(err) = server.serve(&mut chip8) {
+            fail(err);
+        }
+        return;
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| fail(err));
+    writeln!(io::stderr(), "chip8-gdb: listening on 127.0.0.1:{}", port).unwrap();
+    let (stream, _) = listener.accept().unwrap_or_else(|err| fail(err));
+    let reader = stream.try_clone().unwrap_or_else(|err| fail(err));
+    let poller = stream.try_clone().unwrap_or_else(|err| fail(err));
+    let mut server = GdbServer::new(reader, stream);
+    // GDB sends a lone 0x03 byte to interrupt a running target
+    server.interrupted = Some(Box::new(move || {
+        let mut byte = [0];
+        let interrupted = poller.set_nonblocking(true).is_ok() &&
+                          (&poller).read(&mut byte).map(|read| read == 1 && byte[0] == 3).unwrap_or(false);
+        poller.set_nonblocking(false).is_ok() && interrupted
+    }));
+    if let Err(err) = server.serve(&mut chip8) {
+        fail(err);
+    }
+}
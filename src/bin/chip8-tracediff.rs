@@ -0,0 +1,115 @@
+extern crate chip_8_core;
+
+use chip_8_core::{AudioWrapper, Chip8, KeyWrapper, Mode, Quirks};
+use chip_8_core::trace::TraceReader;
+use chip_8_core::tracediff;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+const USAGE: &'static str = "Usage: chip8-tracediff [--schip | --xochip] [--limit CYCLES] [--seed SEED] \
+                             [--left-quirks PROFILE] [--right-quirks PROFILE] LEFT RIGHT\n\
+                             LEFT is a ROM or a binary trace, RIGHT is a ROM. \
+                             PROFILE is default, vip, chip48, schip or xochip.";
+
+/// No keys are ever pressed
+struct NoKeys;
+
+impl KeyWrapper for NoKeys {
+    fn is_pushed(&self, _: u8) -> bool {
+        false
+    }
+    fn get_key(&self) -> Option<u8> {
+        None
+    }
+}
+
+struct Silent;
+
+impl AudioWrapper for Silent {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+fn usage() -> ! {
+    writeln!(io::stderr(), "{}", USAGE).unwrap();
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    writeln!(io::stderr(), "chip8-tracediff: {}", message).unwrap();
+    process::exit(2);
+}
+
+fn profile(name: Option<String>) -> Quirks {
+    match name.as_ref().map(|name| &name[..]) {
+        Some("default") => Quirks::default(),
+        Some("vip") => Quirks::cosmac_vip(),
+        Some("chip48") => Quirks::chip48(),
+        Some("schip") => Quirks::schip(),
+        Some("xochip") => Quirks::xo_chip(),
+        _ => usage(),
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut contents)) {
+        fail(format!("{}: {}", path, err));
+    }
+    contents
+}
+
+fn machine(program: &[u8], mode: Mode, quirks: Quirks, seed: u64) -> Chip8<NoKeys, Silent> {
+    let mut chip8 = Chip8::with_quirks(NoKeys, Silent, quirks);
+    chip8.seed(seed);
+    if let Err(err) = chip8.load_prog_with_mode(&mut &program[..], mode) {
+        fail(err.to_string());
+    }
+    chip8
+}
+
+fn main() {
+    let mut mode = Mode::Chip8;
+    let mut limit = 1000000;
+    let mut seed = 0;
+    let mut left_quirks = Quirks::default();
+    let mut right_quirks = Quirks::default();
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--schip" => mode = Mode::SuperChip,
+            "--xochip" => mode = Mode::XoChip,
+            "--limit" => limit = args.next().and_then(|text| text.parse().ok()).unwrap_or_else(|| usage()),
+            "--seed" => seed = args.next().and_then(|text| text.parse().ok()).unwrap_or_else(|| usage()),
+            "--left-quirks" => left_quirks = profile(args.next()),
+            "--right-quirks" => right_quirks = profile(args.next()),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        usage();
+    }
+    let left = read_file(&paths[0]);
+    let mut right = machine(&read_file(&paths[1]), mode, right_quirks, seed);
+    let divergence = if left.starts_with(b"C8TR") {
+        match tracediff::diff_trace(TraceReader::new(&left[..]), &mut right, limit) {
+            Ok(divergence) => divergence,
+            Err(err) => fail(format!("{}: {}", paths[0], err)),
+        }
+    } else {
+        let mut left = machine(&left, mode, left_quirks, seed);
+        tracediff::diff_machines(&mut left, &mut right, limit)
+    };
+    match divergence {
+        Some(divergence) => {
+            let stdout = io::stdout();
+            divergence.write_report(&mut stdout.lock()).unwrap();
+            process::exit(1);
+        }
+        None => println!("No divergence found"),
+    }
+}
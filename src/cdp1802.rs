@@ -0,0 +1,478 @@
+//! An RCA CDP1802, the CPU of the COSMAC VIP
+//!
+//! CHIP-8 programs for the VIP can call machine code with 0NNN. The interpreter runs the routine
+//! with P = 3 and R3 = NNN, and the routine returns to the interpreter with D4 (SEP R4).
+
+use Chip8Err;
+use Chip8State;
+use Mode;
+
+/// What the CPU is wired to besides memory
+///
+/// Nothing is wired up by default.
+pub trait Bus {
+    /// The byte on the data bus for INP port
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+    /// A byte OUT port put on the data bus
+    fn output(&mut self, _port: u8, _value: u8) {}
+    /// Whether external flag EF1 to EF4 is set
+    fn flag(&mut self, _flag: u8) -> bool {
+        false
+    }
+}
+
+/// A bus with nothing on it
+pub struct NoBus;
+
+impl Bus for NoBus {}
+
+/// The registers of a CDP1802
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Cdp1802 {
+    /// The scratchpad registers R0 to RF
+    pub r: [u16; 16],
+    /// The accumulator
+    pub d: u8,
+    /// The carry flag
+    pub df: bool,
+    /// Which register is the program counter
+    pub p: u8,
+    /// Which register is the data pointer
+    pub x: u8,
+    /// X and P saved by an interrupt or MARK
+    pub t: u8,
+    /// Interrupts are enabled
+    pub ie: bool,
+    pub q: bool,
+    /// The CPU ran IDL and waits for an interrupt or DMA
+    pub idle: bool,
+}
+
+fn read(memory: &[u8], address: u16) -> u8 {
+    memory[address as usize % memory.len()]
+}
+
+fn write(memory: &mut [u8], address: u16, value: u8) {
+    let len = memory.len();
+    memory[address as usize % len] = value;
+}
+
+impl Cdp1802 {
+    /// A CPU as it comes out of reset, running from R0 at 0
+    pub fn new() -> Cdp1802 {
+        Cdp1802 { ie: true, ..Cdp1802::default() }
+    }
+    /// The byte at the program counter, moving past it
+    fn immediate(&mut self, memory: &[u8]) -> u8 {
+        let p = self.p as usize;
+        let byte = read(memory, self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        byte
+    }
+    fn add(&mut self, value: u8, carry: bool) {
+        let sum = self.d as u16 + value as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+    /// Sets D to minuend - subtrahend, with DF clear if it borrowed
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, borrow: bool) {
+        let difference = minuend as i16 - subtrahend as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+    /// Handles an interrupt if they are enabled
+    pub fn interrupt(&mut self) {
+        if self.ie {
+            self.t = self.x << 4 | self.p;
+            self.x = 2;
+            self.p = 1;
+            self.ie = false;
+            self.idle = false;
+        }
+    }
+    /// Does a DMA out cycle, returning the byte R0 pointed to
+    pub fn dma_out(&mut self, memory: &[u8]) -> u8 {
+        let byte = read(memory, self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        byte
+    }
+    /// Runs one instruction, returning the machine cycles it took
+    pub fn step<B: Bus>(&mut self, memory: &mut [u8], bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let optcode = self.immediate(memory);
+        let n = (optcode & 0xF) as usize;
+        let x = self.x as usize;
+        match optcode >> 4 {
+            0 if n == 0 => self.idle = true,
+            // LDN
+            0 => self.d = read(memory, self.r[n]),
+            // INC
+            1 => self.r[n] = self.r[n].wrapping_add(1),
+            // DEC
+            2 => self.r[n] = self.r[n].wrapping_sub(1),
+            // Short branches
+            3 => {
+                let condition = match n & 7 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    3 => self.df,
+                    flag => bus.flag(flag as u8 - 3),
+                };
+                let p = self.p as usize;
+                if condition != (n & 8 != 0) {
+                    let target = read(memory, self.r[p]);
+                    self.r[p] = self.r[p] & 0xFF00 | target as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(1);
+                }
+            }
+            // LDA
+            4 => {
+                self.d = read(memory, self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            // STR
+            5 => write(memory, self.r[n], self.d),
+            // IRX
+            6 if n == 0 => self.r[x] = self.r[x].wrapping_add(1),
+            // OUT
+            6 if n < 8 => {
+                bus.output(n as u8, read(memory, self.r[x]));
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            6 if n == 8 => {}
+            // INP
+            6 => {
+                self.d = bus.input(n as u8 - 8);
+                write(memory, self.r[x], self.d);
+            }
+            7 => {
+                match n {
+                    // RET and DIS
+                    0 | 1 => {
+                        let xp = read(memory, self.r[x]);
+                        self.r[x] = self.r[x].wrapping_add(1);
+                        self.x = xp >> 4;
+                        self.p = xp & 0xF;
+                        self.ie = n == 0;
+                    }
+                    // LDXA
+                    2 => {
+                        self.d = read(memory, self.r[x]);
+                        self.r[x] = self.r[x].wrapping_add(1);
+                    }
+                    // STXD
+                    3 => {
+                        write(memory, self.r[x], self.d);
+                        self.r[x] = self.r[x].wrapping_sub(1);
+                    }
+                    // ADC
+                    4 => {
+                        let (value, carry) = (read(memory, self.r[x]), self.df);
+                        self.add(value, carry);
+                    }
+                    // SDB
+                    5 => {
+                        let (value, d, borrow) = (read(memory, self.r[x]), self.d, !self.df);
+                        self.subtract(value, d, borrow);
+                    }
+                    // SHRC
+                    6 => {
+                        let carry = self.df;
+                        self.df = self.d & 1 != 0;
+                        self.d = self.d >> 1 | (carry as u8) << 7;
+                    }
+                    // SMB
+                    7 => {
+                        let (value, d, borrow) = (read(memory, self.r[x]), self.d, !self.df);
+                        self.subtract(d, value, borrow);
+                    }
+                    // SAV
+                    8 => write(memory, self.r[x], self.t),
+                    // MARK
+                    9 => {
+                        self.t = self.x << 4 | self.p;
+                        write(memory, self.r[2], self.t);
+                        self.x = self.p;
+                        self.r[2] = self.r[2].wrapping_sub(1);
+                    }
+                    // REQ and SEQ
+                    0xA => self.q = false,
+                    0xB => self.q = true,
+                    // ADCI
+                    0xC => {
+                        let (value, carry) = (self.immediate(memory), self.df);
+                        self.add(value, carry);
+                    }
+                    // SDBI
+                    0xD => {
+                        let (value, d, borrow) = (self.immediate(memory), self.d, !self.df);
+                        self.subtract(value, d, borrow);
+                    }
+                    // SHLC
+                    0xE => {
+                        let carry = self.df;
+                        self.df = self.d & 0x80 != 0;
+                        self.d = self.d << 1 | carry as u8;
+                    }
+                    // SMBI
+                    _ => {
+                        let (value, d, borrow) = (self.immediate(memory), self.d, !self.df);
+                        self.subtract(d, value, borrow);
+                    }
+                }
+            }
+            // GLO, GHI, PLO and PHI
+            8 => self.d = self.r[n] as u8,
+            9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = self.r[n] & 0xFF00 | self.d as u16,
+            0xB => self.r[n] = self.r[n] & 0xFF | (self.d as u16) << 8,
+            // Long branches and skips
+            0xC => {
+                let (branch, condition) = match n {
+                    0x0 => (true, true),
+                    0x1 => (true, self.q),
+                    0x2 => (true, self.d == 0),
+                    0x3 => (true, self.df),
+                    // NOP
+                    0x4 => (false, false),
+                    0x5 => (false, !self.q),
+                    0x6 => (false, self.d != 0),
+                    0x7 => (false, !self.df),
+                    0x8 => (false, true),
+                    0x9 => (true, !self.q),
+                    0xA => (true, self.d != 0),
+                    0xB => (true, !self.df),
+                    0xC => (false, self.ie),
+                    0xD => (false, self.q),
+                    0xE => (false, self.d == 0),
+                    _ => (false, self.df),
+                };
+                let p = self.p as usize;
+                if branch && condition {
+                    let high = read(memory, self.r[p]);
+                    let low = read(memory, self.r[p].wrapping_add(1));
+                    self.r[p] = (high as u16) << 8 | low as u16;
+                } else if branch || condition {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+                return 3;
+            }
+            // SEP and SEX
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            // SHR and SHL
+            0xF if n == 6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            }
+            0xF if n == 0xE => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            }
+            _ => {
+                // The low half takes its operand from M(RX), the high half from the next byte
+                let value = if n < 8 {
+                    read(memory, self.r[x])
+                } else {
+                    self.immediate(memory)
+                };
+                match n & 7 {
+                    // LDX and LDI
+                    0 => self.d = value,
+                    1 => self.d |= value,
+                    2 => self.d &= value,
+                    3 => self.d ^= value,
+                    4 => self.add(value, false),
+                    // SD and SDI
+                    5 => {
+                        let d = self.d;
+                        self.subtract(value, d, false);
+                    }
+                    // SM and SMI
+                    _ => {
+                        let d = self.d;
+                        self.subtract(d, value, false);
+                    }
+                }
+            }
+        }
+        2
+    }
+}
+
+/// Where the VIP interpreter keeps V0 to VF
+const REGISTERS_ADDRESS: usize = 0xEF0;
+/// Where the VIP interpreter keeps the display
+const DISPLAY_ADDRESS: usize = 0xF00;
+/// The top of the VIP interpreter's stack
+const STACK_TOP: u16 = 0xECF;
+/// How many instructions a routine gets to return in
+const INSTRUCTION_LIMIT: u32 = 1000000;
+
+/// Runs the machine code routine at address on a CHIP-8 machine like the VIP interpreter does,
+/// returning the machine cycles it took
+///
+/// The registers and the display are copied to where the interpreter keeps them, with I in RA,
+/// the timers in R8 and the address of the next instruction in R5, and copied back once the
+/// routine returns with SEP R4. The program carries on from R5.
+pub fn call_routine(state: &mut Chip8State, address: u16) -> Result<u32, Chip8Err> {
+    if state.mode != Mode::Chip8 || state.memory.len() < 0x1000 {
+        return Err(Chip8Err::UnknownOptcode);
+    }
+    state.memory[REGISTERS_ADDRESS..REGISTERS_ADDRESS + 16].copy_from_slice(&state.data_registers);
+    for y in 0..32 {
+        let row = &state.frame_buffer[y * 16..y * 16 + 8];
+        state.memory[DISPLAY_ADDRESS + y * 8..DISPLAY_ADDRESS + y * 8 + 8].copy_from_slice(row);
+    }
+    let mut cpu = Cdp1802::new();
+    cpu.p = 3;
+    cpu.x = 2;
+    cpu.r[2] = STACK_TOP.wrapping_sub(2 * state.stack.len() as u16);
+    cpu.r[3] = address;
+    cpu.r[5] = state.program_counter.wrapping_add(2);
+    cpu.r[6] = REGISTERS_ADDRESS as u16 | address >> 8 & 0xF;
+    cpu.r[7] = REGISTERS_ADDRESS as u16 | address >> 4 & 0xF;
+    cpu.r[8] = (state.delay_timer as u16) << 8 | state.sound_timer as u16;
+    cpu.r[0xA] = state.address_register;
+    cpu.r[0xB] = DISPLAY_ADDRESS as u16;
+    let mut cycles = 0;
+    for _ in 0..INSTRUCTION_LIMIT {
+        cycles += cpu.step(&mut state.memory, &mut NoBus);
+        if cpu.p == 4 {
+            state.data_registers.copy_from_slice(&state.memory[REGISTERS_ADDRESS..REGISTERS_ADDRESS + 16]);
+            for y in 0..32 {
+                let row = &state.memory[DISPLAY_ADDRESS + y * 8..DISPLAY_ADDRESS + y * 8 + 8];
+                state.frame_buffer[y * 16..y * 16 + 8].copy_from_slice(row);
+            }
+            state.address_register = cpu.r[0xA];
+            state.delay_timer = (cpu.r[8] >> 8) as u8;
+            state.sound_timer = cpu.r[8] as u8;
+            state.program_counter = cpu.r[5];
+            return Ok(cycles);
+        }
+        if cpu.idle {
+            break;
+        }
+    }
+    Err(Chip8Err::RoutineHung)
+}
+
+#[cfg(test)]
+mod tests {
+    use Quirks;
+    use super::{Cdp1802, NoBus};
+    use testing::machine;
+
+    /// A CPU out of reset with program at the start of 256 bytes of memory
+    fn cpu(program: &[u8]) -> (Cdp1802, Vec<u8>) {
+        let mut memory = vec![0; 0x100];
+        memory[..program.len()].copy_from_slice(program);
+        (Cdp1802::new(), memory)
+    }
+
+    /// Runs steps instructions, returning the machine cycles they took
+    fn run(cpu: &mut Cdp1802, memory: &mut [u8], steps: usize) -> u32 {
+        (0..steps).map(|_| cpu.step(memory, &mut NoBus)).sum()
+    }
+
+    #[test]
+    fn registers() {
+        // LDI 34, PHI R5, LDI 12, PLO R5, INC R5, DEC R5, DEC R5, GLO R5
+        let (mut cpu, mut memory) = cpu(&[0xF8, 0x34, 0xB5, 0xF8, 0x12, 0xA5, 0x15, 0x25, 0x25, 0x85]);
+        assert_eq!(run(&mut cpu, &mut memory, 8), 16);
+        assert_eq!((cpu.r[5], cpu.d, cpu.r[0]), (0x3411, 0x11, 10));
+    }
+
+    #[test]
+    fn memory() {
+        // LDI 5A, SEX R1, STXD, IRX, LDXA, LDI 0F, AND
+        let (mut cpu, mut memory) = cpu(&[0xF8, 0x5A, 0xE1, 0x73, 0x60, 0x72, 0xF8, 0x0F, 0xF2]);
+        cpu.r[1] = 0x80;
+        memory[0x81] = 0x3C;
+        run(&mut cpu, &mut memory, 3);
+        assert_eq!((memory[0x80], cpu.r[1]), (0x5A, 0x7F));
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!((cpu.d, cpu.r[1]), (0x5A, 0x81));
+        run(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.d, 0x0C);
+    }
+
+    #[test]
+    fn branches() {
+        let (mut cpu, mut memory) = cpu(&[0xF8, 0x00, // LDI 0
+                                          0x3A, 0x10, // BNZ 10, not taken
+                                          0x32, 0x08, // BZ 08
+                                          0x00, 0x00,
+                                          0xC0, 0x00, 0x20]); // LBR 0020
+        memory[0x20..0x28].copy_from_slice(&[0xC8, 0x00, 0x00, // LSKP
+                                             0x7B, // SEQ
+                                             0xC9, 0x00, 0x40, // LBNQ 0040, not taken
+                                             0x00]); // IDL
+        assert_eq!(run(&mut cpu, &mut memory, 3), 6);
+        assert_eq!(cpu.r[0], 0x08);
+        // Long branches and skips take a machine cycle more
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.r[0], 0x20);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 3);
+        assert_eq!(cpu.r[0], 0x23);
+        assert_eq!(run(&mut cpu, &mut memory, 2), 5);
+        assert_eq!((cpu.r[0], cpu.q), (0x27, true));
+        run(&mut cpu, &mut memory, 1);
+        assert!(cpu.idle);
+        assert_eq!(run(&mut cpu, &mut memory, 1), 1);
+        assert_eq!(cpu.r[0], 0x28);
+    }
+
+    #[test]
+    fn arithmetic_and_carry() {
+        let (mut cpu, mut memory) = cpu(&[0xF8, 0xFF, // LDI FF
+                                          0xFC, 0x01, // ADI 01
+                                          0x7C, 0x00, // ADCI 00
+                                          0xFF, 0x02, // SMI 02
+                                          0x7F, 0x00, // SMBI 00
+                                          0xF6, // SHR
+                                          0x7E, // SHLC
+                                          0xFE]); // SHL
+        let mut results = Vec::new();
+        for _ in 0..8 {
+            cpu.step(&mut memory, &mut NoBus);
+            results.push((cpu.d, cpu.df));
+        }
+        assert_eq!(results,
+                   [(0xFF, false), (0x00, true), (0x01, false), (0xFF, false), (0xFE, true), (0x7F, false),
+                    (0xFE, false), (0xFC, true)]);
+    }
+
+    #[test]
+    fn routine_called_from_chip8() {
+        let mut quirks = Quirks::cosmac_vip();
+        quirks.display_wait = false;
+        // V0 = 05, then call the routine at 0300
+        let mut program = vec![0; 0x100];
+        program[..4].copy_from_slice(&[0x60, 0x05, 0x03, 0x00]);
+        program.extend_from_slice(&[0xF8, 0x0E, 0xBF, 0xF8, 0xF0, 0xAF, // RF = 0EF0, where V0 is kept
+                                    0x0F, 0xFC, 0x03, 0x5F, // V0 += 3
+                                    0xF8, 0x42, 0xAA, // I = 0042
+                                    0xF8, 0x04, 0xBE, 0xF8, 0x00, 0xAE, 0xF8, 0x77, 0x5E, // M(0400) = 77
+                                    0xF8, 0xFF, 0x5B, // The first byte of the display = FF
+                                    0xD4]); // Return to the interpreter
+        let mut chip8 = machine(&program, quirks);
+        chip8.vip_timing = true;
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        assert_eq!(chip8.data_registers[0], 0x08);
+        assert_eq!(chip8.address_register, 0x0042);
+        assert_eq!(chip8.memory[0x400], 0x77);
+        assert_eq!(chip8.frame_buffer[0], 0xFF);
+        assert_eq!(chip8.program_counter, 0x204);
+        // 6XNN, then 0NNN and the 18 instructions of the routine
+        assert_eq!((chip8.cycles(), chip8.frame_cycle()), (2, 46 + 50 + 18 * 2));
+    }
+}
@@ -0,0 +1,465 @@
+//! A Debug Adapter Protocol server driving a Chip8
+//!
+//! Messages are JSON bodies behind a Content-Length header. The launch request takes a
+//! `program` path, which is assembled if it ends in .8o (Octo) or .asm (Cowgod) and loaded as a
+//! ROM otherwise, and an optional `mode` of "chip8", "schip" or "xochip".
+
+use AudioWrapper;
+use Chip8;
+use Chip8Err;
+use DEFAULT_INSTRUCTIONS_PER_FRAME;
+use DEFAULT_TIMER_HZ;
+use KeyWrapper;
+use Mode;
+use assembler::{self, AssembleError, Assembly};
+use debugger::{Breakpoint, Debugger, StopReason};
+use octo;
+use serde_json::{self, Value};
+use serde_json::builder::{ArrayBuilder, ObjectBuilder};
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// How many instructions next and step out run before giving up
+const STEP_LIMIT: u64 = 1000000;
+
+const REGISTERS: i64 = 1;
+const TIMERS: i64 = 2;
+const MEMORY: i64 = 3;
+
+/// Reads one message, returning None at the end of the input
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if len.is_some() {
+                break;
+            }
+        } else if line.starts_with("Content-Length:") {
+            len = line["Content-Length:".len()..].trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; len.unwrap_or(0)];
+    try!(reader.read_exact(&mut body));
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn hex(value: u16, digits: usize) -> String {
+    format!("0x{:01$X}", value, digits)
+}
+
+fn variable(name: &str, value: String) -> Value {
+    ObjectBuilder::new()
+        .insert("name", name)
+        .insert("value", value)
+        .insert("variablesReference", 0)
+        .build()
+}
+
+/// Serves one debugging session
+pub struct DapServer<T: KeyWrapper, A: AudioWrapper, W: Write> {
+    pub chip8: Chip8<T, A>,
+    pub debugger: Debugger,
+    writer: W,
+    seq: u64,
+    /// The path of the launched program
+    program: Option<String>,
+    /// The symbol map when the program was assembled
+    assembly: Option<Assembly>,
+    stop_on_entry: bool,
+    running: bool,
+    source_breakpoints: Vec<Breakpoint>,
+    instruction_breakpoints: Vec<Breakpoint>,
+}
+
+impl<T: KeyWrapper, A: AudioWrapper, W: Write> DapServer<T, A, W> {
+    pub fn new(chip8: Chip8<T, A>, writer: W) -> DapServer<T, A, W> {
+        DapServer {
+            chip8: chip8,
+            debugger: Debugger::new(),
+            writer: writer,
+            seq: 1,
+            program: None,
+            assembly: None,
+            stop_on_entry: false,
+            running: false,
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+        }
+    }
+    /// Whether the program is running and run_frame should be called every frame
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+    fn send(&mut self, mut message: ObjectBuilder) -> io::Result<()> {
+        message = message.insert("seq", self.seq);
+        self.seq += 1;
+        let body = serde_json::to_string(&message.build()).unwrap();
+        try!(write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body));
+        self.writer.flush()
+    }
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(ObjectBuilder::new()
+            .insert("type", "event")
+            .insert("event", event)
+            .insert("body", body))
+    }
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = ObjectBuilder::new()
+            .insert("type", "response")
+            .insert("request_seq", request.find("seq").and_then(Value::as_u64).unwrap_or(0))
+            .insert("command", request.find("command").and_then(Value::as_str).unwrap_or(""))
+            .insert("success", result.is_ok());
+        response = match result {
+            Ok(body) => response.insert("body", body),
+            Err(message) => response.insert("message", message),
+        };
+        self.send(response)
+    }
+    fn stopped(&mut self, reason: StopReason) -> io::Result<()> {
+        self.running = false;
+        let (reason, text) = match reason {
+            StopReason::Breakpoint(_) => ("breakpoint", None),
+            StopReason::Watchpoint { .. } => ("data breakpoint", None),
+            StopReason::Halted |
+            StopReason::Fault(Chip8Err::BadState) => {
+                try!(self.event("exited", ObjectBuilder::new().insert("exitCode", 0).build()));
+                return self.event("terminated", ObjectBuilder::new().build());
+            }
+            StopReason::Fault(err) => {
+                let text = self.chip8.fault().map_or_else(|| err.to_string(), |fault| fault.to_string());
+                ("exception", Some(text))
+            }
+            _ => ("step", None),
+        };
+        let mut body = ObjectBuilder::new()
+            .insert("reason", reason)
+            .insert("threadId", 1)
+            .insert("allThreadsStopped", true);
+        if let Some(text) = text {
+            body = body.insert("text", text);
+        }
+        self.event("stopped", body.build())
+    }
+    /// Runs the rest of the frame, reporting anything that stops the program
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        if !self.running {
+            return Ok(());
+        }
+        match self.debugger.run_frame(&mut self.chip8) {
+            StopReason::FrameEnd => Ok(()),
+            reason => self.stopped(reason),
+        }
+    }
+    fn update_breakpoints(&mut self) {
+        self.debugger.breakpoints = self.source_breakpoints.clone();
+        self.debugger.breakpoints.extend_from_slice(&self.instruction_breakpoints);
+    }
+    fn source(&self) -> Value {
+        let path = self.program.clone().unwrap_or_default();
+        let name = Path::new(&path)
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        ObjectBuilder::new().insert("name", name).insert("path", path).build()
+    }
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = match arguments.find("program").and_then(Value::as_str) {
+            Some(path) => path.to_string(),
+            None => return Err("launch needs a program".to_string()),
+        };
+        let mode = match arguments.find("mode").and_then(Value::as_str) {
+            None | Some("chip8") => Mode::Chip8,
+            Some("schip") => Mode::SuperChip,
+            Some("xochip") => Mode::XoChip,
+            Some(mode) => return Err(format!("unknown mode {}", mode)),
+        };
+        self.stop_on_entry = arguments.find("stopOnEntry").and_then(Value::as_bool).unwrap_or(false);
+        self.chip8.instructions_per_frame = arguments.find("instructionsPerFrame")
+            .and_then(Value::as_u64)
+            .map_or(DEFAULT_INSTRUCTIONS_PER_FRAME, |instructions| instructions as u32);
+        self.chip8.vip_timing = arguments.find("vipTiming").and_then(Value::as_bool).unwrap_or(false);
+        self.chip8.timer_hz = match arguments.find("timerHz").and_then(Value::as_u64) {
+            None => DEFAULT_TIMER_HZ,
+            Some(0) => return Err("timerHz must be more than 0".to_string()),
+            Some(hz) => hz as u32,
+        };
+        let mut contents = Vec::new();
+        if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut contents)) {
+            return Err(format!("{}: {}", path, err));
+        }
+        let assemble: Option<fn(&str) -> Result<Assembly, AssembleError>> =
+            match Path::new(&path).extension().and_then(|extension| extension.to_str()) {
+                Some("8o") => Some(octo::compile),
+                Some("asm") => Some(assembler::assemble),
+                _ => None,
+            };
+        let (origin, binary) = match assemble {
+            Some(assemble) => {
+                let source = String::from_utf8_lossy(&contents).into_owned();
+                let assembly = try!(assemble(&source).map_err(|err| format!("{}: {}", path, err)));
+                let loaded = (assembly.origin as usize, assembly.binary.clone());
+                self.assembly = Some(assembly);
+                loaded
+            }
+            None => (0x200, contents),
+        };
+        if origin < 0x200 {
+            return Err(format!("{}: program starts at {:#X}, below 0x200", path, origin));
+        }
+        // Programs are loaded at 0x200, so one with a later origin is padded out to it, and the
+        // machine keeps the whole program to go back to on a reset
+        let mut program = vec![0; origin - 0x200];
+        program.extend_from_slice(&binary);
+        try!(self.chip8.load_prog_with_mode(&mut &program[..], mode).map_err(|err| err.to_string()));
+        if 0x200 + program.len() > self.chip8.memory().len() {
+            return Err(format!("{}: program doesn't fit in memory", path));
+        }
+        self.program = Some(path);
+        Ok(Value::Null)
+    }
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let lines: Vec<u64> = arguments.find("breakpoints")
+            .and_then(Value::as_array)
+            .map_or(Vec::new(), |breakpoints| {
+                breakpoints.iter()
+                    .filter_map(|breakpoint| breakpoint.find("line").and_then(Value::as_u64))
+                    .collect()
+            });
+        let mut verified = ArrayBuilder::new();
+        self.source_breakpoints.clear();
+        for line in lines {
+            let address = self.assembly.as_ref().and_then(|assembly| assembly.address_of_line(line as usize));
+            let mut breakpoint = ObjectBuilder::new()
+                .insert("verified", address.is_some())
+                .insert("line", line);
+            if let Some(address) = address {
+                self.source_breakpoints.push(Breakpoint::at(address));
+                breakpoint = breakpoint.insert("instructionReference", hex(address, 3));
+            } else {
+                breakpoint = breakpoint.insert("message", "no code on this line");
+            }
+            verified = verified.push(breakpoint.build());
+        }
+        self.update_breakpoints();
+        ObjectBuilder::new().insert("breakpoints", verified.build()).build()
+    }
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Value {
+        let mut verified = ArrayBuilder::new();
+        self.instruction_breakpoints.clear();
+        if let Some(breakpoints) = arguments.find("breakpoints").and_then(Value::as_array) {
+            for breakpoint in breakpoints {
+                let reference = breakpoint.find("instructionReference").and_then(Value::as_str).unwrap_or("");
+                let offset = breakpoint.find("offset").and_then(Value::as_i64).unwrap_or(0);
+                let address = if reference.starts_with("0x") {
+                    u16::from_str_radix(&reference[2..], 16).ok()
+                } else {
+                    reference.parse().ok()
+                };
+                match address {
+                    Some(address) => {
+                        let address = (address as i64 + offset) as u16;
+                        self.instruction_breakpoints.push(Breakpoint::at(address));
+                        verified = verified.push(ObjectBuilder::new()
+                            .insert("verified", true)
+                            .insert("instructionReference", hex(address, 3))
+                            .build());
+                    }
+                    None => {
+                        verified = verified.push(ObjectBuilder::new()
+                            .insert("verified", false)
+                            .insert("message", "not an address")
+                            .build())
+                    }
+                }
+            }
+        }
+        self.update_breakpoints();
+        ObjectBuilder::new().insert("breakpoints", verified.build()).build()
+    }
+    /// The name of the label nearest before address, or the address
+    fn frame_name(&self, address: u16) -> String {
+        self.assembly
+            .as_ref()
+            .and_then(|assembly| {
+                assembly.labels
+                    .iter()
+                    .filter(|&(_, &label)| label <= address)
+                    .max_by_key(|&(_, &label)| label)
+                    .map(|(name, _)| name.clone())
+            })
+            .unwrap_or_else(|| hex(address, 3))
+    }
+    fn stack_trace(&self) -> Value {
+        // The innermost frame is at the program counter, the rest at the calls on the stack
+        let mut addresses = vec![self.chip8.program_counter()];
+        addresses.extend(self.chip8.stack().iter().rev().map(|&address| address.wrapping_sub(2)));
+        let mut frames = ArrayBuilder::new();
+        for (id, &address) in addresses.iter().enumerate() {
+            let line = self.assembly.as_ref().and_then(|assembly| assembly.line_at(address));
+            let mut frame = ObjectBuilder::new()
+                .insert("id", id)
+                .insert("name", self.frame_name(address))
+                .insert("line", line.unwrap_or(0))
+                .insert("column", 0)
+                .insert("instructionPointerReference", hex(address, 3));
+            if line.is_some() {
+                frame = frame.insert("source", self.source());
+            }
+            frames = frames.push(frame.build());
+        }
+        ObjectBuilder::new()
+            .insert("stackFrames", frames.build())
+            .insert("totalFrames", addresses.len())
+            .build()
+    }
+    fn scopes(&self) -> Value {
+        let scope = |name: &str, reference: i64, expensive: bool| {
+            ObjectBuilder::new()
+                .insert("name", name)
+                .insert("variablesReference", reference)
+                .insert("expensive", expensive)
+                .build()
+        };
+        let scopes = ArrayBuilder::new()
+            .push(scope("Registers", REGISTERS, false))
+            .push(scope("Timers", TIMERS, false))
+            .push(scope("Memory", MEMORY, true))
+            .build();
+        ObjectBuilder::new().insert("scopes", scopes).build()
+    }
+    fn variables(&self, reference: i64) -> Value {
+        let mut variables = ArrayBuilder::new();
+        match reference {
+            REGISTERS => {
+                for (x, &value) in self.chip8.registers().iter().enumerate() {
+                    variables = variables.push(variable(&format!("V{:X}", x), hex(value as u16, 2)));
+                }
+                variables = variables.push(variable("I", hex(self.chip8.address_register(), 3)))
+                    .push(variable("PC", hex(self.chip8.program_counter(), 3)))
+                    .push(variable("SP", self.chip8.stack().len().to_string()));
+            }
+            TIMERS => {
+                variables = variables.push(variable("DT", self.chip8.delay_timer().to_string()))
+                    .push(variable("ST", self.chip8.sound_timer().to_string()));
+            }
+            MEMORY => {
+                for (row, bytes) in self.chip8.memory().chunks(16).enumerate() {
+                    let value = bytes.iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    variables = variables.push(variable(&hex(row as u16 * 16, 4), value));
+                }
+            }
+            _ => {}
+        }
+        ObjectBuilder::new().insert("variables", variables.build()).build()
+    }
+    /// Answers a request, returning false once the client disconnects
+    pub fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request.find("command").and_then(Value::as_str).unwrap_or("").to_string();
+        let null = Value::Null;
+        let arguments = request.find("arguments").unwrap_or(&null);
+        let launched = self.program.is_some();
+        match &command[..] {
+            "initialize" => {
+                let capabilities = ObjectBuilder::new()
+                    .insert("supportsConfigurationDoneRequest", true)
+                    .insert("supportsInstructionBreakpoints", true)
+                    .insert("supportsSteppingGranularity", false)
+                    .build();
+                try!(self.respond(request, Ok(capabilities)));
+                try!(self.event("initialized", ObjectBuilder::new().build()));
+            }
+            "launch" => {
+                let result = self.launch(arguments);
+                try!(self.respond(request, result));
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(arguments);
+                try!(self.respond(request, Ok(body)));
+            }
+            "setInstructionBreakpoints" => {
+                let body = self.set_instruction_breakpoints(arguments);
+                try!(self.respond(request, Ok(body)));
+            }
+            "configurationDone" => {
+                try!(self.respond(request, Ok(Value::Null)));
+                if self.stop_on_entry {
+                    try!(self.event("stopped",
+                                    ObjectBuilder::new()
+                                        .insert("reason", "entry")
+                                        .insert("threadId", 1)
+                                        .insert("allThreadsStopped", true)
+                                        .build()));
+                } else {
+                    self.running = launched;
+                }
+            }
+            "threads" => {
+                let threads = ArrayBuilder::new()
+                    .push(ObjectBuilder::new().insert("id", 1).insert("name", "CHIP-8").build())
+                    .build();
+                try!(self.respond(request, Ok(ObjectBuilder::new().insert("threads", threads).build())));
+            }
+            "stackTrace" | "scopes" | "variables" if !launched || self.chip8.state.state().is_none() => {
+                try!(self.respond(request, Err("no program is loaded".to_string())));
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                try!(self.respond(request, Ok(body)));
+            }
+            "scopes" => {
+                let body = self.scopes();
+                try!(self.respond(request, Ok(body)));
+            }
+            "variables" => {
+                let reference = arguments.find("variablesReference").and_then(Value::as_i64).unwrap_or(0);
+                let body = self.variables(reference);
+                try!(self.respond(request, Ok(body)));
+            }
+            "continue" => {
+                self.running = launched;
+                try!(self.respond(request,
+                                  Ok(ObjectBuilder::new().insert("allThreadsContinued", true).build())));
+            }
+            "pause" => {
+                try!(self.respond(request, Ok(Value::Null)));
+                if self.running {
+                    try!(self.event("stopped",
+                                    ObjectBuilder::new()
+                                        .insert("reason", "pause")
+                                        .insert("threadId", 1)
+                                        .insert("allThreadsStopped", true)
+                                        .build()));
+                }
+                self.running = false;
+            }
+            "next" | "stepIn" | "stepOut" if launched => {
+                try!(self.respond(request, Ok(Value::Null)));
+                let reason = match &command[..] {
+                    "next" => self.debugger.step_over(&mut self.chip8, STEP_LIMIT),
+                    "stepIn" => self.debugger.step(&mut self.chip8),
+                    _ => self.debugger.step_out(&mut self.chip8, STEP_LIMIT),
+                };
+                try!(self.stopped(reason));
+            }
+            "disconnect" | "terminate" => {
+                try!(self.respond(request, Ok(Value::Null)));
+                return Ok(false);
+            }
+            _ => {
+                try!(self.respond(request, Err(format!("{} isn't supported", command))));
+            }
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,381 @@
+use AudioWrapper;
+use Chip8;
+use Chip8Err;
+use Chip8State;
+use Instruction;
+use KeyWrapper;
+use Mode;
+use Quirks;
+use RunState;
+use vip_stack_address;
+
+/// A register a breakpoint condition can test
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    DelayTimer,
+    SoundTimer,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A test of a register against a value
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn holds(&self, state: &Chip8State) -> bool {
+        let register = match self.register {
+            Register::V(x) => state.data_registers[x as usize & 0xF] as u16,
+            Register::I => state.address_register,
+            Register::DelayTimer => state.delay_timer as u16,
+            Register::SoundTimer => state.sound_timer as u16,
+        };
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+/// Stops the machine before the instruction at address runs, or before any instruction if
+/// address is None, as long as the condition holds
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Breakpoint {
+    pub address: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    /// A breakpoint on an address without a condition
+    pub fn at(address: u16) -> Breakpoint {
+        Breakpoint {
+            address: Some(address),
+            condition: None,
+        }
+    }
+    fn hit(&self, state: &Chip8State) -> bool {
+        self.address.map_or(true, |address| address == state.program_counter) &&
+        self.condition.map_or(true, |condition| condition.holds(state))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Stops the machine after an instruction reads or writes memory in start..start + len
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub len: usize,
+    pub read: bool,
+    pub write: bool,
+}
+
+/// Why the debugger handed control back
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// The requested step, step over or step out finished
+    Step,
+    /// A breakpoint was hit; the instruction at the program counter hasn't run yet
+    Breakpoint(Breakpoint),
+    /// An instruction accessed watched memory at address
+    Watchpoint {
+        watchpoint: Watchpoint,
+        access: Access,
+        address: usize,
+    },
+    /// The machine faulted, or with break_on_error is about to fault on the next instruction
+    Fault(Chip8Err),
+    /// The program ran 00FD or jumped to itself
+    Halted,
+    /// The frame finished
+    FrameEnd,
+    /// The instruction limit ran out
+    Limit,
+}
+
+/// The memory an instruction is about to read or write, as (access, start, len)
+fn memory_accesses(instruction: &Instruction, state: &Chip8State, quirks: &Quirks)
+                   -> Vec<(Access, usize, usize)> {
+    let i = state.address_register as usize;
+    let depth = state.stack.len();
+    match *instruction {
+        Instruction::Call(_) if quirks.vip_stack => vec![(Access::Write, vip_stack_address(depth), 2)],
+        Instruction::Ret if quirks.vip_stack && depth > 0 => {
+            vec![(Access::Read, vip_stack_address(depth - 1), 2)]
+        }
+        Instruction::Drw { n, .. } => {
+            let len = if n == 0 && state.mode != Mode::Chip8 { 32 } else { n as usize };
+            let planes = (state.planes & 1) as usize + (state.planes >> 1 & 1) as usize;
+            vec![(Access::Read, i, len * planes)]
+        }
+        Instruction::LdB(_) => vec![(Access::Write, i, 3)],
+        Instruction::LdIVx(x) => vec![(Access::Write, i, x as usize + 1)],
+        Instruction::LdVxI(x) => vec![(Access::Read, i, x as usize + 1)],
+        Instruction::SaveRange { x, y } => {
+            vec![(Access::Write, i, (x as isize - y as isize).abs() as usize + 1)]
+        }
+        Instruction::LoadRange { x, y } => {
+            vec![(Access::Read, i, (x as isize - y as isize).abs() as usize + 1)]
+        }
+        Instruction::Audio => vec![(Access::Read, i, 16)],
+        _ => Vec::new(),
+    }
+}
+
+/// The fault instruction is going to stop the machine with, as far as it can be told beforehand
+fn predicted_fault(instruction: &Instruction, state: &Chip8State, quirks: &Quirks) -> Option<Chip8Err> {
+    let depth = state.stack.len();
+    match *instruction {
+        Instruction::Ret if depth == 0 => return Some(Chip8Err::StackUnderFlow),
+        Instruction::Call(_) if depth >= quirks.stack_depth => return Some(Chip8Err::StackOverflow),
+        Instruction::Ret | Instruction::Call(_) if quirks.vip_stack => {
+            let depth = if *instruction == Instruction::Ret { depth - 1 } else { depth };
+            if let Err(err) = state.checked_memory(vip_stack_address(depth), 2) {
+                return Some(err);
+            }
+        }
+        _ => {}
+    }
+    memory_accesses(instruction, state, quirks)
+        .into_iter()
+        .filter_map(|(_, start, len)| state.checked_memory(start, len).err())
+        .next()
+}
+
+/// Why a machine that can't run another instruction stopped
+fn stopped(state: &RunState) -> StopReason {
+    match *state {
+        RunState::Halted(_) => StopReason::Halted,
+        RunState::Faulted { err, .. } => StopReason::Fault(err),
+        _ => StopReason::Fault(Chip8Err::BadState),
+    }
+}
+
+/// Runs a machine an instruction at a time, stopping on breakpoints, watchpoints and faults
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Stop before an instruction that would fault instead of after it faulted the machine
+    ///
+    /// Decoding, the stack and memory are checked beforehand. A 0NNN routine can still fault.
+    pub break_on_error: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.push(Breakpoint::at(address));
+    }
+    /// Removes every breakpoint on address
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.retain(|breakpoint| breakpoint.address != Some(address));
+    }
+    /// Runs instructions until stop returns true, a breakpoint or watchpoint is hit,
+    /// the machine faults or limit instructions have run
+    ///
+    /// Breakpoints aren't checked before the first instruction so a machine sitting on one
+    /// can be resumed.
+    fn run<T, A, F>(&mut self, chip8: &mut Chip8<T, A>, limit: u64, mut stop: F) -> StopReason
+        where T: KeyWrapper,
+              A: AudioWrapper,
+              F: FnMut(&Chip8<T, A>, &Chip8State) -> bool
+    {
+        for executed in 0..limit {
+            let accesses = {
+                let state = match chip8.state.running() {
+                    Some(state) => state,
+                    None => return stopped(&chip8.state),
+                };
+                if executed > 0 {
+                    if let Some(breakpoint) = self.breakpoints.iter().find(|breakpoint| breakpoint.hit(state)) {
+                        return StopReason::Breakpoint(*breakpoint);
+                    }
+                }
+                let program_counter = state.program_counter as usize;
+                match Instruction::fetch(&state.memory, program_counter, state.mode, &chip8.quirks) {
+                    Ok(instruction) => {
+                        if self.break_on_error {
+                            if let Some(err) = predicted_fault(&instruction, state, &chip8.quirks) {
+                                return StopReason::Fault(err);
+                            }
+                        }
+                        memory_accesses(&instruction, state, &chip8.quirks)
+                    }
+                    Err(err) if self.break_on_error => return StopReason::Fault(err),
+                    Err(_) => Vec::new(),
+                }
+            };
+            if let Err(err) = chip8.step() {
+                return StopReason::Fault(err);
+            }
+            for &(access, start, len) in &accesses {
+                for watchpoint in &self.watchpoints {
+                    let watched = match access {
+                        Access::Read => watchpoint.read,
+                        Access::Write => watchpoint.write,
+                    };
+                    let first = start.max(watchpoint.start);
+                    if watched && first < start + len && first < watchpoint.start + watchpoint.len {
+                        return StopReason::Watchpoint {
+                            watchpoint: *watchpoint,
+                            access: access,
+                            address: first,
+                        };
+                    }
+                }
+            }
+            let state = match chip8.state.running() {
+                Some(state) => state,
+                None => return stopped(&chip8.state),
+            };
+            if stop(chip8, state) {
+                return StopReason::Step;
+            }
+        }
+        StopReason::Limit
+    }
+    /// Runs a single instruction
+    pub fn step<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &mut Chip8<T, A>) -> StopReason {
+        self.run(chip8, 1, |_, _| true)
+    }
+    /// Runs a single instruction, treating a 2NNN call and the whole subroutine as one
+    pub fn step_over<T, A>(&mut self, chip8: &mut Chip8<T, A>, limit: u64) -> StopReason
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let (return_address, depth) = match chip8.state.running() {
+            Some(state) => {
+                match Instruction::read(&state.memory, state.program_counter as usize) {
+                    Ok(Instruction::Call(_)) => (state.program_counter + 2, state.stack.len()),
+                    _ => return self.step(chip8),
+                }
+            }
+            None => return stopped(&chip8.state),
+        };
+        self.run(chip8, limit, |_, state| {
+            state.program_counter == return_address && state.stack.len() == depth
+        })
+    }
+    /// Runs until the current subroutine returns with 00EE
+    pub fn step_out<T, A>(&mut self, chip8: &mut Chip8<T, A>, limit: u64) -> StopReason
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let depth = match chip8.state.running() {
+            Some(state) => state.stack.len(),
+            None => return stopped(&chip8.state),
+        };
+        self.run(chip8, limit, |_, state| state.stack.len() < depth)
+    }
+    /// Runs until something stops the machine
+    pub fn cont<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &mut Chip8<T, A>, limit: u64)
+        -> StopReason {
+        self.run(chip8, limit, |_, _| false)
+    }
+    /// Runs the rest of the current frame unless something stops the machine first
+    pub fn run_frame<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &mut Chip8<T, A>) -> StopReason {
+        let frame = chip8.frames();
+        match self.run(chip8, u64::max_value(), |chip8, _| chip8.frames() != frame) {
+            StopReason::Step => StopReason::FrameEnd,
+            reason => reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Chip8Err;
+    use Quirks;
+    use testing::machine;
+    use super::{Access, Debugger, StopReason, Watchpoint};
+
+    #[test]
+    fn jump_to_self_halts() {
+        let mut chip8 = machine(&[0x60, 0x01, 0x12, 0x02], Quirks::default());
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Halted);
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Halted);
+        assert_eq!(debugger.step(&mut chip8), StopReason::Halted);
+    }
+
+    #[test]
+    fn faults_stop() {
+        let mut chip8 = machine(&[0x00, 0xEE], Quirks::default());
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Fault(Chip8Err::StackUnderFlow));
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Fault(Chip8Err::StackUnderFlow));
+    }
+
+    #[test]
+    fn break_on_error_stops_before() {
+        let mut debugger = Debugger::new();
+        debugger.break_on_error = true;
+        // V0 := 1, return with nothing on the stack
+        let mut chip8 = machine(&[0x60, 0x01, 0x00, 0xEE], Quirks::default());
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Fault(Chip8Err::StackUnderFlow));
+        assert!(chip8.state.is_running());
+        assert_eq!(chip8.program_counter, 0x202);
+        // I := 0xFFE, save v3
+        let mut chip8 = machine(&[0xAF, 0xFE, 0xF3, 0x55], Quirks::default());
+        assert_eq!(debugger.cont(&mut chip8, 10),
+                   StopReason::Fault(Chip8Err::MemoryOutOfBounds { addr: 0x1000 }));
+        assert!(chip8.state.is_running());
+        // A subroutine that calls itself
+        let mut chip8 = machine(&[0x22, 0x00], Quirks::default());
+        assert_eq!(debugger.cont(&mut chip8, 100), StopReason::Fault(Chip8Err::StackOverflow));
+        assert_eq!(chip8.stack.len(), 16);
+    }
+
+    #[test]
+    fn vip_stack_watchpoints() {
+        // Call 0x206, halt, then return
+        let program = [0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE];
+        let mut chip8 = machine(&program, Quirks::cosmac_vip());
+        let mut debugger = Debugger::new();
+        debugger.watchpoints.push(Watchpoint {
+            start: 0xEA0,
+            len: 0x30,
+            read: true,
+            write: true,
+        });
+        assert_eq!(debugger.cont(&mut chip8, 10),
+                   StopReason::Watchpoint {
+                       watchpoint: debugger.watchpoints[0],
+                       access: Access::Write,
+                       address: 0xECE,
+                   });
+        assert_eq!(debugger.cont(&mut chip8, 10),
+                   StopReason::Watchpoint {
+                       watchpoint: debugger.watchpoints[0],
+                       access: Access::Read,
+                       address: 0xECE,
+                   });
+        let mut chip8 = machine(&program, Quirks::default());
+        assert_eq!(debugger.cont(&mut chip8, 10), StopReason::Halted);
+    }
+}
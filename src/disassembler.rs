@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::prelude::*;
+use Chip8Err;
+use Instruction;
+use Mode;
+use Quirks;
+
+/// The flavour of assembly a listing is written in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// The mnemonics from Cowgod's Chip-8 Technical Reference, like `LD V0, #12`
+    Cowgod,
+    /// The Octo language, like `v0 := 0x12`
+    Octo,
+}
+
+/// One instruction, or one unknown word, of a disassembled program
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    /// The bytes of the instruction as they appear in memory
+    pub bytes: Vec<u8>,
+    /// The instruction, or the error the interpreter would fault with when executing it
+    pub instruction: Result<Instruction, Chip8Err>,
+}
+
+/// A disassembled program
+#[derive(Clone, Debug)]
+pub struct Disassembly {
+    origin: u16,
+    lines: Vec<Line>,
+    labels: BTreeMap<u16, String>,
+}
+
+/// Disassembles a program loaded at origin, decoding optcodes as a machine running in mode
+///
+/// 0NNN is left as data, since the listing can't tell machine code routines from stray words.
+pub fn disassemble(program: &[u8], origin: u16, mode: Mode) -> Disassembly {
+    // Padding lets the last word be fetched as if it was followed by more memory
+    let mut memory = program.to_vec();
+    memory.extend_from_slice(&[0, 0, 0]);
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < program.len() {
+        let remaining = program.len() - offset;
+        let mut instruction = Instruction::fetch(&memory, offset, mode, &Quirks::default());
+        let len = match instruction {
+            Ok(instruction) if instruction.size() as usize <= remaining => instruction.size() as usize,
+            _ if remaining < 2 => {
+                instruction = Err(Chip8Err::UnknownOptcode);
+                1
+            }
+            Ok(_) => {
+                instruction = Err(Chip8Err::UnknownOptcode);
+                2
+            }
+            Err(_) => 2,
+        };
+        lines.push(Line {
+            address: origin.wrapping_add(offset as u16),
+            bytes: program[offset..offset + len].to_vec(),
+            instruction: instruction,
+        });
+        offset += len;
+    }
+    // Only targets that start a line get a name, since that is where the listing can define it
+    let starts: BTreeSet<u16> = lines.iter().map(|line| line.address).collect();
+    let mut labels = BTreeMap::new();
+    for line in &lines {
+        match line.instruction {
+            Ok(Instruction::Call(address)) |
+            Ok(Instruction::Jp(address)) |
+            Ok(Instruction::JpV0(address)) if !starts.contains(&address) => {}
+            Ok(Instruction::Call(address)) => {
+                labels.insert(address, format!("sub_{:03X}", address));
+            }
+            Ok(Instruction::Jp(address)) |
+            Ok(Instruction::JpV0(address)) => {
+                labels.entry(address).or_insert_with(|| format!("label_{:03X}", address));
+            }
+            _ => {}
+        }
+    }
+    Disassembly {
+        origin: origin,
+        lines: lines,
+        labels: labels,
+    }
+}
+
+impl Disassembly {
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+    /// The generated names of jump and call targets
+    pub fn labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+    /// Writes the listing, with the address and raw bytes of each line in a comment
+    pub fn write<W: Write>(&self, output: &mut W, syntax: Syntax) -> io::Result<()> {
+        let comment = match syntax {
+            Syntax::Cowgod => ";",
+            Syntax::Octo => "#",
+        };
+        if self.origin != 0x200 {
+            match syntax {
+                Syntax::Cowgod => try!(writeln!(output, "    ORG #{:03X}", self.origin)),
+                Syntax::Octo => try!(writeln!(output, ":org 0x{:03X}", self.origin)),
+            }
+        }
+        for line in &self.lines {
+            if let Some(label) = self.labels.get(&line.address) {
+                match syntax {
+                    Syntax::Cowgod => try!(writeln!(output, "{}:", label)),
+                    Syntax::Octo => try!(writeln!(output, ": {}", label)),
+                }
+            }
+            let raw: String = line.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let text = match line.instruction {
+                Ok(ref instruction) => format_instruction(instruction, syntax, &self.labels),
+                Err(_) => format_data(&line.bytes, syntax),
+            };
+            try!(write!(output, "    {:<24}{} {:04X}: {}", text, comment, line.address, raw));
+            if let Err(err) = line.instruction {
+                try!(write!(output, " {}", err));
+            }
+            try!(writeln!(output, ""));
+        }
+        Ok(())
+    }
+}
+
+fn format_data(bytes: &[u8], syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod if bytes.len() == 2 => format!("DW #{:02X}{:02X}", bytes[0], bytes[1]),
+        Syntax::Cowgod => format!("DB #{:02X}", bytes[0]),
+        Syntax::Octo => {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+            bytes.join(" ")
+        }
+    }
+}
+
+/// Formats an instruction, naming addresses found in labels
+pub fn format_instruction(instruction: &Instruction,
+                          syntax: Syntax,
+                          labels: &BTreeMap<u16, String>)
+                          -> String {
+    match syntax {
+        Syntax::Cowgod => format_cowgod(instruction, labels),
+        Syntax::Octo => format_octo(instruction, labels),
+    }
+}
+
+fn format_cowgod(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    use Instruction::*;
+    let address = |address: u16| {
+        labels.get(&address).cloned().unwrap_or_else(|| format!("#{:03X}", address))
+    };
+    match *instruction {
+        Sys(nnn) => format!("SYS {}", address(nnn)),
+        Cls => "CLS".to_string(),
+        Ret => "RET".to_string(),
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        Lores => "LOW".to_string(),
+        Hires => "HIGH".to_string(),
+        Jp(nnn) => format!("JP {}", address(nnn)),
+        Call(nnn) => format!("CALL {}", address(nnn)),
+        SeByte { x, byte } => format!("SE V{:X}, #{:02X}", x, byte),
+        SneByte { x, byte } => format!("SNE V{:X}, #{:02X}", x, byte),
+        SeReg { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+        LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+        LdByte { x, byte } => format!("LD V{:X}, #{:02X}", x, byte),
+        AddByte { x, byte } => format!("ADD V{:X}, #{:02X}", x, byte),
+        LdReg { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        Shr { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        Subn { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        Shl { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        SneReg { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        LdI(nnn) => format!("LD I, {}", address(nnn)),
+        JpV0(nnn) => format!("JP V0, {}", address(nnn)),
+        Rnd { x, byte } => format!("RND V{:X}, #{:02X}", x, byte),
+        Drw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Skp(x) => format!("SKP V{:X}", x),
+        Sknp(x) => format!("SKNP V{:X}", x),
+        LdLongI(nnnn) => format!("LDL I, #{:04X}", nnnn),
+        Plane(n) => format!("PLANE {}", n),
+        Audio => "AUDIO".to_string(),
+        LdVxDt(x) => format!("LD V{:X}, DT", x),
+        LdVxK(x) => format!("LD V{:X}, K", x),
+        LdDtVx(x) => format!("LD DT, V{:X}", x),
+        LdStVx(x) => format!("LD ST, V{:X}", x),
+        AddI(x) => format!("ADD I, V{:X}", x),
+        LdF(x) => format!("LD F, V{:X}", x),
+        LdHf(x) => format!("LD HF, V{:X}", x),
+        LdB(x) => format!("LD B, V{:X}", x),
+        Pitch(x) => format!("PITCH V{:X}", x),
+        LdIVx(x) => format!("LD [I], V{:X}", x),
+        LdVxI(x) => format!("LD V{:X}, [I]", x),
+        LdRVx(x) => format!("LD R, V{:X}", x),
+        LdVxR(x) => format!("LD V{:X}, R", x),
+    }
+}
+
+fn format_octo(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    use Instruction::*;
+    let address = |address: u16| {
+        labels.get(&address).cloned().unwrap_or_else(|| format!("0x{:03X}", address))
+    };
+    match *instruction {
+        // Octo has no mnemonic for machine code calls
+        Sys(nnn) => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xFF),
+        Cls => "clear".to_string(),
+        Ret => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Lores => "lores".to_string(),
+        Hires => "hires".to_string(),
+        Jp(nnn) => format!("jump {}", address(nnn)),
+        Call(nnn) => {
+            match labels.get(&nnn) {
+                Some(label) => label.clone(),
+                None => format!(":call 0x{:03X}", nnn),
+            }
+        }
+        // Octo spells skips as the condition under which the next instruction runs
+        SeByte { x, byte } => format!("if v{:x} != 0x{:02X} then", x, byte),
+        SneByte { x, byte } => format!("if v{:x} == 0x{:02X} then", x, byte),
+        SeReg { x, y } => format!("if v{:x} != v{:x} then", x, y),
+        SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+        LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+        LdByte { x, byte } => format!("v{:x} := 0x{:02X}", x, byte),
+        AddByte { x, byte } => format!("v{:x} += 0x{:02X}", x, byte),
+        LdReg { x, y } => format!("v{:x} := v{:x}", x, y),
+        Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+        And { x, y } => format!("v{:x} &= v{:x}", x, y),
+        Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+        Add { x, y } => format!("v{:x} += v{:x}", x, y),
+        Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+        Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+        Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+        Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+        SneReg { x, y } => format!("if v{:x} == v{:x} then", x, y),
+        LdI(nnn) => format!("i := {}", address(nnn)),
+        JpV0(nnn) => format!("jump0 {}", address(nnn)),
+        Rnd { x, byte } => format!("v{:x} := random 0x{:02X}", x, byte),
+        Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Skp(x) => format!("if v{:x} -key then", x),
+        Sknp(x) => format!("if v{:x} key then", x),
+        LdLongI(nnnn) => format!("i := long 0x{:04X}", nnnn),
+        Plane(n) => format!("plane {}", n),
+        Audio => "audio".to_string(),
+        LdVxDt(x) => format!("v{:x} := delay", x),
+        LdVxK(x) => format!("v{:x} := key", x),
+        LdDtVx(x) => format!("delay := v{:x}", x),
+        LdStVx(x) => format!("buzzer := v{:x}", x),
+        AddI(x) => format!("i += v{:x}", x),
+        LdF(x) => format!("i := hex v{:x}", x),
+        LdHf(x) => format!("i := bighex v{:x}", x),
+        LdB(x) => format!("bcd v{:x}", x),
+        Pitch(x) => format!("pitch := v{:x}", x),
+        LdIVx(x) => format!("save v{:x}", x),
+        LdVxI(x) => format!("load v{:x}", x),
+        LdRVx(x) => format!("saveflags v{:x}", x),
+        LdVxR(x) => format!("loadflags v{:x}", x),
+    }
+}
@@ -0,0 +1,99 @@
+//! The context of a machine fault, for error messages and crash reports
+
+use Chip8Err;
+use Chip8State;
+use Instruction;
+use disassembler::{self, Syntax};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+/// How many of the addresses run before a fault are remembered
+pub const HISTORY_LEN: usize = 16;
+
+/// What the machine was doing when it faulted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    pub error: Chip8Err,
+    /// The address of the instruction that faulted
+    pub program_counter: u16,
+    /// The word at the program counter, unless it is outside memory
+    pub optcode: Option<u16>,
+    /// The number of instructions run before the fault
+    pub cycle: u64,
+    pub frame: u64,
+    /// The addresses of the last instructions run, oldest first
+    pub history: Vec<u16>,
+}
+
+/// The instruction at address in cowgod syntax, or ??? if there isn't one
+fn mnemonic(state: &Chip8State, address: u16) -> String {
+    match Instruction::read(&state.memory, address as usize) {
+        Ok(instruction) => {
+            disassembler::format_instruction(&instruction, Syntax::Cowgod, &BTreeMap::new())
+        }
+        Err(_) => "???".to_string(),
+    }
+}
+
+impl Fault {
+    /// Writes a human readable crash report, including the registers and the instructions of
+    /// state when it is the state the machine faulted in
+    pub fn write_report<W: Write>(&self, output: &mut W, state: Option<&Chip8State>) -> io::Result<()> {
+        try!(writeln!(output, "{}", self));
+        let state = match state {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        try!(writeln!(output, "Instruction: {}", mnemonic(state, self.program_counter)));
+        try!(writeln!(output, ""));
+        try!(writeln!(output, "Registers:"));
+        for (x, value) in state.data_registers.iter().enumerate() {
+            try!(write!(output, " V{:X}={:02X}", x, value));
+        }
+        try!(writeln!(output,
+                      "\n I={:04X} DT={:02X} ST={:02X} mode={:?}",
+                      state.address_register,
+                      state.delay_timer,
+                      state.sound_timer,
+                      state.mode));
+        try!(writeln!(output, "Stack:"));
+        for (depth, address) in state.stack.iter().enumerate().rev() {
+            try!(writeln!(output, " {:2}: {:04X}", depth, address));
+        }
+        try!(writeln!(output, "Last instructions:"));
+        for &address in &self.history {
+            try!(writeln!(output, " {:04X}: {}", address, mnemonic(state, address)));
+        }
+        try!(writeln!(output, "Memory at I:"));
+        let start = state.address_register as usize;
+        let end = cmp::min(start + 16, state.memory.len());
+        try!(write!(output, " {:04X}:", start));
+        for byte in state.memory.get(start..end).unwrap_or(&[]) {
+            try!(write!(output, " {:02X}", byte));
+        }
+        writeln!(output, "")
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} at {:04X}", error::Error::description(&self.error), self.program_counter));
+        if let Some(optcode) = self.optcode {
+            try!(write!(f, " (optcode {:04X})", optcode));
+        }
+        write!(f, " on cycle {}, frame {}", self.cycle, self.frame)
+    }
+}
+
+impl error::Error for Fault {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&self.error)
+    }
+}
@@ -0,0 +1,468 @@
+//! A GDB remote serial protocol stub driving a Chip8
+//!
+//! The registers are V0 through VF (one byte each), I and PC (two bytes each, little endian),
+//! then SP (the stack depth), DT and ST (one byte each).
+
+use AudioWrapper;
+use Chip8;
+use Chip8Err;
+use KeyWrapper;
+use debugger::{Breakpoint, Debugger, StopReason, Watchpoint};
+use std::io;
+use std::io::prelude::*;
+
+const REGISTER_COUNT: usize = 21;
+
+const TARGET_XML: &'static str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.chip8.core\">\
+<reg name=\"v0\" bitsize=\"8\" regnum=\"0\"/><reg name=\"v1\" bitsize=\"8\"/>\
+<reg name=\"v2\" bitsize=\"8\"/><reg name=\"v3\" bitsize=\"8\"/>\
+<reg name=\"v4\" bitsize=\"8\"/><reg name=\"v5\" bitsize=\"8\"/>\
+<reg name=\"v6\" bitsize=\"8\"/><reg name=\"v7\" bitsize=\"8\"/>\
+<reg name=\"v8\" bitsize=\"8\"/><reg name=\"v9\" bitsize=\"8\"/>\
+<reg name=\"va\" bitsize=\"8\"/><reg name=\"vb\" bitsize=\"8\"/>\
+<reg name=\"vc\" bitsize=\"8\"/><reg name=\"vd\" bitsize=\"8\"/>\
+<reg name=\"ve\" bitsize=\"8\"/><reg name=\"vf\" bitsize=\"8\"/>\
+<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+<reg name=\"sp\" bitsize=\"8\"/><reg name=\"dt\" bitsize=\"8\"/><reg name=\"st\" bitsize=\"8\"/>\
+</feature></target>";
+
+/// Serves one GDB connection
+pub struct GdbServer<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+    pub debugger: Debugger,
+    no_ack: bool,
+    last_stop: StopReason,
+    /// Called between frames while continuing, returning true if GDB asked to interrupt
+    pub interrupted: Option<Box<FnMut() -> bool>>,
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn decode_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    text.chunks(2)
+        .map(|pair| hex_digit(pair[0]).and_then(|high| hex_digit(pair[1]).map(|low| high << 4 | low)))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_number(text: &[u8]) -> Option<usize> {
+    ::std::str::from_utf8(text).ok().and_then(|text| usize::from_str_radix(text, 16).ok())
+}
+
+/// Splits "addr,len" into numbers
+fn parse_pair(text: &[u8]) -> Option<(usize, usize)> {
+    text.iter().position(|&byte| byte == b',').and_then(|comma| {
+        parse_number(&text[..comma])
+            .and_then(|first| parse_number(&text[comma + 1..]).map(|second| (first, second)))
+    })
+}
+
+/// Whether len bytes from address are all in memory
+fn fits(address: usize, len: usize, memory: &[u8]) -> bool {
+    address.checked_add(len).map_or(false, |end| end <= memory.len())
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match *reason {
+        StopReason::Fault(Chip8Err::BadState) |
+        StopReason::Halted => "W00".to_string(),
+        // SIGSEGV
+        StopReason::Fault(Chip8Err::MemoryOutOfBounds { .. }) |
+        StopReason::Fault(Chip8Err::PcOutOfBounds) => "S0b".to_string(),
+        // SIGILL
+        StopReason::Fault(_) => "S04".to_string(),
+        StopReason::Watchpoint { watchpoint, address, .. } => {
+            let kind = match (watchpoint.read, watchpoint.write) {
+                (true, true) => "awatch",
+                (true, false) => "rwatch",
+                _ => "watch",
+            };
+            format!("T05{}:{:x};", kind, address)
+        }
+        StopReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+        // SIGTRAP
+        _ => "S05".to_string(),
+    }
+}
+
+impl<R: Read, W: Write> GdbServer<R, W> {
+    pub fn new(reader: R, writer: W) -> GdbServer<R, W> {
+        GdbServer {
+            reader: reader,
+            writer: writer,
+            debugger: Debugger::new(),
+            no_ack: false,
+            last_stop: StopReason::Step,
+            interrupted: None,
+        }
+    }
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match try!(self.reader.read(&mut byte)) {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+    /// Reads the next packet, returning None when the connection closes
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match try!(self.read_byte()) {
+                None => return Ok(None),
+                Some(b'$') => {}
+                // Acks and interrupts outside of a continue are ignored
+                Some(_) => continue,
+            }
+            let mut packet = Vec::new();
+            let mut checksum: u8 = 0;
+            loop {
+                match try!(self.read_byte()) {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        checksum = checksum.wrapping_add(byte);
+                        packet.push(byte);
+                    }
+                }
+            }
+            let mut sent = [0; 2];
+            try!(self.reader.read_exact(&mut sent));
+            let matches = decode_hex(&sent).map_or(false, |sent| sent[0] == checksum);
+            if !self.no_ack {
+                try!(self.writer.write_all(if matches { b"+" } else { b"-" }));
+                try!(self.writer.flush());
+            }
+            if matches {
+                return Ok(Some(packet));
+            }
+        }
+    }
+    fn send(&mut self, packet: &str) -> io::Result<()> {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        try!(write!(self.writer, "${}#{:02x}", packet, checksum));
+        try!(self.writer.flush());
+        if !self.no_ack {
+            // Wait for the ack, resending on a nack
+            loop {
+                match try!(self.read_byte()) {
+                    Some(b'+') | None => break,
+                    Some(b'-') => {
+                        try!(write!(self.writer, "${}#{:02x}", packet, checksum));
+                        try!(self.writer.flush());
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+    fn registers<T: KeyWrapper, A: AudioWrapper>(chip8: &Chip8<T, A>) -> Vec<u8> {
+        match chip8.state.state() {
+            Some(state) => {
+                let mut registers = state.data_registers.to_vec();
+                registers.push(state.address_register as u8);
+                registers.push((state.address_register >> 8) as u8);
+                registers.push(state.program_counter as u8);
+                registers.push((state.program_counter >> 8) as u8);
+                registers.push(state.stack.len() as u8);
+                registers.push(state.delay_timer);
+                registers.push(state.sound_timer);
+                registers
+            }
+            None => vec![0; REGISTER_COUNT + 2],
+        }
+    }
+    fn set_register<T, A>(chip8: &mut Chip8<T, A>, register: usize, value: &[u8]) -> bool
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let state = match chip8.state.state_mut() {
+            Some(state) => state,
+            None => return false,
+        };
+        let word = value[0] as u16 | value.get(1).map_or(0, |&high| (high as u16) << 8);
+        match register {
+            0...15 => state.data_registers[register] = value[0],
+            16 => state.address_register = word,
+            17 => state.program_counter = word,
+            // The stack depth is read only
+            18 => return value[0] as usize == state.stack.len(),
+            19 => state.delay_timer = value[0],
+            20 => state.sound_timer = value[0],
+            _ => return false,
+        }
+        true
+    }
+    /// Continues until a stop, checking for an interrupt between frames
+    fn cont<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &mut Chip8<T, A>) -> StopReason {
+        loop {
+            match self.debugger.run_frame(chip8) {
+                StopReason::FrameEnd => {}
+                reason => return reason,
+            }
+            if let Some(ref mut interrupted) = self.interrupted {
+                if interrupted() {
+                    return StopReason::Step;
+                }
+            }
+        }
+    }
+    fn handle<T, A>(&mut self, chip8: &mut Chip8<T, A>, packet: &[u8]) -> io::Result<bool>
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        if packet.is_empty() {
+            try!(self.send(""));
+            return Ok(true);
+        }
+        let (command, arguments) = (packet[0], &packet[1..]);
+        let reply = match command {
+            b'?' => stop_reply(&self.last_stop),
+            b'g' => encode_hex(&GdbServer::<R, W>::registers(chip8)),
+            b'G' => {
+                match decode_hex(arguments) {
+                    Some(ref values) if values.len() >= REGISTER_COUNT + 2 => {
+                        let mut register = 0;
+                        let mut index = 0;
+                        while register < REGISTER_COUNT {
+                            let width = if register == 16 || register == 17 { 2 } else { 1 };
+                            // The stack depth can't be changed, so it is skipped
+                            if register != 18 {
+                                GdbServer::<R, W>::set_register(chip8, register,
+                                                                &values[index..index + width]);
+                            }
+                            index += width;
+                            register += 1;
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'p' => {
+                let registers = GdbServer::<R, W>::registers(chip8);
+                match parse_number(arguments) {
+                    Some(16) => encode_hex(&registers[16..18]),
+                    Some(17) => encode_hex(&registers[18..20]),
+                    Some(register) if register < 16 => encode_hex(&registers[register..register + 1]),
+                    Some(register) if register < REGISTER_COUNT => {
+                        encode_hex(&registers[register + 2..register + 3])
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'P' => {
+                let equals = arguments.iter().position(|&byte| byte == b'=');
+                let register = equals.and_then(|equals| parse_number(&arguments[..equals]));
+                let value = equals.and_then(|equals| decode_hex(&arguments[equals + 1..]));
+                match (register, value) {
+                    (Some(register), Some(ref value)) if !value.is_empty() => {
+                        if GdbServer::<R, W>::set_register(chip8, register, value) {
+                            "OK".to_string()
+                        } else {
+                            "E01".to_string()
+                        }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'm' => {
+                match (parse_pair(arguments), chip8.state.state()) {
+                    (Some((address, len)), Some(state)) if fits(address, len, &state.memory) => {
+                        encode_hex(&state.memory[address..address + len])
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'M' => {
+                let colon = arguments.iter().position(|&byte| byte == b':').unwrap_or(arguments.len());
+                let data = decode_hex(if colon < arguments.len() { &arguments[colon + 1..] } else { &[] });
+                match (parse_pair(&arguments[..colon]), data, chip8.state.state_mut()) {
+                    (Some((address, len)), Some(ref data), Some(state))
+                        if data.len() == len && fits(address, len, &state.memory) => {
+                        state.memory[address..address + len].copy_from_slice(data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            b'c' => {
+                if let Some(address) = parse_number(arguments) {
+                    if let Some(state) = chip8.state.state_mut() {
+                        state.program_counter = address as u16;
+                    }
+                }
+                self.last_stop = self.cont(chip8);
+                stop_reply(&self.last_stop)
+            }
+            b's' => {
+                if let Some(address) = parse_number(arguments) {
+                    if let Some(state) = chip8.state.state_mut() {
+                        state.program_counter = address as u16;
+                    }
+                }
+                self.last_stop = self.debugger.step(chip8);
+                stop_reply(&self.last_stop)
+            }
+            b'Z' | b'z' => {
+                let insert = command == b'Z';
+                let kind = arguments.get(0).cloned().unwrap_or(0);
+                let fields = &arguments[if arguments.len() > 2 { 2 } else { arguments.len() }..];
+                match (kind, parse_pair(fields)) {
+                    (b'0', Some((address, _))) | (b'1', Some((address, _))) => {
+                        self.debugger.remove_breakpoint(address as u16);
+                        if insert {
+                            self.debugger.breakpoints.push(Breakpoint::at(address as u16));
+                        }
+                        "OK".to_string()
+                    }
+                    (b'2', Some((address, len))) |
+                    (b'3', Some((address, len))) |
+                    (b'4', Some((address, len))) => {
+                        let watchpoint = Watchpoint {
+                            start: address,
+                            len: len,
+                            read: kind != b'2',
+                            write: kind != b'3',
+                        };
+                        self.debugger.watchpoints.retain(|other| *other != watchpoint);
+                        if insert {
+                            self.debugger.watchpoints.push(watchpoint);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            b'q' => {
+                if arguments.starts_with(b"Supported") {
+                    "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string()
+                } else if arguments.starts_with(b"Attached") {
+                    "1".to_string()
+                } else if arguments.starts_with(b"C") {
+                    "QC1".to_string()
+                } else if arguments.starts_with(b"fThreadInfo") {
+                    "m1".to_string()
+                } else if arguments.starts_with(b"sThreadInfo") {
+                    "l".to_string()
+                } else if arguments.starts_with(b"Xfer:features:read:target.xml:") {
+                    let range = &arguments[b"Xfer:features:read:target.xml:".len()..];
+                    match parse_pair(range) {
+                        Some((offset, len)) => {
+                            let xml = TARGET_XML.as_bytes();
+                            let start = offset.min(xml.len());
+                            let end = (offset + len).min(xml.len());
+                            let prefix = if end < xml.len() { "m" } else { "l" };
+                            format!("{}{}", prefix, String::from_utf8_lossy(&xml[start..end]))
+                        }
+                        None => "E01".to_string(),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+            b'Q' if arguments.starts_with(b"StartNoAckMode") => {
+                try!(self.send("OK"));
+                self.no_ack = true;
+                return Ok(true);
+            }
+            b'H' | b'T' => "OK".to_string(),
+            b'D' => {
+                try!(self.send("OK"));
+                return Ok(false);
+            }
+            b'k' => return Ok(false),
+            _ => String::new(),
+        };
+        try!(self.send(&reply));
+        Ok(true)
+    }
+    /// Answers packets until GDB detaches, kills the target or disconnects
+    pub fn serve<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &mut Chip8<T, A>) -> io::Result<()> {
+        while let Some(packet) = try!(self.read_packet()) {
+            if !try!(self.handle(chip8, &packet)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use AudioWrapper;
+    use Chip8;
+    use KeyWrapper;
+    use super::GdbServer;
+
+    struct NoKeys;
+
+    impl KeyWrapper for NoKeys {
+        fn is_pushed(&self, _: u8) -> bool {
+            false
+        }
+        fn get_key(&self) -> Option<u8> {
+            None
+        }
+    }
+
+    struct Silence;
+
+    impl AudioWrapper for Silence {
+        fn play(&mut self) {}
+        fn stop(&mut self) {}
+    }
+
+    fn packet(body: &str) -> String {
+        let checksum = body.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${}#{:02x}", body, checksum)
+    }
+
+    /// Plays a GDB session of packets against a machine running program, returning the replies
+    fn session(program: &[u8], packets: &[&str]) -> Vec<String> {
+        let mut chip8 = Chip8::new(NoKeys, Silence);
+        chip8.load_prog(&mut &program[..]).unwrap();
+        // The first packet is acked both ways, after which acks are switched off
+        let mut input = format!("{}+", packet("QStartNoAckMode"));
+        for body in packets {
+            input.push_str(&packet(body));
+        }
+        let mut output = Vec::new();
+        GdbServer::new(input.as_bytes(), &mut output).serve(&mut chip8).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("+$OK#"), "{}", output);
+        output.split('$')
+            .skip(2)
+            .map(|reply| {
+                let (body, checksum) = reply.split_at(reply.find('#').unwrap());
+                assert_eq!(packet(body), format!("${}{}", body, checksum));
+                body.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn memory_and_registers() {
+        let replies = session(&[0x60, 0x12, 0x00, 0xEE],
+                              &["m200,4", "M300,2:abcd", "m300,2", "s", "p0", "P0=34", "p0", "?", "k"]);
+        assert_eq!(replies, vec!["601200ee", "OK", "abcd", "S05", "12", "OK", "34", "S05"]);
+    }
+
+    #[test]
+    fn out_of_range_memory() {
+        let replies = session(&[0x12, 0x00],
+                              &["mffffffffffffffff,10", "Mffffffffffffffff,1:00", "mfff,2", "m1000,0", "D"]);
+        assert_eq!(replies, vec!["E01", "E01", "E01", "", "OK"]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Spotting loops that can't get anywhere until the timers tick
+//!
+//! A short loop is idle when a pass through it leaves the registers as they were and nothing in
+//! it writes memory, draws, sets a timer or reads the keys. Until the delay timer ticks, every
+//! later pass does exactly the same, which is how most programs wait for the next frame.
+
+use Chip8State;
+use Instruction;
+
+/// The furthest back, in bytes, a jump can go and still close a loop worth watching
+const MAX_LOOP_LEN: u16 = 16;
+
+/// A loop from start to the jump back at end, with the registers the jump was last reached with
+#[derive(Clone)]
+struct Candidate {
+    start: u16,
+    end: u16,
+    registers: [u8; 16],
+    address_register: u16,
+}
+
+/// Watches the instructions a machine runs for an idle loop
+#[derive(Clone, Default)]
+pub struct LoopWatch {
+    candidate: Option<Candidate>,
+}
+
+impl LoopWatch {
+    /// Looks at the instruction about to run on state
+    ///
+    /// Returns whether the machine is idle, or None if the instruction doesn't change that.
+    pub fn observe(&mut self, instruction: Option<&Instruction>, state: &Chip8State) -> Option<bool> {
+        let program_counter = state.program_counter;
+        match instruction {
+            Some(&Instruction::Jp(start)) if start <= program_counter &&
+                                             program_counter - start <= MAX_LOOP_LEN => {
+                let idle = self.candidate.as_ref().map_or(false, |candidate| {
+                    candidate.start == start && candidate.end == program_counter &&
+                    candidate.registers == state.data_registers &&
+                    candidate.address_register == state.address_register
+                });
+                self.candidate = Some(Candidate {
+                    start: start,
+                    end: program_counter,
+                    registers: state.data_registers,
+                    address_register: state.address_register,
+                });
+                Some(idle)
+            }
+            Some(instruction) if is_pure(instruction) &&
+                                 self.candidate.as_ref().map_or(false, |candidate| {
+                candidate.start <= program_counter && program_counter < candidate.end
+            }) => None,
+            _ => {
+                self.candidate = None;
+                Some(false)
+            }
+        }
+    }
+    /// Forgets the loop, since the timers ticking can change where it goes
+    pub fn reset(&mut self) {
+        self.candidate = None;
+    }
+}
+
+/// Whether instruction only reads memory and the timers, and only writes registers
+///
+/// Key presses can come at any time, so a loop that checks the keys is never idle.
+fn is_pure(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    match *instruction {
+        SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } |
+        LdByte { .. } | AddByte { .. } | LdReg { .. } | Or { .. } | And { .. } | Xor { .. } |
+        Add { .. } | Sub { .. } | Shr { .. } | Subn { .. } | Shl { .. } | LdI(_) | LdLongI(_) |
+        AddI(_) | LdF(_) | LdHf(_) | LdVxDt(_) | LdVxI(_) | LoadRange { .. } | LdVxR(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use AudioWrapper;
+    use Chip8;
+    use KeyWrapper;
+    use Quirks;
+    use RunState;
+    use testing::{machine, run};
+
+    /// Sets the delay timer to 5 and polls it until it runs out, then jumps to itself
+    const WAIT: [u8; 12] = [0x60, 0x05, 0xF0, 0x15, 0xF0, 0x07, 0x30, 0x00, 0x12, 0x04, 0x12, 0x0A];
+
+    fn is_idle<T: KeyWrapper, A: AudioWrapper>(chip8: &Chip8<T, A>) -> bool {
+        match chip8.state {
+            RunState::Idle(_) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn delay_timer_poll_is_idle() {
+        let mut chip8 = machine(&WAIT, Quirks::default());
+        chip8.skip_idle = true;
+        // The first pass through the loop only finds it
+        run(&mut chip8, 2 + 3);
+        assert!(!is_idle(&chip8));
+        run(&mut chip8, 3);
+        assert!(is_idle(&chip8));
+        assert_eq!((chip8.cycles(), chip8.frames()), (8, 0));
+        // The rest of the frame goes by in one step, with nothing run
+        run(&mut chip8, 1);
+        assert!(!is_idle(&chip8));
+        assert_eq!((chip8.cycles(), chip8.frames(), chip8.frame_cycle()), (8, 1, 0));
+        assert_eq!(chip8.delay_timer, 4);
+
+        let mut slow = machine(&WAIT, Quirks::default());
+        while !slow.state.is_halted() {
+            slow.run_vblank().unwrap();
+        }
+        while !chip8.state.is_halted() {
+            chip8.run_vblank().unwrap();
+        }
+        assert_eq!(chip8.frames(), slow.frames());
+        assert!(chip8.cycles() < slow.cycles());
+    }
+
+    #[test]
+    fn key_loops_are_not_idle() {
+        // Waits for key 0 with EX9E, then with FX0A
+        let mut chip8 = machine(&[0xE0, 0x9E, 0x12, 0x00], Quirks::default());
+        chip8.skip_idle = true;
+        for _ in 0..30 {
+            run(&mut chip8, 1);
+            assert!(!is_idle(&chip8));
+        }
+        assert_eq!(chip8.cycles(), 30);
+
+        let mut chip8 = machine(&[0xF0, 0x0A, 0x12, 0x00], Quirks::default());
+        chip8.skip_idle = true;
+        for _ in 0..30 {
+            run(&mut chip8, 1);
+            assert!(!is_idle(&chip8));
+        }
+        assert!(chip8.state.waiting().is_some());
+    }
+}
@@ -0,0 +1,163 @@
+//! Keys fed in as they go down and come up
+//!
+//! A frontend that gets key events from its window can push them into a KeyQueue, stamped with
+//! the frame the machine is on, and hand the queue to the machine as its keys.
+
+use KeyEvent;
+use KeyEvents;
+use KeyWrapper;
+use std::collections::VecDeque;
+
+/// The most events kept waiting for FX0A, past which the oldest are dropped
+const MAX_EVENTS: usize = 64;
+
+/// The keys held down, and the presses and releases FX0A hasn't looked at yet
+#[derive(Clone, Debug, Default)]
+pub struct KeyQueue {
+    held: u16,
+    events: VecDeque<KeyEvent>,
+}
+
+impl KeyQueue {
+    pub fn new() -> KeyQueue {
+        KeyQueue::default()
+    }
+    /// Records key going down in frame
+    pub fn press(&mut self, key: u8, frame: u64) {
+        self.push(KeyEvent {
+            key: key,
+            pressed: true,
+            frame: frame,
+        })
+    }
+    /// Records key coming back up in frame
+    pub fn release(&mut self, key: u8, frame: u64) {
+        self.push(KeyEvent {
+            key: key,
+            pressed: false,
+            frame: frame,
+        })
+    }
+    pub fn push(&mut self, event: KeyEvent) {
+        let event = KeyEvent { key: event.key & 0xF, ..event };
+        if event.pressed {
+            self.held |= 1 << event.key;
+        } else {
+            self.held &= !(1 << event.key);
+        }
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+impl KeyWrapper for KeyQueue {
+    fn is_pushed(&self, key: u8) -> bool {
+        self.held & 1 << (key & 0xF) != 0
+    }
+    fn get_key(&self) -> Option<u8> {
+        (0..16).find(|&key| self.is_pushed(key))
+    }
+    fn events(&mut self) -> Option<&mut KeyEvents> {
+        Some(self)
+    }
+}
+
+impl KeyEvents for KeyQueue {
+    fn next_event(&mut self, frame: u64) -> Option<KeyEvent> {
+        if self.events.front().map_or(false, |event| event.frame <= frame) {
+            self.events.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Chip8;
+    use Quirks;
+    use rewind::Rewind;
+    use savestate::SaveState;
+    use super::KeyQueue;
+    use testing::{Silence, run};
+
+    /// Waits for a key to put in V3, then jumps to itself
+    const PROGRAM: [u8; 4] = [0xF3, 0x0A, 0x12, 0x02];
+
+    fn machine(key_release: bool) -> Chip8<KeyQueue, Silence> {
+        let mut quirks = Quirks::default();
+        quirks.key_release = key_release;
+        let mut chip8 = Chip8::with_quirks(KeyQueue::new(), Silence, quirks);
+        chip8.load_prog(&mut &PROGRAM[..]).unwrap();
+        chip8
+    }
+
+    /// A machine whose FX0A has seen key 7 go down and waits for it to come up
+    fn pressed() -> Chip8<KeyQueue, Silence> {
+        let mut chip8 = machine(true);
+        run(&mut chip8, 1);
+        let frame = chip8.frames();
+        chip8.key_wrapper.press(7, frame);
+        run(&mut chip8, 1);
+        assert_eq!(chip8.state.waiting(), Some((3, Some(7))));
+        chip8
+    }
+
+    fn release(chip8: &mut Chip8<KeyQueue, Silence>) {
+        let frame = chip8.frames();
+        chip8.key_wrapper.release(7, frame);
+        run(chip8, 1);
+    }
+
+    #[test]
+    fn press_finishes_the_wait() {
+        let mut chip8 = machine(false);
+        run(&mut chip8, 5);
+        assert_eq!(chip8.state.waiting(), Some((3, None)));
+        assert_eq!(chip8.program_counter, 0x200);
+        let frame = chip8.frames();
+        chip8.key_wrapper.press(7, frame);
+        run(&mut chip8, 1);
+        assert_eq!(chip8.state.waiting(), None);
+        assert_eq!((chip8.data_registers[3], chip8.program_counter), (7, 0x202));
+    }
+
+    #[test]
+    fn release_finishes_the_wait() {
+        let mut chip8 = pressed();
+        run(&mut chip8, 20);
+        assert_eq!(chip8.state.waiting(), Some((3, Some(7))));
+        assert_eq!(chip8.program_counter, 0x200);
+        release(&mut chip8);
+        assert_eq!(chip8.state.waiting(), None);
+        assert_eq!((chip8.data_registers[3], chip8.program_counter), (7, 0x202));
+    }
+
+    #[test]
+    fn wait_survives_savestate() {
+        let chip8 = pressed();
+        let mut bytes = Vec::new();
+        SaveState::capture(&chip8, &PROGRAM, false).unwrap().write(&mut bytes).unwrap();
+        let mut loaded = machine(true);
+        SaveState::read(&mut &bytes[..]).unwrap().restore(&mut loaded);
+        assert_eq!(loaded.state.waiting(), Some((3, Some(7))));
+        release(&mut loaded);
+        assert_eq!((loaded.data_registers[3], loaded.program_counter), (7, 0x202));
+    }
+
+    #[test]
+    fn wait_survives_rewind() {
+        let mut chip8 = pressed();
+        let mut rewind = Rewind::new(1 << 20);
+        rewind.record(&chip8);
+        release(&mut chip8);
+        assert_eq!(chip8.state.waiting(), None);
+        assert_eq!(rewind.step_back_cycles(&mut chip8, 1), Ok(true));
+        assert_eq!(chip8.state.waiting(), Some((3, Some(7))));
+        assert_eq!(chip8.program_counter, 0x200);
+        release(&mut chip8);
+        assert_eq!((chip8.data_registers[3], chip8.program_counter), (7, 0x202));
+    }
+}
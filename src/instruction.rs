@@ -0,0 +1,341 @@
+use Chip8Err;
+use Mode;
+use Quirks;
+
+/// A decoded chip8, SUPER-CHIP or XO-CHIP instruction
+///
+/// x and y are register numbers, n is the low nibble and byte the low byte of the optcode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// 0NNN: Call a machine code routine
+    Sys(u16),
+    /// 00E0: Clear the screen
+    Cls,
+    /// 00EE: Return from a subroutine
+    Ret,
+    /// 00CN: Scroll the screen down N lines (SUPER-CHIP)
+    ScrollDown(u8),
+    /// 00DN: Scroll the screen up N lines (XO-CHIP)
+    ScrollUp(u8),
+    /// 00FB: Scroll the screen right 4 pixels (SUPER-CHIP)
+    ScrollRight,
+    /// 00FC: Scroll the screen left 4 pixels (SUPER-CHIP)
+    ScrollLeft,
+    /// 00FD: Exit the interpreter (SUPER-CHIP)
+    Exit,
+    /// 00FE: Switch to the 64x32 screen (SUPER-CHIP)
+    Lores,
+    /// 00FF: Switch to the 128x64 screen (SUPER-CHIP)
+    Hires,
+    /// 1NNN: Jump to NNN
+    Jp(u16),
+    /// 2NNN: Call the subroutine at NNN
+    Call(u16),
+    /// 3XNN: Skip the next instruction if VX == NN
+    SeByte { x: u8, byte: u8 },
+    /// 4XNN: Skip the next instruction if VX != NN
+    SneByte { x: u8, byte: u8 },
+    /// 5XY0: Skip the next instruction if VX == VY
+    SeReg { x: u8, y: u8 },
+    /// 5XY2: Store VX through VY at I (XO-CHIP)
+    SaveRange { x: u8, y: u8 },
+    /// 5XY3: Load VX through VY from I (XO-CHIP)
+    LoadRange { x: u8, y: u8 },
+    /// 6XNN: VX = NN
+    LdByte { x: u8, byte: u8 },
+    /// 7XNN: VX += NN without touching VF
+    AddByte { x: u8, byte: u8 },
+    /// 8XY0: VX = VY
+    LdReg { x: u8, y: u8 },
+    /// 8XY1: VX |= VY
+    Or { x: u8, y: u8 },
+    /// 8XY2: VX &= VY
+    And { x: u8, y: u8 },
+    /// 8XY3: VX ^= VY
+    Xor { x: u8, y: u8 },
+    /// 8XY4: VX += VY, VF = carry
+    Add { x: u8, y: u8 },
+    /// 8XY5: VX -= VY, VF = not borrow
+    Sub { x: u8, y: u8 },
+    /// 8XY6: VX >>= 1, VF = the bit shifted out
+    Shr { x: u8, y: u8 },
+    /// 8XY7: VX = VY - VX, VF = not borrow
+    Subn { x: u8, y: u8 },
+    /// 8XYE: VX <<= 1, VF = the bit shifted out
+    Shl { x: u8, y: u8 },
+    /// 9XY0: Skip the next instruction if VX != VY
+    SneReg { x: u8, y: u8 },
+    /// ANNN: I = NNN
+    LdI(u16),
+    /// BNNN: Jump to NNN + V0
+    JpV0(u16),
+    /// CXNN: VX = random & NN
+    Rnd { x: u8, byte: u8 },
+    /// DXYN: Draw an 8xN sprite, or a 16x16 one when N is 0 on SUPER-CHIP
+    Drw { x: u8, y: u8, n: u8 },
+    /// EX9E: Skip the next instruction if key VX is pushed
+    Skp(u8),
+    /// EXA1: Skip the next instruction if key VX isn't pushed
+    Sknp(u8),
+    /// F000 NNNN: I = NNNN (XO-CHIP)
+    LdLongI(u16),
+    /// FN01: Select the bitplanes to draw on (XO-CHIP)
+    Plane(u8),
+    /// F002: Load the audio pattern buffer from I (XO-CHIP)
+    Audio,
+    /// FX07: VX = delay timer
+    LdVxDt(u8),
+    /// FX0A: Wait for a key and put it in VX
+    LdVxK(u8),
+    /// FX15: Delay timer = VX
+    LdDtVx(u8),
+    /// FX18: Sound timer = VX
+    LdStVx(u8),
+    /// FX1E: I += VX
+    AddI(u8),
+    /// FX29: I = the small font sprite for VX
+    LdF(u8),
+    /// FX30: I = the big font sprite for VX (SUPER-CHIP)
+    LdHf(u8),
+    /// FX33: Store the decimal digits of VX at I
+    LdB(u8),
+    /// FX3A: Audio pitch = VX (XO-CHIP)
+    Pitch(u8),
+    /// FX55: Store V0 through VX at I
+    LdIVx(u8),
+    /// FX65: Load V0 through VX from I
+    LdVxI(u8),
+    /// FX75: Store V0 through VX in the RPL flags (SUPER-CHIP)
+    LdRVx(u8),
+    /// FX85: Load V0 through VX from the RPL flags (SUPER-CHIP)
+    LdVxR(u8),
+}
+
+impl Instruction {
+    /// Decodes a two byte optcode
+    ///
+    /// F000 is the first half of a four byte instruction and has to be decoded with decode_long.
+    pub fn decode(optcode: u16) -> Result<Instruction, Chip8Err> {
+        use self::Instruction::*;
+        let nibble_1 = (optcode >> 12) as u8;
+        let x = (optcode >> 8 & 0xF) as u8;
+        let y = (optcode >> 4 & 0xF) as u8;
+        let n = (optcode & 0xF) as u8;
+        let byte = optcode as u8;
+        let address = optcode & 0xFFF;
+        let instruction = match nibble_1 {
+            0 => {
+                match address {
+                    0x0E0 => Cls,
+                    0x0EE => Ret,
+                    0x0C0...0x0CF => ScrollDown(n),
+                    0x0D0...0x0DF => ScrollUp(n),
+                    0x0FB => ScrollRight,
+                    0x0FC => ScrollLeft,
+                    0x0FD => Exit,
+                    0x0FE => Lores,
+                    0x0FF => Hires,
+                    _ => Sys(address),
+                }
+            }
+            1 => Jp(address),
+            2 => Call(address),
+            3 => SeByte { x: x, byte: byte },
+            4 => SneByte { x: x, byte: byte },
+            5 => {
+                match n {
+                    0 => SeReg { x: x, y: y },
+                    2 => SaveRange { x: x, y: y },
+                    3 => LoadRange { x: x, y: y },
+                    _ => return Err(Chip8Err::UnknownOptcode),
+                }
+            }
+            6 => LdByte { x: x, byte: byte },
+            7 => AddByte { x: x, byte: byte },
+            8 => {
+                match n {
+                    0 => LdReg { x: x, y: y },
+                    1 => Or { x: x, y: y },
+                    2 => And { x: x, y: y },
+                    3 => Xor { x: x, y: y },
+                    4 => Add { x: x, y: y },
+                    5 => Sub { x: x, y: y },
+                    6 => Shr { x: x, y: y },
+                    7 => Subn { x: x, y: y },
+                    0xE => Shl { x: x, y: y },
+                    _ => return Err(Chip8Err::UnknownOptcode),
+                }
+            }
+            9 => {
+                if n != 0 {
+                    return Err(Chip8Err::UnknownOptcode);
+                }
+                SneReg { x: x, y: y }
+            }
+            0xA => LdI(address),
+            0xB => JpV0(address),
+            0xC => Rnd { x: x, byte: byte },
+            0xD => Drw { x: x, y: y, n: n },
+            0xE => {
+                match byte {
+                    0x9E => Skp(x),
+                    0xA1 => Sknp(x),
+                    _ => return Err(Chip8Err::UnknownOptcode),
+                }
+            }
+            _ => {
+                match byte {
+                    0x01 if x <= 3 => Plane(x),
+                    0x02 if x == 0 => Audio,
+                    0x07 => LdVxDt(x),
+                    0x0A => LdVxK(x),
+                    0x15 => LdDtVx(x),
+                    0x18 => LdStVx(x),
+                    0x1E => AddI(x),
+                    0x29 => LdF(x),
+                    0x30 => LdHf(x),
+                    0x33 => LdB(x),
+                    0x3A => Pitch(x),
+                    0x55 => LdIVx(x),
+                    0x65 => LdVxI(x),
+                    0x75 => LdRVx(x),
+                    0x85 => LdVxR(x),
+                    _ => return Err(Chip8Err::UnknownOptcode),
+                }
+            }
+        };
+        Ok(instruction)
+    }
+    /// Decodes an optcode along with the word after it, which only F000 uses
+    pub fn decode_long(optcode: u16, operand: u16) -> Result<Instruction, Chip8Err> {
+        if optcode == 0xF000 {
+            Ok(Instruction::LdLongI(operand))
+        } else {
+            Instruction::decode(optcode)
+        }
+    }
+    /// Decodes the instruction stored at address
+    ///
+    /// An instruction that doesn't fit in memory is a PcOutOfBounds.
+    pub fn read(memory: &[u8], address: usize) -> Result<Instruction, Chip8Err> {
+        if address + 2 > memory.len() {
+            return Err(Chip8Err::PcOutOfBounds);
+        }
+        let optcode = (memory[address] as u16) << 8 | memory[address + 1] as u16;
+        if optcode == 0xF000 {
+            if address + 4 > memory.len() {
+                return Err(Chip8Err::PcOutOfBounds);
+            }
+            let operand = (memory[address + 2] as u16) << 8 | memory[address + 3] as u16;
+            Instruction::decode_long(optcode, operand)
+        } else {
+            Instruction::decode(optcode)
+        }
+    }
+    /// Decodes the instruction at address the way a machine running in mode with quirks would
+    ///
+    /// Anything the machine can't execute is an UnknownOptcode, which includes 0NNN unless the
+    /// machine_code quirk is on.
+    pub fn fetch(memory: &[u8], address: usize, mode: Mode, quirks: &Quirks)
+                 -> Result<Instruction, Chip8Err> {
+        let instruction = try!(Instruction::read(memory, address));
+        match instruction {
+            Instruction::Sys(_) if !quirks.machine_code => Err(Chip8Err::UnknownOptcode),
+            _ if !instruction.supported_by(mode) => Err(Chip8Err::UnknownOptcode),
+            _ => Ok(instruction),
+        }
+    }
+    /// Encodes the instruction, returning the first word for F000 NNNN
+    pub fn encode(&self) -> u16 {
+        use self::Instruction::*;
+        fn xy(high: u16, x: u8, y: u8, n: u16) -> u16 {
+            high << 12 | (x as u16) << 8 | (y as u16) << 4 | n
+        }
+        fn xb(high: u16, x: u8, byte: u8) -> u16 {
+            high << 12 | (x as u16) << 8 | byte as u16
+        }
+        match *self {
+            Sys(address) => address & 0xFFF,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollDown(n) => 0x00C0 | n as u16 & 0xF,
+            ScrollUp(n) => 0x00D0 | n as u16 & 0xF,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Lores => 0x00FE,
+            Hires => 0x00FF,
+            Jp(address) => 0x1000 | address & 0xFFF,
+            Call(address) => 0x2000 | address & 0xFFF,
+            SeByte { x, byte } => xb(3, x, byte),
+            SneByte { x, byte } => xb(4, x, byte),
+            SeReg { x, y } => xy(5, x, y, 0),
+            SaveRange { x, y } => xy(5, x, y, 2),
+            LoadRange { x, y } => xy(5, x, y, 3),
+            LdByte { x, byte } => xb(6, x, byte),
+            AddByte { x, byte } => xb(7, x, byte),
+            LdReg { x, y } => xy(8, x, y, 0),
+            Or { x, y } => xy(8, x, y, 1),
+            And { x, y } => xy(8, x, y, 2),
+            Xor { x, y } => xy(8, x, y, 3),
+            Add { x, y } => xy(8, x, y, 4),
+            Sub { x, y } => xy(8, x, y, 5),
+            Shr { x, y } => xy(8, x, y, 6),
+            Subn { x, y } => xy(8, x, y, 7),
+            Shl { x, y } => xy(8, x, y, 0xE),
+            SneReg { x, y } => xy(9, x, y, 0),
+            LdI(address) => 0xA000 | address & 0xFFF,
+            JpV0(address) => 0xB000 | address & 0xFFF,
+            Rnd { x, byte } => xb(0xC, x, byte),
+            Drw { x, y, n } => xy(0xD, x, y, n as u16 & 0xF),
+            Skp(x) => xb(0xE, x, 0x9E),
+            Sknp(x) => xb(0xE, x, 0xA1),
+            LdLongI(_) => 0xF000,
+            Plane(x) => xb(0xF, x, 0x01),
+            Audio => 0xF002,
+            LdVxDt(x) => xb(0xF, x, 0x07),
+            LdVxK(x) => xb(0xF, x, 0x0A),
+            LdDtVx(x) => xb(0xF, x, 0x15),
+            LdStVx(x) => xb(0xF, x, 0x18),
+            AddI(x) => xb(0xF, x, 0x1E),
+            LdF(x) => xb(0xF, x, 0x29),
+            LdHf(x) => xb(0xF, x, 0x30),
+            LdB(x) => xb(0xF, x, 0x33),
+            Pitch(x) => xb(0xF, x, 0x3A),
+            LdIVx(x) => xb(0xF, x, 0x55),
+            LdVxI(x) => xb(0xF, x, 0x65),
+            LdRVx(x) => xb(0xF, x, 0x75),
+            LdVxR(x) => xb(0xF, x, 0x85),
+        }
+    }
+    /// The encoded instruction as big endian bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let optcode = self.encode();
+        let mut bytes = vec![(optcode >> 8) as u8, optcode as u8];
+        if let Instruction::LdLongI(address) = *self {
+            bytes.push((address >> 8) as u8);
+            bytes.push(address as u8);
+        }
+        bytes
+    }
+    /// The length of the encoded instruction in bytes
+    pub fn size(&self) -> u16 {
+        if let Instruction::LdLongI(_) = *self { 4 } else { 2 }
+    }
+    /// Whether a machine running in mode can execute the instruction
+    pub fn supported_by(&self, mode: Mode) -> bool {
+        use self::Instruction::*;
+        match *self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Lores | Hires | LdHf(_) => {
+                mode != Mode::Chip8
+            }
+            // SUPER-CHIP only has 8 RPL flags
+            LdRVx(x) | LdVxR(x) => {
+                mode == Mode::XoChip || mode == Mode::SuperChip && x < 8
+            }
+            ScrollUp(_) | SaveRange { .. } | LoadRange { .. } | LdLongI(_) | Plane(_) |
+            Audio | Pitch(_) => mode == Mode::XoChip,
+            _ => true,
+        }
+    }
+}
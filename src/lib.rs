@@ -1,156 +1,168 @@
 extern crate rand;
 extern crate serde;
+extern crate serde_json;
+extern crate sha1;
 
-use rand::Rng;
 use std::io::prelude::*;
+use std::cmp;
+use std::collections::VecDeque;
+use std::error;
+use std::io;
 use std::io::Error;
 use std::fmt;
-use std::iter::Enumerate;
 use std::iter::Iterator;
-use std::slice;
 use std::mem;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::time::Duration;
 use serde::Serialize;
 use serde::Serializer;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::bytes::ByteBufVisitor;
 
+pub mod assembler;
+pub mod cdp1802;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
+mod fault;
+pub mod gdb;
+mod idle;
+pub mod input;
+mod instruction;
+pub mod movie;
+pub mod octo;
+mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+#[cfg(test)]
+mod testing;
+mod timing;
+pub mod trace;
+pub mod tracediff;
+
+pub use fault::Fault;
+use fault::HISTORY_LEN;
+use idle::LoopWatch;
+pub use instruction::Instruction;
+pub use quirks::{LoadStoreQuirk, Quirks};
+use random::{RandomSource, XorShift};
+use trace::{TraceRecord, Tracer};
+
 pub trait KeyWrapper {
     fn is_pushed(&self, u8) -> bool;
     fn get_key(&self) -> Option<u8>;
+    /// The presses and releases behind the keys, if this input keeps them
+    ///
+    /// FX0A waits on these when they are there, so a key tapped between two instructions still
+    /// counts. Otherwise it polls get_key and is_pushed.
+    fn events(&mut self) -> Option<&mut KeyEvents> {
+        None
+    }
 }
 
-pub trait AudioWrapper {
-    fn play(&mut self);
-    fn stop(&mut self);
-}
-
-struct BitIter<'a> {
-    slice_iter: std::slice::Iter<'a, u8>,
-    current_byte: u8,
-    bit_mask: u8,
+/// A key going down or coming back up
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+    /// The frame of the machine it happened in
+    pub frame: u64,
 }
 
-impl<'a> BitIter<'a> {
-    fn new(slice: &[u8]) -> BitIter {
-        let mut iter = slice.iter();
-        let current_byte;
-        let bit_mask;
-        if let Some(byte) = iter.next() {
-            current_byte = *byte;
-            bit_mask = 1 << 7;
-        } else {
-            current_byte = 0;
-            bit_mask = 0;
-        }
-        BitIter {
-            slice_iter: iter,
-            current_byte: current_byte,
-            bit_mask: bit_mask,
-        }
-    }
+/// Input that reports each press and release, oldest first
+pub trait KeyEvents {
+    /// Takes the oldest event that happened by the end of frame
+    fn next_event(&mut self, frame: u64) -> Option<KeyEvent>;
 }
 
-impl<'a> Iterator for BitIter<'a> {
-    type Item = bool;
-
-    fn next(&mut self) -> Option<bool> {
-        if self.bit_mask > 0 {
-            let return_val = self.current_byte & self.bit_mask != 0;
-            self.bit_mask >>= 1;
-            Some(return_val)
-        } else if let Some(byte) = self.slice_iter.next() {
-            self.current_byte = *byte;
-            self.bit_mask = 1 << 7;
-            self.next()
-        } else {
-            None
-        }
-    }
+pub trait AudioWrapper {
+    fn play(&mut self);
+    fn stop(&mut self);
+    /// Called when an XO-CHIP program changes the 1-bit audio pattern or its pitch
+    ///
+    /// The pattern is played at 4000 * 2 ^ ((pitch - 64) / 48) bits per second.
+    fn set_pattern(&mut self, _pattern: &[u8; 16], _pitch: u8) {}
 }
 
-struct MutBit<'a> {
-    slice: &'a mut [u8],
-    index: usize,
-    bit_mask: u8,
+/// An iterator of all the lit pixels returned as (x, y, planes)
+///
+/// planes has bit n set if the pixel is lit on bitplane n.
+pub struct PlaneIter<'a> {
+    state: &'a Chip8State,
+    x: usize,
+    y: usize,
 }
 
-impl<'a> MutBit<'a> {
-    fn new(slice: &mut [u8]) -> MutBit {
-        let bit_mask = 1 << 7;
-        MutBit {
-            slice: slice,
-            index: 0,
-            bit_mask: bit_mask,
-        }
-    }
+impl<'a> Iterator for PlaneIter<'a> {
+    type Item = (usize, usize, u8);
 
-    fn next(&mut self) {
-        self.bit_mask >>= 1;
-        if self.bit_mask == 0 {
-            self.bit_mask = 1 << 7;
-            self.index += 1;
-            if self.index >= self.slice.len() {
-                self.index = 0;
+    fn next(&mut self) -> Option<(usize, usize, u8)> {
+        let (width, height) = (self.state.width(), self.state.height());
+        while self.y < height {
+            let (x, y) = (self.x, self.y);
+            self.x += 1;
+            if self.x >= width {
+                self.x = 0;
+                self.y += 1;
+            }
+            let mut planes = 0;
+            for plane in 0..PLANES {
+                if self.state.pixel(plane, x, y) {
+                    planes |= 1 << plane;
+                }
+            }
+            if planes != 0 {
+                return Some((x, y, planes));
             }
         }
-    }
-
-    fn toggle(&mut self) -> bool {
-        let return_val = self.slice[self.index] & self.bit_mask != 0;
-        self.slice[self.index] ^= self.bit_mask;
-        return_val
-    }
-
-    fn skip(&mut self, skip: u8) {
-        for _ in 0..skip {
-            self.next()
-        }
+        None
     }
 }
 
 /// An iterator of all the white pixels returned as (x, y)
 pub struct PixelIter<'a> {
-    itery: Enumerate<slice::Iter<'a, [u8; 8]>>,
-    y_pos: usize,
-    iterx: Enumerate<BitIter<'a>>,
+    planes: PlaneIter<'a>,
 }
 
 impl<'a> Iterator for PixelIter<'a> {
     type Item = (usize, usize);
 
     fn next(&mut self) -> Option<(usize, usize)> {
-        // x, y
-        loop {
-            if let Some((x, pixel)) = self.iterx.next() {
-                if pixel {
-                    return Some((x, self.y_pos));
-                }
-                continue;
-            }
-            if let Some((y, iter_x)) = self.itery.next() {
-                self.iterx = BitIter::new(iter_x).enumerate();
-                self.y_pos = y;
-                continue;
-            }
-            return None;
-        }
+        self.planes.next().map(|(x, y, _)| (x, y))
     }
 }
 
-fn convert_address(nibble: u8, byte: u8) -> u16 {
-    let mut address = nibble as u16;
-    address <<= 8;
-    address | byte as u16
+/// The registers of an XO-CHIP range, which runs backwards if x > y
+fn register_range(x: u8, y: u8) -> Vec<usize> {
+    let (x, y) = (x as usize, y as usize);
+    if x <= y {
+        (x..y + 1).collect()
+    } else {
+        (y..x + 1).rev().collect()
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Chip8Err {
     UnknownOptcode,
     StackUnderFlow,
+    /// 2NNN was run with the stack already as deep as the quirks allow
+    StackOverflow,
     BadState,
+    /// The program ran 00FD or jumped to itself
+    ///
+    /// Running the program halts the machine instead of returning this, so it only shows up in
+    /// traces.
+    Exit,
+    /// An instruction touched memory past the end, starting at addr
+    MemoryOutOfBounds { addr: usize },
+    /// The program counter left memory
+    PcOutOfBounds,
+    /// A machine code routine called with 0NNN didn't return
+    RoutineHung,
 }
 
 impl fmt::Display for Chip8Err {
@@ -158,351 +170,980 @@ impl fmt::Display for Chip8Err {
         match *self {
             Chip8Err::UnknownOptcode => write!(f, "There was an unknown optcode."),
             Chip8Err::StackUnderFlow => write!(f, "There was a stack underflow"),
+            Chip8Err::StackOverflow => write!(f, "There was a stack overflow"),
             Chip8Err::BadState => write!(f, "An invalid state was executed"),
+            Chip8Err::Exit => write!(f, "The program exited"),
+            Chip8Err::MemoryOutOfBounds { addr } => {
+                write!(f, "Memory at {:#X} is out of bounds", addr)
+            }
+            Chip8Err::PcOutOfBounds => write!(f, "The program counter left memory"),
+            Chip8Err::RoutineHung => write!(f, "A machine code routine didn't return"),
+        }
+    }
+}
+
+impl error::Error for Chip8Err {
+    fn description(&self) -> &str {
+        match *self {
+            Chip8Err::UnknownOptcode => "unknown optcode",
+            Chip8Err::StackUnderFlow => "stack underflow",
+            Chip8Err::StackOverflow => "stack overflow",
+            Chip8Err::BadState => "invalid state",
+            Chip8Err::Exit => "program exited",
+            Chip8Err::MemoryOutOfBounds { .. } => "memory out of bounds",
+            Chip8Err::PcOutOfBounds => "program counter out of bounds",
+            Chip8Err::RoutineHung => "machine code routine hung",
         }
     }
 }
 
 static FONT: &'static [u8] = include_bytes!("font.bin");
+static BIG_FONT: &'static [u8] = include_bytes!("big_font.bin");
+/// Where the 8x10 SUPER-CHIP font is loaded
+const BIG_FONT_ADDRESS: usize = 0x50;
 
-struct Seriable0x1000Array([u8; 0x1000]);
+/// The number of bytes in a row of the frame buffer
+const ROW_LEN: usize = 128 / 8;
+/// The number of bytes in one bitplane of the frame buffer
+const PLANE_LEN: usize = ROW_LEN * 64;
+/// The number of XO-CHIP bitplanes
+const PLANES: usize = 2;
 
-impl Serialize for Seriable0x1000Array {
+macro_rules! seriable_array {
+    ($name:ident, $len:expr) => {
+        struct $name([u8; $len]);
+
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl Deserialize for $name {
+            fn deserialize<D>(deserializer: &mut D) -> Result<$name, D::Error>
+                where D: Deserializer {
+                let mut array = [0; $len];
+                let buff = try!(deserializer.deserialize_bytes(ByteBufVisitor));
+                array.copy_from_slice(&buff);
+                Ok($name(array))
+            }
+        }
+
+        impl Deref for $name {
+            type Target = [u8; $len];
+
+            fn deref(&self) -> &[u8; $len] {
+                &self.0
+            }
+        }
+
+        impl DerefMut for $name {
+            fn deref_mut(&mut self) -> &mut [u8; $len] {
+                &mut self.0
+            }
+        }
+
+        impl Clone for $name {
+            fn clone(&self) -> $name {
+                $name(self.0)
+            }
+        }
+    }
+}
+
+// Two bitplanes of 128x64 pixels, one bit each
+seriable_array!(Seriable0x800Array, 0x800);
+
+/// The address space, 4 KiB or 64 KiB for XO-CHIP
+struct SeriableMemory(Vec<u8>);
+
+impl Serialize for SeriableMemory {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
         serializer.serialize_bytes(&self.0)
     }
 }
 
-impl Deserialize for Seriable0x1000Array {
-    fn deserialize<D>(deserializer: &mut D)
-        -> Result<Seriable0x1000Array, D::Error> where D: Deserializer {
-        let mut array = [0; 0x1000];
+impl Deserialize for SeriableMemory {
+    fn deserialize<D>(deserializer: &mut D) -> Result<SeriableMemory, D::Error>
+        where D: Deserializer {
         let buff = try!(deserializer.deserialize_bytes(ByteBufVisitor));
-        array.copy_from_slice(&buff);
-        Ok(Seriable0x1000Array(array))
+        Ok(SeriableMemory(buff.into()))
     }
 }
 
-include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
-
-impl Deref for Seriable0x1000Array {
-    type Target = [u8; 0x1000];
+impl Deref for SeriableMemory {
+    type Target = [u8];
 
-    fn deref(&self) -> &[u8; 0x1000] {
+    fn deref(&self) -> &[u8] {
         &self.0
     }
 }
 
-impl DerefMut for Seriable0x1000Array {
-    fn deref_mut(&mut self) -> &mut [u8; 0x1000] {
+impl DerefMut for SeriableMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
         &mut self.0
     }
 }
 
+include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
+
+impl Mode {
+    /// The number files store the mode as
+    fn to_byte(&self) -> u8 {
+        match *self {
+            Mode::Chip8 => 0,
+            Mode::SuperChip => 1,
+            Mode::XoChip => 2,
+        }
+    }
+    fn from_byte(byte: u8) -> Option<Mode> {
+        match byte {
+            0 => Some(Mode::Chip8),
+            1 => Some(Mode::SuperChip),
+            2 => Some(Mode::XoChip),
+            _ => None,
+        }
+    }
+}
+
 impl Clone for Chip8State {
     fn clone(&self) -> Chip8State {
+        Chip8State { memory: SeriableMemory(self.memory.0.clone()), ..self.without_memory() }
+    }
+}
+
+impl Chip8State {
+    /// A copy of the state with empty memory
+    fn without_memory(&self) -> Chip8State {
         Chip8State {
             data_registers: self.data_registers,
             address_register: self.address_register,
-            memory: Seriable0x1000Array(*self.memory),
+            memory: SeriableMemory(Vec::new()),
             program_counter: self.program_counter,
             stack: self.stack.clone(),
             delay_timer: self.delay_timer,
             sound_timer: self.sound_timer,
-            frame_buffer: self.frame_buffer,
+            frame_buffer: self.frame_buffer.clone(),
+            mode: self.mode,
+            hires: self.hires,
+            rpl_flags: self.rpl_flags,
+            planes: self.planes,
+            audio_pattern: self.audio_pattern,
+            pitch: self.pitch,
         }
     }
 }
 
 impl Chip8State {
-    fn new() -> Chip8State {
+    fn new(mode: Mode) -> Chip8State {
+        let memory_len = if mode == Mode::XoChip { 0x10000 } else { 0x1000 };
         let mut state = Chip8State {
             data_registers: [0; 16],
             address_register: 0,
-            memory: Seriable0x1000Array([0; 0x1000]),
+            memory: SeriableMemory(vec![0; memory_len]),
             program_counter: 0x200, // Entry point of most programs
             stack: Vec::with_capacity(16),
             delay_timer: 0,
             sound_timer: 0,
-            frame_buffer: [[0; 8]; 32],
+            frame_buffer: Seriable0x800Array([0; 0x800]),
+            mode: mode,
+            hires: false,
+            rpl_flags: [0; 16],
+            planes: 1,
+            audio_pattern: [0; 16],
+            pitch: 64,
         };
         state.memory[0..FONT.len()].copy_from_slice(FONT);
+        if mode != Mode::Chip8 {
+            state.memory[BIG_FONT_ADDRESS..BIG_FONT_ADDRESS + BIG_FONT.len()]
+                .copy_from_slice(BIG_FONT);
+        }
         state
     }
     /// Returns a PixelIter over the current screen
     pub fn frame_iter(&self) -> PixelIter {
-        let mut itery = self.frame_buffer.iter().enumerate();
-        let (y_pos, x) = itery.next().unwrap();
-        let iterx = BitIter::new(x).enumerate();
-        PixelIter {
-            itery: itery,
-            y_pos: y_pos,
-            iterx: iterx,
+        PixelIter { planes: self.frame_planes_iter() }
+    }
+    /// Returns a PlaneIter over the current screen, for mapping XO-CHIP bitplanes to colours
+    pub fn frame_planes_iter(&self) -> PlaneIter {
+        PlaneIter {
+            state: self,
+            x: 0,
+            y: 0,
+        }
+    }
+    /// The instruction set the program is run with
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    /// Whether the SUPER-CHIP 128x64 screen is in use
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+    /// The width of the current screen in pixels
+    pub fn width(&self) -> usize {
+        if self.hires { 128 } else { 64 }
+    }
+    /// The height of the current screen in pixels
+    pub fn height(&self) -> usize {
+        if self.hires { 64 } else { 32 }
+    }
+    /// The XO-CHIP audio pattern buffer
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+    /// The XO-CHIP audio pitch register
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+    /// V0 through VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.data_registers
+    }
+    pub fn registers_mut(&mut self) -> &mut [u8; 16] {
+        &mut self.data_registers
+    }
+    /// I
+    pub fn address_register(&self) -> u16 {
+        self.address_register
+    }
+    pub fn set_address_register(&mut self, address: u16) {
+        self.address_register = address
+    }
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.program_counter = address
+    }
+    /// The return addresses of the subroutines being run, innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value
+    }
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+    /// The len bytes at address, or the first address past the end of memory
+    fn checked_memory(&self, address: usize, len: usize) -> Result<&[u8], Chip8Err> {
+        let end = try!(self.memory_end(address, len));
+        Ok(&self.memory[address..end])
+    }
+    fn checked_memory_mut(&mut self, address: usize, len: usize) -> Result<&mut [u8], Chip8Err> {
+        let end = try!(self.memory_end(address, len));
+        Ok(&mut self.memory[address..end])
+    }
+    /// The end of the len bytes at address, which can be huge with a wrapped stack address
+    fn memory_end(&self, address: usize, len: usize) -> Result<usize, Chip8Err> {
+        match address.checked_add(len) {
+            Some(end) if end <= self.memory.len() => Ok(end),
+            _ => Err(Chip8Err::MemoryOutOfBounds { addr: cmp::max(address, self.memory.len()) }),
         }
     }
     pub fn from_prog<T>(input: &mut T) -> Result<Chip8State, Error> where T: Read {
-        let mut new_state = Chip8State::new();
+        Chip8State::from_prog_with_mode(input, Mode::Chip8)
+    }
+    pub fn from_prog_with_mode<T>(input: &mut T, mode: Mode) -> Result<Chip8State, Error>
+        where T: Read {
+        let mut new_state = Chip8State::new(mode);
         let len = new_state.memory.len();
         {
-            let mut program_mem = &mut new_state.memory[0x200..len];
+            let program_mem = &mut new_state.memory[0x200..len];
             try!(input.read(program_mem));
         }
         Ok(new_state)
     }
+    fn pixel(&self, plane: usize, x: usize, y: usize) -> bool {
+        self.frame_buffer[plane * PLANE_LEN + y * ROW_LEN + x / 8] & 0x80 >> (x % 8) != 0
+    }
+    fn set_pixel(&mut self, plane: usize, x: usize, y: usize, on: bool) {
+        let bit_mask = 0x80 >> (x % 8);
+        let index = plane * PLANE_LEN + y * ROW_LEN + x / 8;
+        if on {
+            self.frame_buffer[index] |= bit_mask;
+        } else {
+            self.frame_buffer[index] &= !bit_mask;
+        }
+    }
+    /// The bitplanes that drawing, clearing and scrolling act on
+    fn selected_planes(&self) -> Vec<usize> {
+        (0..PLANES).filter(|plane| self.planes & 1 << plane != 0).collect()
+    }
+    fn clear_screen(&mut self) {
+        for plane in self.selected_planes() {
+            for byte in &mut self.frame_buffer[plane * PLANE_LEN..(plane + 1) * PLANE_LEN] {
+                *byte = 0;
+            }
+        }
+    }
+    fn scroll_down(&mut self, lines: usize) {
+        for plane in self.selected_planes() {
+            for y in (0..self.height()).rev() {
+                for x in 0..self.width() {
+                    let on = y >= lines && self.pixel(plane, x, y - lines);
+                    self.set_pixel(plane, x, y, on);
+                }
+            }
+        }
+    }
+    fn scroll_up(&mut self, lines: usize) {
+        let height = self.height();
+        for plane in self.selected_planes() {
+            for y in 0..height {
+                for x in 0..self.width() {
+                    let on = y + lines < height && self.pixel(plane, x, y + lines);
+                    self.set_pixel(plane, x, y, on);
+                }
+            }
+        }
+    }
+    fn scroll_right(&mut self, columns: usize) {
+        for plane in self.selected_planes() {
+            for y in 0..self.height() {
+                for x in (0..self.width()).rev() {
+                    let on = x >= columns && self.pixel(plane, x - columns, y);
+                    self.set_pixel(plane, x, y, on);
+                }
+            }
+        }
+    }
+    fn scroll_left(&mut self, columns: usize) {
+        let width = self.width();
+        for plane in self.selected_planes() {
+            for y in 0..self.height() {
+                for x in 0..width {
+                    let on = x + columns < width && self.pixel(plane, x + columns, y);
+                    self.set_pixel(plane, x, y, on);
+                }
+            }
+        }
+    }
+    /// Xors a sprite onto every selected plane, returning true if a lit pixel was erased
+    ///
+    /// When several planes are selected each one takes the next sprite in memory.
+    fn draw_sprite(&mut self, x: u8, y: u8, rows: usize, wide: bool, quirks: &Quirks)
+        -> Result<bool, Chip8Err> {
+        let (width, height) = (self.width(), self.height());
+        let sprite_width = if wide { 16 } else { 8 };
+        let sprite_len = rows * sprite_width / 8;
+        let start_x = x as usize % width;
+        let start_y = if quirks.wrap_y || quirks.wrap_start_y { y as usize % height } else { y as usize };
+        let planes = self.selected_planes();
+        let sprites = try!(self.checked_memory(self.address_register as usize, planes.len() * sprite_len))
+            .to_vec();
+        let mut collision = false;
+        for (plane_n, plane) in planes.into_iter().enumerate() {
+            let sprite_address = plane_n * sprite_len;
+            for line_n in 0..rows {
+                let mut y = start_y + line_n;
+                if y >= height {
+                    if !quirks.wrap_y {
+                        break;
+                    }
+                    y %= height;
+                }
+                let line_address = sprite_address + line_n * sprite_width / 8;
+                let mut sprite_line = sprites[line_address] as u16;
+                if wide {
+                    sprite_line = sprite_line << 8 | sprites[line_address + 1] as u16;
+                } else {
+                    sprite_line <<= 8;
+                }
+                for bit_n in 0..sprite_width {
+                    if sprite_line & 0x8000 >> bit_n == 0 {
+                        continue;
+                    }
+                    let mut x = start_x + bit_n;
+                    if x >= width {
+                        if !quirks.wrap_x {
+                            break;
+                        }
+                        x %= width;
+                    }
+                    let on = self.pixel(plane, x, y);
+                    collision |= on;
+                    self.set_pixel(plane, x, y, !on);
+                }
+            }
+        }
+        Ok(collision)
+    }
+    /// Moves the program counter past the next instruction
+    fn skip_next(&mut self) {
+        let next = self.checked_memory(self.program_counter as usize + 2, 2).ok();
+        if self.mode == Mode::XoChip && next == Some(&[0xF0, 0][..]) {
+            // F000 NNNN is twice as long
+            self.program_counter = self.program_counter.wrapping_add(4);
+        } else {
+            self.program_counter = self.program_counter.wrapping_add(2);
+        }
+    }
+}
+
+/// Where the VIP interpreter keeps the return address of the call nested depth levels deep
+///
+/// Its stack pointer starts at 0xECF and moves down, storing the high byte below the low one.
+fn vip_stack_address(depth: usize) -> usize {
+    VIP_STACK_END.wrapping_sub(2 * (depth + 1))
+}
+
+/// One past the top of the VIP interpreter's stack
+const VIP_STACK_END: usize = 0xED0;
+
+/// How many instructions run before the timers tick, unless set otherwise
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 11;
+/// How many times a second the timers tick, unless set otherwise
+pub const DEFAULT_TIMER_HZ: u32 = 60;
+
+/// What a machine is doing
+#[derive(Clone)]
+pub enum RunState {
+    /// No program has been loaded
+    Empty,
+    Running(Chip8State),
+    /// FX0A is waiting for a key to put in V(register), and for key to come back up if it has
+    /// been pressed and the quirks wait for the release
+    WaitingForKey {
+        register: u8,
+        key: Option<u8>,
+        state: Chip8State,
+    },
+    /// The program is going round a loop that can't finish before the timers tick
+    Idle(Chip8State),
+    /// The program ran 00FD, or jumped to itself with no sound left to play
+    Halted(Chip8State),
+    /// The program stopped on err, with the program counter at the instruction that caused it
+    Faulted { err: Chip8Err, state: Chip8State },
+}
+
+impl RunState {
+    /// The state of the program, whether or not it is still running
+    pub fn state(&self) -> Option<&Chip8State> {
+        match *self {
+            RunState::Empty => None,
+            RunState::Running(ref state) |
+            RunState::WaitingForKey { ref state, .. } |
+            RunState::Idle(ref state) |
+            RunState::Halted(ref state) |
+            RunState::Faulted { ref state, .. } => Some(state),
+        }
+    }
+    pub fn state_mut(&mut self) -> Option<&mut Chip8State> {
+        match *self {
+            RunState::Empty => None,
+            RunState::Running(ref mut state) |
+            RunState::WaitingForKey { ref mut state, .. } |
+            RunState::Idle(ref mut state) |
+            RunState::Halted(ref mut state) |
+            RunState::Faulted { ref mut state, .. } => Some(state),
+        }
+    }
+    pub fn into_state(self) -> Option<Chip8State> {
+        match self {
+            RunState::Empty => None,
+            RunState::Running(state) |
+            RunState::WaitingForKey { state, .. } |
+            RunState::Idle(state) |
+            RunState::Halted(state) |
+            RunState::Faulted { state, .. } => Some(state),
+        }
+    }
+    /// The state of the program if it can run another instruction
+    pub fn running(&self) -> Option<&Chip8State> {
+        match *self {
+            RunState::Running(ref state) |
+            RunState::WaitingForKey { ref state, .. } |
+            RunState::Idle(ref state) => Some(state),
+            _ => None,
+        }
+    }
+    pub fn is_running(&self) -> bool {
+        self.running().is_some()
+    }
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            RunState::Empty => true,
+            _ => false,
+        }
+    }
+    /// Whether the program ran 00FD or jumped to itself
+    pub fn is_halted(&self) -> bool {
+        match *self {
+            RunState::Halted(_) => true,
+            _ => false,
+        }
+    }
+    /// The register FX0A is waiting to put a key in and the key it is waiting to come up
+    pub fn waiting(&self) -> Option<(u8, Option<u8>)> {
+        match *self {
+            RunState::WaitingForKey { register, key, .. } => Some((register, key)),
+            _ => None,
+        }
+    }
+    /// A machine that can run, waiting for a key if FX0A was
+    fn resume(state: Chip8State, waiting: Option<(u8, Option<u8>)>) -> RunState {
+        match waiting {
+            Some((register, key)) => {
+                RunState::WaitingForKey {
+                    register: register,
+                    key: key,
+                    state: state,
+                }
+            }
+            None => RunState::Running(state),
+        }
+    }
+    /// Moves a running machine between Running and Idle
+    fn set_idle(&mut self, idle: bool) {
+        let change = match *self {
+            RunState::Running(_) => idle,
+            RunState::Idle(_) => !idle,
+            _ => false,
+        };
+        if change {
+            *self = match mem::replace(self, RunState::Empty) {
+                RunState::Running(state) => RunState::Idle(state),
+                RunState::Idle(state) => RunState::Running(state),
+                other => other,
+            };
+        }
+    }
+    /// The error that stopped the program, if it faulted
+    pub fn error(&self) -> Option<Chip8Err> {
+        match *self {
+            RunState::Faulted { err, .. } => Some(err),
+            _ => None,
+        }
+    }
 }
 
 /// The chip8 machine
 pub struct Chip8<T: KeyWrapper, A: AudioWrapper> {
-    pub state: Result<Chip8State, (Option<Chip8State>, Chip8Err)>,
-    rng: rand::ThreadRng,
+    pub state: RunState,
+    /// The state the program was loaded into, for reset
+    initial_state: Option<Chip8State>,
+    rng: Box<RandomSource>,
     pub key_wrapper: T,
     pub audio_wrapper: A,
+    pub quirks: Quirks,
+    /// How many instructions run before the timers tick, unless vip_timing is set
+    pub instructions_per_frame: u32,
+    /// Charges every instruction the machine cycles it takes on the COSMAC VIP, ticking the timers
+    /// once the interpreter's share of a frame is used up
+    pub vip_timing: bool,
+    /// How many times a second the timers tick, which sets the speed of run_for
+    pub timer_hz: u32,
+    /// Skips an idle loop straight to the end of the frame instead of going round it
+    pub skip_idle: bool,
+    /// Records every instruction run when set
+    pub tracer: Option<Tracer>,
+    /// The addresses of the last instructions run
+    history: VecDeque<u16>,
+    idle_loop: LoopWatch,
+    fault: Option<Fault>,
+    cycles: u64,
+    frames: u64,
+    frame_cycle: u32,
+    /// The part of a cycle run_for has been given time for but hasn't run, in billionths
+    cycle_nanos: u64,
+    /// How far the last instruction of run_for went past the time it was given
+    overrun: u64,
 }
 
 impl<T: KeyWrapper, A: AudioWrapper> Chip8<T, A> {
     /// Makes a machine without a state
     pub fn new(key_wrapper: T, audio_wrapper: A) -> Chip8<T, A> {
+        Chip8::with_quirks(key_wrapper, audio_wrapper, Quirks::default())
+    }
+    /// Makes a machine without a state that runs optcodes as described by quirks
+    pub fn with_quirks(key_wrapper: T, audio_wrapper: A, quirks: Quirks) -> Chip8<T, A> {
         Chip8 {
-            state: Err((None, Chip8Err::BadState)),
-            rng: rand::thread_rng(),
+            state: RunState::Empty,
+            initial_state: None,
+            rng: Box::new(XorShift::new(rand::random())),
             key_wrapper: key_wrapper,
             audio_wrapper: audio_wrapper,
+            quirks: quirks,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            vip_timing: false,
+            timer_hz: DEFAULT_TIMER_HZ,
+            skip_idle: false,
+            tracer: None,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            idle_loop: LoopWatch::default(),
+            fault: None,
+            cycles: 0,
+            frames: 0,
+            frame_cycle: 0,
+            cycle_nanos: 0,
+            overrun: 0,
         }
     }
-    fn run_optcode(&mut self) -> Result<(), Chip8Err> {
-        let mut state;
-        if let Ok(ref mut good_state) = self.state {
-            state = good_state
-        } else {
-            return Err(Chip8Err::BadState)
-        }
-        let optcode_byte_1 = state.memory[state.program_counter as usize];
-        let optcode_nibble_1 = optcode_byte_1 >> 4;
-        let optcode_nibble_2 = optcode_byte_1 & 0x0f;
-        let optcode_byte_2 = state.memory[state.program_counter as usize + 1];
-        let optcode_nibble_3 = optcode_byte_2 >> 4;
-        let optcode_nibble_4 = optcode_byte_2 & 0x0f;
-        match optcode_nibble_1 {
-            0 => {
-                if optcode_nibble_2 != 0x00 {
-                    return Err(Chip8Err::UnknownOptcode);
+    /// Replaces the random number generator with the default one started from seed, so CXNN
+    /// gives the same numbers every run
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Box::new(XorShift::new(seed));
+    }
+    pub fn set_random_source(&mut self, rng: Box<RandomSource>) {
+        self.rng = rng;
+    }
+    pub fn random_source(&self) -> &RandomSource {
+        &*self.rng
+    }
+    pub fn random_source_mut(&mut self) -> &mut RandomSource {
+        &mut *self.rng
+    }
+    /// Runs the instruction at the program counter, handing a record of it to the tracer, and
+    /// returns the machine cycles a 0NNN routine took
+    fn run_optcode(&mut self) -> Result<u32, Chip8Err> {
+        if self.tracer.is_none() {
+            return self.execute_optcode();
+        }
+        let record = match self.state.running() {
+            Some(state) => TraceRecord::before(state, &self.quirks, self.cycles),
+            None => None,
+        };
+        let result = self.execute_optcode();
+        // The instruction that stops the machine is traced too, marked with the error
+        if let (Some(mut record), Some(state)) = (record, self.state.state()) {
+            record.finish(state, result.err());
+            if let Some(ref mut tracer) = self.tracer {
+                tracer.record(&record);
+            }
+        }
+        result
+    }
+    /// Runs the instruction at the program counter, keeping track of FX0A waiting for a key
+    fn execute_optcode(&mut self) -> Result<u32, Chip8Err> {
+        let (waiting, routine_cycles) = try!(self.execute_instruction());
+        if waiting != self.state.waiting() {
+            self.state = match mem::replace(&mut self.state, RunState::Empty) {
+                RunState::Running(state) |
+                RunState::WaitingForKey { state, .. } |
+                RunState::Idle(state) => RunState::resume(state, waiting),
+                other => other,
+            };
+        }
+        Ok(routine_cycles)
+    }
+    /// Runs the instruction at the program counter, returning the register FX0A is waiting to put a
+    /// key in and the key it is waiting to come up, and the machine cycles a 0NNN routine took
+    fn execute_instruction(&mut self) -> Result<(Option<(u8, Option<u8>)>, u32), Chip8Err> {
+        let (state, waiting) = match self.state {
+            RunState::WaitingForKey { ref mut state, key, .. } => (state, Some(key)),
+            RunState::Running(ref mut state) |
+            RunState::Idle(ref mut state) => (state, None),
+            _ => return Err(Chip8Err::BadState),
+        };
+        let program_counter = state.program_counter as usize;
+        let instruction = try!(Instruction::fetch(&state.memory, program_counter, state.mode, &self.quirks));
+        match instruction {
+            Instruction::Sys(address) => {
+                let cycles = try!(cdp1802::call_routine(state, address));
+                if state.sound_timer > 0 {
+                    self.audio_wrapper.play();
+                } else {
+                    self.audio_wrapper.stop();
                 }
-                match optcode_byte_2 {
-                    0xE0 => state.frame_buffer = [[0; 8]; 32],
-                    0xEE => {
-                        if let Some(x) = state.stack.pop() {
-                            state.program_counter = x;
-                            return Ok(());
-                        } else {
-                            return Err(Chip8Err::StackUnderFlow);
-                        }
+                return Ok((None, cycles));
+            }
+            Instruction::Cls => state.clear_screen(),
+            Instruction::Ret => {
+                if let Some(mut x) = state.stack.pop() {
+                    if self.quirks.vip_stack {
+                        let address = vip_stack_address(state.stack.len());
+                        let bytes = try!(state.checked_memory(address, 2));
+                        x = (bytes[0] as u16) << 8 | bytes[1] as u16;
                     }
-                    _ => return Err(Chip8Err::UnknownOptcode),
+                    state.program_counter = x;
+                    return Ok((None, 0));
+                } else {
+                    return Err(Chip8Err::StackUnderFlow);
                 }
             }
-            1 => {
-                state.program_counter = convert_address(optcode_nibble_2, optcode_byte_2);
-                return Ok(());
+            Instruction::ScrollDown(n) => state.scroll_down(n as usize),
+            Instruction::ScrollUp(n) => state.scroll_up(n as usize),
+            Instruction::ScrollRight => state.scroll_right(4),
+            Instruction::ScrollLeft => state.scroll_left(4),
+            Instruction::Exit => return Err(Chip8Err::Exit),
+            Instruction::Lores => {
+                state.hires = false;
+                *state.frame_buffer = [0; 0x800];
             }
-            2 => {
-                state.stack.push(state.program_counter + 2);
-                state.program_counter = convert_address(optcode_nibble_2, optcode_byte_2);
-                return Ok(());
+            Instruction::Hires => {
+                state.hires = true;
+                *state.frame_buffer = [0; 0x800];
             }
-            3 => {
-                if state.data_registers[optcode_nibble_2 as usize] == optcode_byte_2 {
-                    state.program_counter += 2;
+            Instruction::Jp(address) => {
+                // Nothing can ever happen again, unless there's a sound still to finish
+                if address == state.program_counter && state.sound_timer == 0 {
+                    return Err(Chip8Err::Exit);
                 }
+                state.program_counter = address;
+                return Ok((None, 0));
             }
-            4 => {
-                if state.data_registers[optcode_nibble_2 as usize] != optcode_byte_2 {
-                    state.program_counter += 2;
+            Instruction::Call(address) => {
+                if state.stack.len() >= self.quirks.stack_depth {
+                    return Err(Chip8Err::StackOverflow);
                 }
+                let return_address = state.program_counter.wrapping_add(2);
+                if self.quirks.vip_stack {
+                    let stack_address = vip_stack_address(state.stack.len());
+                    try!(state.checked_memory_mut(stack_address, 2))
+                        .copy_from_slice(&[(return_address >> 8) as u8, return_address as u8]);
+                }
+                state.stack.push(return_address);
+                state.program_counter = address;
+                return Ok((None, 0));
             }
-            5 => {
-                if state.data_registers[optcode_nibble_4 as usize] != 0 {
-                    return Err(Chip8Err::UnknownOptcode);
+            Instruction::SeByte { x, byte } => {
+                if state.data_registers[x as usize] == byte {
+                    state.skip_next();
                 }
-                if state.data_registers[optcode_nibble_2 as usize] ==
-                    state.data_registers[optcode_nibble_3 as usize] {
-                    state.program_counter += 2;
+            }
+            Instruction::SneByte { x, byte } => {
+                if state.data_registers[x as usize] != byte {
+                    state.skip_next();
                 }
             }
-            6 => state.data_registers[optcode_nibble_2 as usize] = optcode_byte_2,
-            7 => {
-                state.data_registers[optcode_nibble_2 as usize] =
-                    state.data_registers[optcode_nibble_2 as usize].wrapping_add(optcode_byte_2)
+            Instruction::SeReg { x, y } => {
+                if state.data_registers[x as usize] == state.data_registers[y as usize] {
+                    state.skip_next();
+                }
             }
-            8 => {
-                match optcode_nibble_4 {
-                    0 => {
-                        state.data_registers[optcode_nibble_2 as usize] =
-                            state.data_registers[optcode_nibble_3 as usize]
-                    }
-                    1 => {
-                        state.data_registers[optcode_nibble_2 as usize] |=
-                            state.data_registers[optcode_nibble_3 as usize]
-                    }
-                    2 => {
-                        state.data_registers[optcode_nibble_2 as usize] &=
-                            state.data_registers[optcode_nibble_3 as usize]
-                    }
-                    3 => {
-                        state.data_registers[optcode_nibble_2 as usize] ^=
-                            state.data_registers[optcode_nibble_3 as usize]
-                    }
-                    4 => {
-                        let (added, overflow) = state.data_registers[optcode_nibble_2 as usize]
-                            .overflowing_add(state.data_registers[optcode_nibble_3 as usize]);
-                        state.data_registers[optcode_nibble_2 as usize] = added;
-                        state.data_registers[0xF] = overflow as u8;
-                    }
-                    5 => {
-                        let (subed, mut overflow) = state.data_registers[optcode_nibble_2 as usize]
-                            .overflowing_sub(state.data_registers[optcode_nibble_3 as usize]);
-                        state.data_registers[optcode_nibble_2 as usize] = subed;
-                        overflow = !overflow; // Inverted borrow_flag
-                        state.data_registers[0xF] = overflow as u8;
-                    }
-                    6 => {
-                        let lsb = state.data_registers[optcode_nibble_2 as usize] & 1;
-                        state.data_registers[optcode_nibble_2 as usize] >>= 1;
-                        state.data_registers[0xF] = lsb;
-                    }
-                    7 => {
-                        let (subed, mut overflow) = state.data_registers[optcode_nibble_3 as usize]
-                            .overflowing_sub(state.data_registers[optcode_nibble_2 as usize]);
-                        state.data_registers[optcode_nibble_2 as usize] = subed;
-                        overflow = !overflow; // Inverted borrow_flag
-                        state.data_registers[0xF] = overflow as u8;
-                    }
-                    0xE => {
-                        let mut msb = state.data_registers[optcode_nibble_2 as usize] & 0x80;
-                        state.data_registers[optcode_nibble_2 as usize] -= msb; // Bypass overflow
-                        state.data_registers[optcode_nibble_2 as usize] <<= 1;
-                        msb >>= 7; // Move the most significant bit into the least significant bit
-                        state.data_registers[0xF] = msb;
-                    }
-                    _ => return Err(Chip8Err::UnknownOptcode),
+            Instruction::SaveRange { x, y } => {
+                let registers = register_range(x, y);
+                let values: Vec<u8> =
+                    registers.iter().map(|&register| state.data_registers[register]).collect();
+                let address = state.address_register as usize;
+                try!(state.checked_memory_mut(address, values.len())).copy_from_slice(&values);
+            }
+            Instruction::LoadRange { x, y } => {
+                let registers = register_range(x, y);
+                let values = try!(state.checked_memory(state.address_register as usize, registers.len()))
+                    .to_vec();
+                for (register, value) in registers.into_iter().zip(values) {
+                    state.data_registers[register] = value;
                 }
             }
-            9 => {
-                if optcode_nibble_4 != 0 {
-                    return Err(Chip8Err::UnknownOptcode);
+            Instruction::LdByte { x, byte } => state.data_registers[x as usize] = byte,
+            Instruction::AddByte { x, byte } => {
+                state.data_registers[x as usize] =
+                    state.data_registers[x as usize].wrapping_add(byte)
+            }
+            Instruction::LdReg { x, y } => {
+                state.data_registers[x as usize] = state.data_registers[y as usize]
+            }
+            Instruction::Or { x, y } => {
+                state.data_registers[x as usize] |= state.data_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    state.data_registers[0xF] = 0;
                 }
-                if state.data_registers[optcode_nibble_2 as usize] !=
-                    state.data_registers[optcode_nibble_3 as usize] {
-                    state.program_counter += 2;
+            }
+            Instruction::And { x, y } => {
+                state.data_registers[x as usize] &= state.data_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    state.data_registers[0xF] = 0;
                 }
             }
-            0xA => state.address_register = convert_address(optcode_nibble_2, optcode_byte_2),
-            0xB => {
-                state.program_counter = convert_address(optcode_nibble_2, optcode_byte_2);
-                state.program_counter += state.data_registers[0] as u16;
-                return Ok(());
-            }
-            0xC => {
-                let rand: u8 = self.rng.gen();
-                state.data_registers[optcode_nibble_2 as usize] = rand & optcode_byte_2;
-            }
-            0xD => {
-                state.data_registers[0xF] = 0;
-                for (line_n, line) in state.frame_buffer
-                    .iter_mut()
-                    .skip(state.data_registers[optcode_nibble_3 as usize] as usize)
-                    .take(optcode_nibble_4 as usize)
-                    .enumerate() {
-                    let mut mut_bit = MutBit::new(line);
-                    mut_bit.skip(state.data_registers[optcode_nibble_2 as usize]);
-                    for bit in BitIter::new(
-                        &state.memory[state.address_register as usize + line_n..state.memory.len()])
-                        .take(8) {
-                        if bit && mut_bit.toggle() {
-                            state.data_registers[0xF] = 1;
-                        }
-                        mut_bit.next();
-                    }
+            Instruction::Xor { x, y } => {
+                state.data_registers[x as usize] ^= state.data_registers[y as usize];
+                if self.quirks.logic_resets_vf {
+                    state.data_registers[0xF] = 0;
                 }
             }
-            0xE => {
-                match optcode_byte_2 {
-                    0x9E => {
-                        if self.key_wrapper
-                            .is_pushed(state.data_registers[optcode_nibble_2 as usize]) {
-                            state.program_counter += 2;
-                        }
-                    }
-                    0xA1 => {
-                        if !self.key_wrapper
-                            .is_pushed(state.data_registers[optcode_nibble_2 as usize]) {
-                            state.program_counter += 2;
-                        }
-                    }
-                    _ => return Err(Chip8Err::UnknownOptcode),
+            Instruction::Add { x, y } => {
+                let (added, overflow) = state.data_registers[x as usize]
+                    .overflowing_add(state.data_registers[y as usize]);
+                state.data_registers[x as usize] = added;
+                state.data_registers[0xF] = overflow as u8;
+            }
+            Instruction::Sub { x, y } => {
+                let (subed, mut overflow) = state.data_registers[x as usize]
+                    .overflowing_sub(state.data_registers[y as usize]);
+                state.data_registers[x as usize] = subed;
+                overflow = !overflow; // Inverted borrow_flag
+                state.data_registers[0xF] = overflow as u8;
+            }
+            Instruction::Shr { x, y } => {
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let lsb = state.data_registers[source as usize] & 1;
+                state.data_registers[x as usize] = state.data_registers[source as usize] >> 1;
+                state.data_registers[0xF] = lsb;
+            }
+            Instruction::Subn { x, y } => {
+                let (subed, mut overflow) = state.data_registers[y as usize]
+                    .overflowing_sub(state.data_registers[x as usize]);
+                state.data_registers[x as usize] = subed;
+                overflow = !overflow; // Inverted borrow_flag
+                state.data_registers[0xF] = overflow as u8;
+            }
+            Instruction::Shl { x, y } => {
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                let mut msb = state.data_registers[source as usize] & 0x80;
+                // Bypass overflow
+                state.data_registers[x as usize] = (state.data_registers[source as usize] - msb) << 1;
+                msb >>= 7; // Move the most significant bit into the least significant bit
+                state.data_registers[0xF] = msb;
+            }
+            Instruction::SneReg { x, y } => {
+                if state.data_registers[x as usize] != state.data_registers[y as usize] {
+                    state.skip_next();
+                }
+            }
+            Instruction::LdI(address) => state.address_register = address,
+            Instruction::JpV0(address) => {
+                let offset_register = if self.quirks.jump_uses_vx { address >> 8 } else { 0 };
+                state.program_counter = address;
+                state.program_counter += state.data_registers[offset_register as usize] as u16;
+                return Ok((None, 0));
+            }
+            Instruction::Rnd { x, byte } => {
+                state.data_registers[x as usize] = self.rng.next_byte(state) & byte;
+            }
+            Instruction::Drw { x, y, n } => {
+                let x = state.data_registers[x as usize];
+                let y = state.data_registers[y as usize];
+                let collision = if n == 0 && state.mode != Mode::Chip8 {
+                    try!(state.draw_sprite(x, y, 16, true, &self.quirks))
+                } else {
+                    try!(state.draw_sprite(x, y, n as usize, false, &self.quirks))
+                };
+                state.data_registers[0xF] = collision as u8;
+            }
+            Instruction::Skp(x) => {
+                if self.key_wrapper.is_pushed(state.data_registers[x as usize]) {
+                    state.skip_next();
                 }
             }
-            0xF => {
-                match optcode_byte_2 {
-                    0x07 => state.data_registers[optcode_nibble_2 as usize] = state.delay_timer,
-                    0x0A => {
-                        if let Some(key) = self.key_wrapper.get_key() {
-                            state.data_registers[optcode_nibble_2 as usize] = key
-                        } else {
-                            return Ok(());
+            Instruction::Sknp(x) => {
+                if !self.key_wrapper.is_pushed(state.data_registers[x as usize]) {
+                    state.skip_next();
+                }
+            }
+            Instruction::LdLongI(address) => state.address_register = address,
+            Instruction::Plane(planes) => state.planes = planes,
+            Instruction::Audio => {
+                let pattern = try!(state.checked_memory(state.address_register as usize, 16)).to_vec();
+                state.audio_pattern.copy_from_slice(&pattern);
+                self.audio_wrapper.set_pattern(&state.audio_pattern, state.pitch);
+            }
+            Instruction::LdVxDt(x) => state.data_registers[x as usize] = state.delay_timer,
+            Instruction::LdVxK(x) => {
+                let release = self.quirks.key_release;
+                let mut pressed = waiting.and_then(|key| key);
+                let mut done = None;
+                if let Some(events) = self.key_wrapper.events() {
+                    while let Some(event) = events.next_event(self.frames) {
+                        // Whatever happened before FX0A started waiting doesn't count
+                        if waiting.is_none() && event.frame < self.frames {
+                            continue;
                         }
-                    }
-                    0x15 => state.delay_timer = state.data_registers[optcode_nibble_2 as usize],
-                    0x18 => {
-                        state.sound_timer = state.data_registers[optcode_nibble_2 as usize];
-                        if state.sound_timer > 0 {
-                            self.audio_wrapper.play();
+                        match pressed {
+                            None if event.pressed && release => pressed = Some(event.key),
+                            None if event.pressed => done = Some(event.key),
+                            Some(key) if key == event.key && !event.pressed => done = Some(key),
+                            _ => {}
                         }
-                    }
-                    0x1E => {
-                        state.address_register +=
-                            state.data_registers[optcode_nibble_2 as usize] as u16
-                    }
-                    0x29 => {
-                        // Font loading
-                        state.address_register =
-                            state.data_registers[optcode_nibble_2 as usize] as u16 * 5
-                    }
-                    0x33 => {
-                        let nums = state.data_registers[optcode_nibble_2 as usize];
-                        state.memory[state.address_register as usize] = nums / 100;
-                        state.memory[state.address_register as usize + 1] = nums % 100 / 10;
-                        state.memory[state.address_register as usize + 2] = nums % 100 % 10;
-                    }
-                    0x55 => {
-                        for i in 0..optcode_nibble_2 as usize + 1 {
-                            state.memory[state.address_register as usize + i] =
-                                state.data_registers[i];
+                        if done.is_some() {
+                            // Later events are left for the next FX0A
+                            break;
                         }
                     }
-                    0x65 => {
-                        for i in 0..optcode_nibble_2 as usize + 1 {
-                            state.data_registers[i] = state.memory[state.address_register as usize +
-                            i];
+                } else {
+                    match pressed {
+                        Some(key) => {
+                            if !self.key_wrapper.is_pushed(key) {
+                                done = Some(key)
+                            }
                         }
+                        None if release => pressed = self.key_wrapper.get_key(),
+                        None => done = self.key_wrapper.get_key(),
                     }
-                    _ => return Err(Chip8Err::UnknownOptcode),
                 }
+                match done {
+                    Some(key) => state.data_registers[x as usize] = key,
+                    None => return Ok((Some((x, pressed)), 0)),
+                }
+            }
+            Instruction::LdDtVx(x) => state.delay_timer = state.data_registers[x as usize],
+            Instruction::LdStVx(x) => {
+                state.sound_timer = state.data_registers[x as usize];
+                if state.sound_timer > 0 {
+                    self.audio_wrapper.play();
+                }
+            }
+            Instruction::AddI(x) => {
+                state.address_register =
+                    state.address_register.wrapping_add(state.data_registers[x as usize] as u16)
+            }
+            Instruction::LdF(x) => {
+                // Font loading
+                state.address_register = state.data_registers[x as usize] as u16 * 5
+            }
+            Instruction::LdHf(x) => {
+                // Big font loading
+                state.address_register = BIG_FONT_ADDRESS as u16 +
+                    (state.data_registers[x as usize] & 0xF) as u16 * 10
+            }
+            Instruction::LdB(x) => {
+                let nums = state.data_registers[x as usize];
+                let address = state.address_register as usize;
+                try!(state.checked_memory_mut(address, 3))
+                    .copy_from_slice(&[nums / 100, nums % 100 / 10, nums % 100 % 10]);
+            }
+            Instruction::Pitch(x) => {
+                state.pitch = state.data_registers[x as usize];
+                self.audio_wrapper.set_pattern(&state.audio_pattern, state.pitch);
+            }
+            Instruction::LdIVx(x) => {
+                let len = x as usize + 1;
+                let address = state.address_register as usize;
+                let values = state.data_registers;
+                try!(state.checked_memory_mut(address, len)).copy_from_slice(&values[..len]);
+                state.address_register =
+                    state.address_register.wrapping_add(self.quirks.load_store.increment(x));
+            }
+            Instruction::LdVxI(x) => {
+                let len = x as usize + 1;
+                let values = try!(state.checked_memory(state.address_register as usize, len)).to_vec();
+                state.data_registers[..len].copy_from_slice(&values);
+                state.address_register =
+                    state.address_register.wrapping_add(self.quirks.load_store.increment(x));
+            }
+            Instruction::LdRVx(x) => {
+                let len = x as usize + 1;
+                state.rpl_flags[..len].copy_from_slice(&state.data_registers[..len]);
+            }
+            Instruction::LdVxR(x) => {
+                let len = x as usize + 1;
+                state.data_registers[..len].copy_from_slice(&state.rpl_flags[..len]);
             }
-            _ => return Err(Chip8Err::UnknownOptcode),
         }
-        state.program_counter += 2;
-        Ok(())
+        state.program_counter = state.program_counter.wrapping_add(instruction.size());
+        Ok((None, 0))
     }
-    fn run_vblank_uncaught(&mut self) -> Result<(), Chip8Err> {
-        for _ in 0..11 {
-            try!(self.run_optcode())
-        }
-        let mut state;
-        if let Ok(ref mut good_state) = self.state {
-            state = good_state
-        } else {
-            return Err(Chip8Err::BadState)
-        }
+    fn tick_timers(&mut self) -> Result<(), Chip8Err> {
+        let state = match self.state {
+            RunState::Running(ref mut state) |
+            RunState::WaitingForKey { ref mut state, .. } |
+            RunState::Idle(ref mut state) => state,
+            _ => return Err(Chip8Err::BadState),
+        };
         if state.delay_timer > 0 {
             state.delay_timer -= 1;
         }
@@ -514,22 +1155,258 @@ impl<T: KeyWrapper, A: AudioWrapper> Chip8<T, A> {
         }
         Ok(())
     }
-    /// Simulates one frame of a chip8
-    pub fn run_vblank(&mut self) -> Result<(), Chip8Err> {
-        if let Err(error) = self.run_vblank_uncaught() {
+    /// How far through a frame the machine gets before the timers tick
+    fn frame_len(&self) -> u32 {
+        if self.vip_timing {
+            timing::CYCLES_PER_FRAME
+        } else {
+            cmp::max(self.instructions_per_frame, 1)
+        }
+    }
+    /// Runs an instruction, returning how far it moved the machine through the frame
+    fn step_uncaught(&mut self) -> Result<u32, Chip8Err> {
+        if let RunState::Idle(_) = self.state {
+            if self.skip_idle {
+                // Nothing can change before the timers tick, so go straight there
+                let advance = self.frame_len().saturating_sub(self.frame_cycle);
+                try!(self.advance_frame(advance));
+                return Ok(advance);
+            }
+        }
+        let (program_counter, instruction, mut advance, idle) = match self.state.running() {
+            Some(state) => {
+                let program_counter = state.program_counter as usize;
+                let instruction =
+                    Instruction::fetch(&state.memory, program_counter, state.mode, &self.quirks).ok();
+                let idle = self.idle_loop.observe(instruction.as_ref(), state);
+                let advance = match instruction {
+                    Some(ref instruction) if self.vip_timing => {
+                        timing::instruction_cycles(instruction, state)
+                    }
+                    _ => 1,
+                };
+                (state.program_counter, instruction, advance, idle)
+            }
+            None => return Err(Chip8Err::BadState),
+        };
+        let routine_cycles = try!(self.run_optcode());
+        if self.vip_timing {
+            advance += routine_cycles;
+        }
+        if let Some(idle) = idle {
+            self.state.set_idle(idle);
+        }
+        if self.history.len() >= HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(program_counter);
+        if let Some(instruction) = instruction {
+            let next = program_counter.wrapping_add(instruction.size());
+            let skipped = self.state.running().map_or(false, |state| state.program_counter != next);
+            if self.vip_timing && timing::is_skip(&instruction) && skipped {
+                advance += timing::SKIP_CYCLES;
+            }
+            if let Instruction::Drw { .. } = instruction {
+                if self.quirks.display_wait {
+                    // Drawing waits for the display interrupt, which starts the next frame
+                    advance = cmp::max(advance, self.frame_len().saturating_sub(self.frame_cycle));
+                }
+            }
+        }
+        self.cycles += 1;
+        try!(self.advance_frame(advance));
+        Ok(advance)
+    }
+    /// Moves advance through the frame, ticking the timers at the end of it
+    fn advance_frame(&mut self, advance: u32) -> Result<(), Chip8Err> {
+        self.frame_cycle += advance;
+        let frame_len = self.frame_len();
+        while self.frame_cycle >= frame_len {
+            // Machine cycles past the end of a frame are taken from the next one
+            self.frame_cycle = if self.vip_timing { self.frame_cycle - frame_len } else { 0 };
+            self.frames += 1;
+            try!(self.tick_timers());
+            self.idle_loop.reset();
+            self.state.set_idle(false);
+        }
+        Ok(())
+    }
+    /// Stops the machine after an error, as Halted if the program exited and Faulted otherwise
+    ///
+    /// Exiting is how a program is meant to end, so it isn't an error to the caller.
+    fn catch(&mut self, result: Result<(), Chip8Err>) -> Result<(), Chip8Err> {
+        if let Err(error) = result {
             if error != Chip8Err::BadState {
-                let old_state = mem::replace(&mut self.state, Err((None, error))).ok().unwrap();
-                self.state = Err((Some(old_state), error));
-                self.audio_wrapper.stop()
+                let old_state = match mem::replace(&mut self.state, RunState::Empty).into_state() {
+                    Some(state) => state,
+                    None => return Err(error),
+                };
+                self.audio_wrapper.stop();
+                if error == Chip8Err::Exit {
+                    self.state = RunState::Halted(old_state);
+                    return Ok(());
+                }
+                let program_counter = old_state.program_counter;
+                self.fault = Some(Fault {
+                    error: error,
+                    program_counter: program_counter,
+                    optcode: old_state.checked_memory(program_counter as usize, 2)
+                        .ok()
+                        .map(|word| (word[0] as u16) << 8 | word[1] as u16),
+                    cycle: self.cycles,
+                    frame: self.frames,
+                    history: self.history.iter().cloned().collect(),
+                });
+                self.state = RunState::Faulted {
+                    err: error,
+                    state: old_state,
+                };
             }
             Err(error)
         } else {
             Ok(())
         }
     }
+    /// Runs a single instruction, ticking the timers if it was the last one of the frame
+    ///
+    /// A program that runs 00FD or jumps to itself leaves the machine halted, which
+    /// state.is_halted tells apart from an instruction that carried on.
+    pub fn step(&mut self) -> Result<(), Chip8Err> {
+        self.step_advance().map(|_| ())
+    }
+    /// Runs a single instruction, returning how far it moved the machine through the frame
+    fn step_advance(&mut self) -> Result<u32, Chip8Err> {
+        match self.step_uncaught() {
+            Ok(advance) => Ok(advance),
+            Err(error) => self.catch(Err(error)).map(|_| 0),
+        }
+    }
+    /// Simulates the rest of the current frame of a chip8, stopping early if the program halts
+    pub fn run_vblank(&mut self) -> Result<(), Chip8Err> {
+        let frame = self.frames;
+        while self.frames == frame && !self.state.is_halted() {
+            try!(self.step());
+        }
+        Ok(())
+    }
+    /// Runs cycles instructions, ticking the timers wherever a frame ends among them, stopping
+    /// early if the program halts
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), Chip8Err> {
+        for _ in 0..cycles {
+            if self.state.is_halted() {
+                break;
+            }
+            try!(self.step());
+        }
+        Ok(())
+    }
+    /// Runs as many instructions as fit in duration, with timer_hz frames a second
+    ///
+    /// Time too short for a whole instruction is saved for the next call, as is time an
+    /// instruction ran over by, so calling this with the time since the last call keeps the
+    /// machine at the right speed. Returns the number of instructions run, which stops short if
+    /// the program halts.
+    pub fn run_for(&mut self, duration: Duration) -> Result<u64, Chip8Err> {
+        let per_second = self.frame_len() as u64 * self.timer_hz as u64;
+        // Whole seconds are counted apart from the nanoseconds so long durations can't overflow
+        let fraction = (duration.subsec_nanos() as u64).saturating_mul(per_second)
+            .saturating_add(self.cycle_nanos);
+        self.cycle_nanos = fraction % 1000000000;
+        let mut budget = duration.as_secs().saturating_mul(per_second)
+            .saturating_add(fraction / 1000000000);
+        if budget <= self.overrun {
+            self.overrun -= budget;
+            return Ok(0);
+        }
+        budget -= self.overrun;
+        self.overrun = 0;
+        let mut cycles = 0;
+        while budget > 0 && !self.state.is_halted() {
+            let advance = try!(self.step_advance()) as u64;
+            cycles += 1;
+            if advance >= budget {
+                self.overrun = advance - budget;
+                break;
+            }
+            budget -= advance;
+        }
+        Ok(cycles)
+    }
+    /// The number of instructions executed since the program was loaded
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    /// The number of frames completed since the program was loaded
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+    /// How far the current frame has got, in instructions or in VIP machine cycles
+    pub fn frame_cycle(&self) -> u32 {
+        self.frame_cycle
+    }
+    /// What the machine was doing when it faulted, if it is stopped by a fault
+    pub fn fault(&self) -> Option<&Fault> {
+        match self.state {
+            RunState::Faulted { .. } => self.fault.as_ref(),
+            _ => None,
+        }
+    }
+    /// Writes a crash report of the fault the machine is stopped by, returning false if it isn't
+    pub fn write_crash_report<W: Write>(&self, output: &mut W) -> io::Result<bool> {
+        match (self.fault(), &self.state) {
+            (Some(fault), &RunState::Faulted { ref state, .. }) => {
+                try!(fault.write_report(output, Some(state)));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Starts a halted or faulted machine again, for example after patching its memory
+    ///
+    /// The instruction that stopped it runs again. Returns false if the machine isn't stopped.
+    pub fn resume(&mut self) -> bool {
+        self.state = match mem::replace(&mut self.state, RunState::Empty) {
+            RunState::Halted(state) |
+            RunState::Faulted { state, .. } => RunState::Running(state),
+            other => {
+                self.state = other;
+                return false;
+            }
+        };
+        true
+    }
+    /// Starts the loaded program over, returning false if there isn't one
+    pub fn reset(&mut self) -> bool {
+        let state = match self.initial_state {
+            Some(ref state) => state.clone(),
+            None => return false,
+        };
+        self.audio_wrapper.stop();
+        self.state = RunState::Running(state);
+        self.clear_counters();
+        true
+    }
+    /// Forgets the run so far, for a machine that is starting over
+    fn clear_counters(&mut self) {
+        self.history.clear();
+        self.idle_loop.reset();
+        self.fault = None;
+        self.cycle_nanos = 0;
+        self.overrun = 0;
+        self.cycles = 0;
+        self.frames = 0;
+        self.frame_cycle = 0;
+    }
     pub fn load_prog<R: Read>(&mut self, input: &mut R) -> Result<(), Error> {
+        self.load_prog_with_mode(input, Mode::Chip8)
+    }
+    pub fn load_prog_with_mode<R: Read>(&mut self, input: &mut R, mode: Mode)
+        -> Result<(), Error> {
         self.audio_wrapper.stop();
-        self.state = Ok(try!(Chip8State::from_prog(input)));
+        let state = try!(Chip8State::from_prog_with_mode(input, mode));
+        self.initial_state = Some(state.clone());
+        self.state = RunState::Running(state);
+        self.clear_counters();
         Ok(())
     }
 }
@@ -538,24 +1415,115 @@ impl<T, K> Clone for Chip8<T, K> where T: Clone + KeyWrapper, K: Clone + AudioWr
     fn clone(&self) -> Chip8<T, K> {
         Chip8 {
             state: self.state.clone(),
-            rng: rand::thread_rng(),
+            initial_state: self.initial_state.clone(),
+            rng: self.rng.box_clone(),
             key_wrapper: self.key_wrapper.clone(),
             audio_wrapper: self.audio_wrapper.clone(),
+            quirks: self.quirks,
+            instructions_per_frame: self.instructions_per_frame,
+            vip_timing: self.vip_timing,
+            timer_hz: self.timer_hz,
+            skip_idle: self.skip_idle,
+            // A trace only follows the original machine
+            tracer: None,
+            history: self.history.clone(),
+            idle_loop: self.idle_loop.clone(),
+            fault: self.fault.clone(),
+            cycles: self.cycles,
+            frames: self.frames,
+            frame_cycle: self.frame_cycle,
+            cycle_nanos: self.cycle_nanos,
+            overrun: self.overrun,
         }
     }
 }
 
-/// Panics if the state is invalid
+/// Panics if no program is loaded
 impl<T, K> Deref for Chip8<T, K> where T: KeyWrapper, K: AudioWrapper {
     type Target = Chip8State;
 
     fn deref(&self) -> &Chip8State {
-        self.state.as_ref().ok().expect("Tried to deref an invalid state")
+        self.state.state().expect("Tried to deref an empty machine")
     }
 }
 
 impl<T, K> DerefMut for Chip8<T, K> where T: KeyWrapper, K: AudioWrapper {
     fn deref_mut(&mut self) -> &mut Chip8State {
-        self.state.as_mut().ok().expect("Tried to deref an invalid state")
+        self.state.state_mut().expect("Tried to deref an empty machine")
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use Chip8;
+    use Chip8Err;
+    use Mode;
+    use Quirks;
+    use std::time::Duration;
+    use testing::{NoKeys, Silence, machine};
+
+    /// Runs program after pointing I at address, returning the error of its last instruction
+    fn fault_at(program: &[u8], mode: Mode, address: u16) -> Result<(), Chip8Err> {
+        let mut chip8 = Chip8::new(NoKeys, Silence);
+        chip8.load_prog_with_mode(&mut &program[..], mode).unwrap();
+        chip8.set_address_register(address);
+        for _ in 1..program.len() / 2 {
+            chip8.step().unwrap();
+        }
+        chip8.step()
+    }
+
+    #[test]
+    fn index_past_the_end_of_memory() {
+        let end = Chip8Err::MemoryOutOfBounds { addr: 0x1000 };
+        assert_eq!(fault_at(&[0xF5, 0x55], Mode::Chip8, 0xFFE), Err(end));
+        assert_eq!(fault_at(&[0xF5, 0x65], Mode::Chip8, 0xFFE), Err(end));
+        assert_eq!(fault_at(&[0xF0, 0x33], Mode::Chip8, 0xFFF), Err(end));
+        assert_eq!(fault_at(&[0xD0, 0x05], Mode::Chip8, 0xFFE), Err(end));
+        assert_eq!(fault_at(&[0xF0, 0x02], Mode::XoChip, 0xFFF8),
+                   Err(Chip8Err::MemoryOutOfBounds { addr: 0x10000 }));
+        // The last byte of memory is still in reach
+        assert_eq!(fault_at(&[0xF0, 0x55], Mode::Chip8, 0xFFF), Ok(()));
+    }
+
+    #[test]
+    fn vip_stack_below_memory() {
+        let mut quirks = Quirks::cosmac_vip();
+        quirks.stack_depth = usize::max_value();
+        let mut chip8 = machine(&[0x22, 0x00], quirks);
+        // Slot 0x767 is the last one at or above address 0
+        chip8.stack = vec![0x202; 0x768];
+        let wrapped = Chip8Err::MemoryOutOfBounds { addr: usize::max_value() - 1 };
+        assert_eq!(chip8.step(), Err(wrapped));
+
+        let mut chip8 = machine(&[0x00, 0xEE], quirks);
+        chip8.stack = vec![0x202; 0x769];
+        assert_eq!(chip8.step(), Err(wrapped));
+        let mut chip8 = machine(&[0x00, 0xEE], quirks);
+        chip8.stack = vec![0x202; 0x768];
+        assert_eq!(chip8.step(), Ok(()));
+    }
+
+    #[test]
+    fn halting_is_not_an_error() {
+        // Counts in V0 and then jumps to itself
+        let program = [0x70, 0x01, 0x30, 0x03, 0x12, 0x00, 0x12, 0x06];
+        let mut chip8 = machine(&program, Quirks::default());
+        assert_eq!(chip8.run_vblank(), Ok(()));
+        assert!(chip8.state.is_halted());
+        assert_eq!((chip8.program_counter, chip8.data_registers[0]), (0x206, 3));
+        assert_eq!(chip8.run_vblank(), Ok(()));
+
+        let mut chip8 = machine(&program, Quirks::default());
+        assert_eq!(chip8.run_cycles(100), Ok(()));
+        assert!(chip8.state.is_halted());
+        let mut chip8 = machine(&program, Quirks::default());
+        assert_eq!(chip8.run_for(Duration::from_secs(1)), Ok(9));
+        assert!(chip8.state.is_halted());
+
+        let mut chip8 = Chip8::new(NoKeys, Silence);
+        chip8.load_prog_with_mode(&mut &[0x00, 0xFD][..], Mode::SuperChip).unwrap();
+        assert_eq!(chip8.step(), Ok(()));
+        assert!(chip8.state.is_halted());
+        assert_eq!(chip8.program_counter, 0x200);
+    }
+}
@@ -0,0 +1,336 @@
+//! Recording key presses and playing them back
+//!
+//! A movie file is the magic "C8MV", a little endian u16 version, the ROM's SHA-1, the mode byte,
+//! the quirks as text behind a little endian u16 length, the little endian u64 seed, and the
+//! instructions per frame and timer frequency as little endian u32s followed by a byte with bit 0
+//! set for VIP timing and bit 1 for skipping idle loops. Every frame follows as a little endian
+//! u16 of held keys and the FX0A key, 0xFF for none. Version 1 movies have no speed settings.
+//!
+//! Both wrappers sample the keys once a frame, when the frontend calls next_frame before running
+//! the frame, so the machine sees exactly the same keys on playback.
+
+use AudioWrapper;
+use Chip8;
+use DEFAULT_INSTRUCTIONS_PER_FRAME;
+use DEFAULT_TIMER_HZ;
+use KeyWrapper;
+use Mode;
+use Quirks;
+use savestate::rom_sha1;
+use std::io;
+use std::io::prelude::*;
+
+const MAGIC: &'static [u8; 4] = b"C8MV";
+const VERSION: u16 = 2;
+
+/// The keys of one frame
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MovieFrame {
+    /// One bit per held key
+    pub keys: u16,
+    /// What get_key returned
+    pub key: Option<u8>,
+}
+
+/// A run of a ROM, with everything needed to reproduce it
+///
+/// The machine is set up from the movie by start, when recording as well as on playback.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Movie {
+    pub rom_sha1: [u8; 20],
+    pub mode: Mode,
+    pub quirks: Quirks,
+    /// The seed of the machine's random number generator
+    pub seed: u64,
+    pub instructions_per_frame: u32,
+    pub vip_timing: bool,
+    pub timer_hz: u32,
+    pub skip_idle: bool,
+    pub frames: Vec<MovieFrame>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Movie {
+    /// An empty movie of rom, run at the default speed
+    pub fn new(rom: &[u8], mode: Mode, quirks: Quirks, seed: u64) -> Movie {
+        Movie {
+            rom_sha1: rom_sha1(rom),
+            mode: mode,
+            quirks: quirks,
+            seed: seed,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            vip_timing: false,
+            timer_hz: DEFAULT_TIMER_HZ,
+            skip_idle: false,
+            frames: Vec::new(),
+        }
+    }
+    /// Loads rom into a machine with the movie's mode, quirks, seed and speed, ready for its first
+    /// frame
+    pub fn start<T, A>(&self, chip8: &mut Chip8<T, A>, rom: &[u8]) -> io::Result<()>
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        if rom_sha1(rom) != self.rom_sha1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the movie was made with a different ROM"));
+        }
+        chip8.quirks = self.quirks;
+        chip8.seed(self.seed);
+        chip8.instructions_per_frame = self.instructions_per_frame;
+        chip8.vip_timing = self.vip_timing;
+        chip8.timer_hz = self.timer_hz;
+        chip8.skip_idle = self.skip_idle;
+        chip8.load_prog_with_mode(&mut &rom[..], self.mode)
+    }
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let quirks = self.quirks.to_text();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION as u8, (VERSION >> 8) as u8]);
+        bytes.extend_from_slice(&self.rom_sha1);
+        bytes.push(self.mode.to_byte());
+        bytes.extend_from_slice(&[quirks.len() as u8, (quirks.len() >> 8) as u8]);
+        bytes.extend_from_slice(quirks.as_bytes());
+        bytes.extend((0..8).map(|byte| (self.seed >> (byte * 8)) as u8));
+        bytes.extend((0..4).map(|byte| (self.instructions_per_frame >> (byte * 8)) as u8));
+        bytes.extend((0..4).map(|byte| (self.timer_hz >> (byte * 8)) as u8));
+        bytes.push(self.vip_timing as u8 | (self.skip_idle as u8) << 1);
+        for frame in &self.frames {
+            bytes.extend_from_slice(&[frame.keys as u8, (frame.keys >> 8) as u8, frame.key.unwrap_or(0xFF)]);
+        }
+        output.write_all(&bytes)
+    }
+    pub fn read<R: Read>(input: &mut R) -> io::Result<Movie> {
+        let mut header = [0; 27];
+        try!(input.read_exact(&mut header));
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a movie"));
+        }
+        let version = header[4] as u16 | (header[5] as u16) << 8;
+        if version == 0 || version > VERSION {
+            return Err(invalid("unsupported movie version"));
+        }
+        let mut rom_sha1 = [0; 20];
+        rom_sha1.copy_from_slice(&header[6..26]);
+        let mode = try!(Mode::from_byte(header[26]).ok_or_else(|| invalid("bad mode in movie")));
+        let mut len = [0; 2];
+        try!(input.read_exact(&mut len));
+        let mut quirks = vec![0; len[0] as usize | (len[1] as usize) << 8];
+        try!(input.read_exact(&mut quirks));
+        let quirks = try!(Quirks::from_text(&String::from_utf8_lossy(&quirks))
+            .ok_or_else(|| invalid("bad quirks in movie")));
+        let mut seed = [0; 8];
+        try!(input.read_exact(&mut seed));
+        let mut movie = Movie {
+            rom_sha1: rom_sha1,
+            mode: mode,
+            quirks: quirks,
+            seed: seed.iter().rev().fold(0, |seed, &byte| seed << 8 | byte as u64),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            vip_timing: false,
+            timer_hz: DEFAULT_TIMER_HZ,
+            skip_idle: false,
+            frames: Vec::new(),
+        };
+        if version >= 2 {
+            let mut speed = [0; 9];
+            try!(input.read_exact(&mut speed));
+            let u32_at = |index: usize| {
+                speed[index..index + 4].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+            };
+            movie.instructions_per_frame = u32_at(0);
+            movie.timer_hz = u32_at(4);
+            movie.vip_timing = speed[8] & 1 != 0;
+            movie.skip_idle = speed[8] & 2 != 0;
+            if movie.timer_hz == 0 {
+                return Err(invalid("bad timer frequency in movie"));
+            }
+        }
+        let mut frames = Vec::new();
+        try!(input.read_to_end(&mut frames));
+        if frames.len() % 3 != 0 {
+            return Err(invalid("movie ends in the middle of a frame"));
+        }
+        movie.frames = frames.chunks(3)
+            .map(|frame| {
+                MovieFrame {
+                    keys: frame[0] as u16 | (frame[1] as u16) << 8,
+                    key: if frame[2] == 0xFF { None } else { Some(frame[2]) },
+                }
+            })
+            .collect();
+        Ok(movie)
+    }
+}
+
+/// Passes on the keys of another wrapper while recording them into a movie
+pub struct MovieRecorder<K: KeyWrapper> {
+    pub inner: K,
+    movie: Movie,
+    frame: MovieFrame,
+}
+
+impl<K: KeyWrapper> MovieRecorder<K> {
+    /// Records onto the end of movie
+    pub fn new(inner: K, movie: Movie) -> MovieRecorder<K> {
+        MovieRecorder {
+            inner: inner,
+            movie: movie,
+            frame: MovieFrame::default(),
+        }
+    }
+    /// Samples the inner wrapper for the coming frame
+    pub fn next_frame(&mut self) {
+        self.frame = MovieFrame {
+            keys: (0..16).filter(|&key| self.inner.is_pushed(key)).fold(0, |keys, key| keys | 1 << key),
+            key: self.inner.get_key(),
+        };
+        self.movie.frames.push(self.frame);
+    }
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+impl<K: KeyWrapper> KeyWrapper for MovieRecorder<K> {
+    fn is_pushed(&self, key: u8) -> bool {
+        self.frame.keys & 1 << (key & 0xF) != 0
+    }
+    fn get_key(&self) -> Option<u8> {
+        self.frame.key
+    }
+}
+
+/// Plays back the keys of a movie, holding nothing once it runs out
+pub struct MoviePlayer {
+    movie: Movie,
+    position: usize,
+    frame: MovieFrame,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            movie: movie,
+            position: 0,
+            frame: MovieFrame::default(),
+        }
+    }
+    /// Moves on to the keys of the coming frame, returning false once the movie has run out
+    pub fn next_frame(&mut self) -> bool {
+        self.frame = self.movie.frames.get(self.position).cloned().unwrap_or_default();
+        self.position += 1;
+        self.position <= self.movie.frames.len()
+    }
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.movie.frames.len()
+    }
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+impl KeyWrapper for MoviePlayer {
+    fn is_pushed(&self, key: u8) -> bool {
+        self.frame.keys & 1 << (key & 0xF) != 0
+    }
+    fn get_key(&self) -> Option<u8> {
+        self.frame.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Chip8;
+    use KeyWrapper;
+    use Mode;
+    use Quirks;
+    use super::{Movie, MovieFrame, MoviePlayer, MovieRecorder};
+    use testing::{Silence, assert_same};
+
+    /// Adds random numbers into V2, waits for a key while key 0 is held, sums the keys in V6,
+    /// counts the loops in V4 and then waits for the delay timer
+    const PROGRAM: [u8; 24] = [0xC1, 0xFF, 0x82, 0x14, 0xE0, 0xA1, 0xF5, 0x0A, 0x86, 0x54, 0x74, 0x01,
+                               0x67, 0x03, 0xF7, 0x15, 0xF7, 0x07, 0x37, 0x00, 0x12, 0x10, 0x12, 0x00];
+
+    /// Keys the test sets by hand
+    struct Held(MovieFrame);
+
+    impl KeyWrapper for Held {
+        fn is_pushed(&self, key: u8) -> bool {
+            self.0.keys & 1 << key != 0
+        }
+        fn get_key(&self) -> Option<u8> {
+            self.0.key
+        }
+    }
+
+    fn movie() -> Movie {
+        let mut movie = Movie::new(&PROGRAM, Mode::Chip8, Quirks::default(), 0x1234_5678_9ABC_DEF0);
+        movie.instructions_per_frame = 7;
+        movie.timer_hz = 50;
+        movie.skip_idle = true;
+        movie
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut movie = movie();
+        movie.vip_timing = true;
+        movie.frames = vec![MovieFrame { keys: 0x8001, key: None }, MovieFrame { keys: 0, key: Some(0xA) }];
+        let mut bytes = Vec::new();
+        movie.write(&mut bytes).unwrap();
+        assert_eq!(Movie::read(&mut &bytes[..]).unwrap(), movie);
+    }
+
+    #[test]
+    fn version_one_plays_at_the_default_speed() {
+        let mut movie = movie();
+        movie.frames = vec![MovieFrame { keys: 1, key: Some(3) }];
+        let mut bytes = Vec::new();
+        movie.write(&mut bytes).unwrap();
+        bytes[4] = 1;
+        let speed = bytes.len() - 3 - 9;
+        bytes.drain(speed..speed + 9);
+        let old = Movie::read(&mut &bytes[..]).unwrap();
+        assert_eq!(old.frames, movie.frames);
+        let default = Movie::new(&PROGRAM, Mode::Chip8, Quirks::default(), movie.seed);
+        assert_eq!((old.instructions_per_frame, old.vip_timing, old.timer_hz, old.skip_idle),
+                   (default.instructions_per_frame, default.vip_timing, default.timer_hz, default.skip_idle));
+    }
+
+    #[test]
+    fn playback_matches_recording() {
+        let movie = movie();
+        let recorder = MovieRecorder::new(Held(MovieFrame::default()), movie.clone());
+        let mut recording = Chip8::new(recorder, Silence);
+        movie.start(&mut recording, &PROGRAM).unwrap();
+        for frame in 0..40u16 {
+            recording.key_wrapper.inner.0 = MovieFrame {
+                keys: frame.wrapping_mul(0x9E37),
+                key: if frame % 3 == 0 { Some(frame as u8 & 0xF) } else { None },
+            };
+            recording.key_wrapper.next_frame();
+            recording.run_vblank().unwrap();
+        }
+        let mut bytes = Vec::new();
+        recording.key_wrapper.movie().write(&mut bytes).unwrap();
+
+        let mut playback = Chip8::new(MoviePlayer::new(Movie::read(&mut &bytes[..]).unwrap()), Silence);
+        let movie = playback.key_wrapper.movie().clone();
+        movie.start(&mut playback, &PROGRAM).unwrap();
+        while playback.key_wrapper.next_frame() {
+            playback.run_vblank().unwrap();
+        }
+        assert!(playback.key_wrapper.is_finished());
+        assert_same(&playback, &recording);
+        assert_eq!((playback.cycles, playback.frames), (recording.cycles, recording.frames));
+        assert!(playback.data_registers[6] != 0);
+    }
+}
@@ -0,0 +1,789 @@
+//! A compiler for the Octo language
+//!
+//! Supports labels, register and i operations, `if ... then`, `if ... begin ... else ... end`,
+//! `loop ... while ... again`, `:macro`, `:calc`, `:alias`, `:const`, `:org`, `:byte`, `:call`
+//! and bare numbers as sprite or data bytes.
+
+use assembler::{AssembleError, Assembly};
+use std::collections::{BTreeMap, HashMap};
+use Instruction;
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// What a forward reference to a label patches once the label is known
+#[derive(Copy, Clone, Debug)]
+enum Patch {
+    /// The low 12 bits of the word at the position
+    Address,
+    /// The 16 bit word at the position
+    Long,
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    position: usize,
+    line: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: BTreeMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    patches: Vec<(usize, String, Patch, usize)>,
+    /// Positions of jumps that need the address after the end of the block
+    blocks: Vec<Block>,
+    lines: Vec<(u16, usize)>,
+}
+
+enum Block {
+    If { jump: usize },
+    Else { jump: usize },
+    Loop { start: u16, breaks: Vec<usize> },
+}
+
+const ORIGIN: usize = 0x200;
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let text = match text.find('#') {
+            Some(index) => &text[..index],
+            None => text,
+        };
+        for word in text.split_whitespace() {
+            tokens.push(Token {
+                text: word.to_string(),
+                line: index + 1,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, text) = if text.starts_with('-') {
+        (true, &text[1..])
+    } else {
+        (false, text)
+    };
+    let value = if text.starts_with("0x") {
+        i64::from_str_radix(&text[2..], 16).ok().map(|value| value as f64)
+    } else if text.starts_with("0b") {
+        i64::from_str_radix(&text[2..], 2).ok().map(|value| value as f64)
+    } else if text.chars().next().map_or(false, |c| c.is_digit(10)) {
+        text.parse().ok()
+    } else {
+        None
+    };
+    value.map(|value| if negative { -value } else { value })
+}
+
+fn binary_operator(operator: &str, left: f64, right: f64) -> Option<f64> {
+    let (a, b) = (left as i64, right as i64);
+    let value = match operator {
+        "+" => left + right,
+        "-" => left - right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => (a % b) as f64,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => (a << b) as f64,
+        ">>" => (a >> b) as f64,
+        "<" => (left < right) as i64 as f64,
+        ">" => (left > right) as i64 as f64,
+        "<=" => (left <= right) as i64 as f64,
+        ">=" => (left >= right) as i64 as f64,
+        "==" => (left == right) as i64 as f64,
+        "!=" => (left != right) as i64 as f64,
+        "min" => left.min(right),
+        "max" => left.max(right),
+        _ => return None,
+    };
+    Some(value)
+}
+
+impl Compiler {
+    fn error<T>(&self, message: String) -> Result<T, AssembleError> {
+        Err(AssembleError {
+            line: self.line,
+            message: message,
+        })
+    }
+    fn next(&mut self) -> Result<String, AssembleError> {
+        match self.tokens.get(self.position).cloned() {
+            Some(token) => {
+                self.position += 1;
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error("unexpected end of the program".to_string()),
+        }
+    }
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| &token.text[..])
+    }
+    fn expect(&mut self, expected: &str) -> Result<(), AssembleError> {
+        let token = try!(self.next());
+        if token == expected {
+            Ok(())
+        } else {
+            self.error(format!("expected {} but found {}", expected, token))
+        }
+    }
+    fn register_of(&self, token: &str) -> Option<u8> {
+        if let Some(register) = self.aliases.get(token) {
+            return Some(*register);
+        }
+        let lower = token.to_lowercase();
+        if lower.len() == 2 && lower.starts_with('v') {
+            u8::from_str_radix(&lower[1..], 16).ok()
+        } else {
+            None
+        }
+    }
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = try!(self.next());
+        match self.register_of(&token) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register but found {}", token)),
+        }
+    }
+    fn constant_of(&self, token: &str) -> Option<f64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).cloned())
+            .or_else(|| self.labels.get(token).map(|address| *address as f64))
+    }
+    /// Reads a number, constant or defined label
+    fn value(&mut self, max: f64) -> Result<u16, AssembleError> {
+        let token = try!(self.next());
+        match self.constant_of(&token) {
+            Some(value) if value >= -128.0 && value < 0.0 && max == 255.0 => {
+                Ok((value as i64 & 0xFF) as u16)
+            }
+            Some(value) if value >= 0.0 && value <= max => Ok(value as u16),
+            Some(value) => self.error(format!("{} doesn't fit in {}", value, max)),
+            None => self.error(format!("undefined name {}", token)),
+        }
+    }
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        self.value(255.0).map(|value| value as u8)
+    }
+    /// Reads an address, recording a patch if it names a label that isn't defined yet
+    fn address(&mut self, patch: Patch, offset: usize) -> Result<u16, AssembleError> {
+        let token = try!(self.next());
+        let max = match patch {
+            Patch::Address => 0xFFF,
+            Patch::Long => 0xFFFF,
+        };
+        match self.constant_of(&token) {
+            Some(value) if value >= 0.0 && value <= max as f64 => Ok(value as u16),
+            Some(value) => self.error(format!("{} isn't a valid address", value)),
+            None if self.register_of(&token).is_none() => {
+                let line = self.line;
+                self.patches.push((self.here + offset, token, patch, line));
+                Ok(0)
+            }
+            None => self.error(format!("expected an address but found {}", token)),
+        }
+    }
+    fn emit_byte(&mut self, byte: u8) -> Result<(), AssembleError> {
+        let index = self.here - ORIGIN;
+        if self.here >= 0x10000 {
+            return self.error("the program doesn't fit in memory".to_string());
+        }
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+        self.here += 1;
+        Ok(())
+    }
+    fn emit(&mut self, instruction: Instruction) -> Result<(), AssembleError> {
+        self.lines.push((self.here as u16, self.line));
+        for byte in instruction.to_bytes() {
+            try!(self.emit_byte(byte));
+        }
+        Ok(())
+    }
+    fn patch_word(&mut self, position: usize, address: u16) {
+        let index = position - ORIGIN;
+        self.rom[index] = self.rom[index] & 0xF0 | (address >> 8) as u8 & 0xF;
+        self.rom[index + 1] = address as u8;
+    }
+    /// Emits a skip that skips the next instruction unless the condition holds
+    ///
+    /// With negate the next instruction is skipped when the condition holds instead.
+    fn condition(&mut self, negate: bool) -> Result<(), AssembleError> {
+        let x = try!(self.register());
+        let operator = try!(self.next());
+        match (&operator[..], negate) {
+            ("key", false) | ("-key", true) => return self.emit(Instruction::Sknp(x)),
+            ("key", true) | ("-key", false) => return self.emit(Instruction::Skp(x)),
+            _ => {}
+        }
+        let operand = try!(self.next());
+        let y = self.register_of(&operand);
+        let comparison = match &operator[..] {
+            "==" | "!=" => None,
+            "<" | ">" | "<=" | ">=" => Some(operator.clone()),
+            _ => return self.error(format!("unknown comparison {}", operator)),
+        };
+        if let Some(comparison) = comparison {
+            // Octo compares with a subtraction into vf, leaving the borrow flag
+            match y {
+                Some(y) => try!(self.emit(Instruction::LdReg { x: 0xF, y: y })),
+                None => {
+                    self.position -= 1;
+                    let byte = try!(self.byte());
+                    try!(self.emit(Instruction::LdByte { x: 0xF, byte: byte }));
+                }
+            }
+            let (subtract, true_when_flag) = match &comparison[..] {
+                ">" => (Instruction::Sub { x: 0xF, y: x }, 0),
+                "<=" => (Instruction::Sub { x: 0xF, y: x }, 1),
+                "<" => (Instruction::Subn { x: 0xF, y: x }, 0),
+                _ => (Instruction::Subn { x: 0xF, y: x }, 1),
+            };
+            try!(self.emit(subtract));
+            return self.emit(if (true_when_flag == 0) != negate {
+                Instruction::SneByte { x: 0xF, byte: 0 }
+            } else {
+                Instruction::SeByte { x: 0xF, byte: 0 }
+            });
+        }
+        let equal = (operator == "==") != negate;
+        let instruction = match (y, equal) {
+            (Some(y), true) => Instruction::SneReg { x: x, y: y },
+            (Some(y), false) => Instruction::SeReg { x: x, y: y },
+            (None, _) => {
+                self.position -= 1;
+                let byte = try!(self.byte());
+                if equal {
+                    Instruction::SneByte { x: x, byte: byte }
+                } else {
+                    Instruction::SeByte { x: x, byte: byte }
+                }
+            }
+        };
+        self.emit(instruction)
+    }
+    fn register_statement(&mut self, x: u8) -> Result<(), AssembleError> {
+        let operator = try!(self.next());
+        let operand = try!(self.next());
+        let y = self.register_of(&operand);
+        let instruction = match (&operator[..], y) {
+            (":=", Some(y)) => Instruction::LdReg { x: x, y: y },
+            (":=", None) => {
+                match &operand[..] {
+                    "random" => Instruction::Rnd { x: x, byte: try!(self.byte()) },
+                    "delay" => Instruction::LdVxDt(x),
+                    "key" => Instruction::LdVxK(x),
+                    _ => {
+                        self.position -= 1;
+                        Instruction::LdByte { x: x, byte: try!(self.byte()) }
+                    }
+                }
+            }
+            ("+=", Some(y)) => Instruction::Add { x: x, y: y },
+            ("+=", None) => {
+                self.position -= 1;
+                Instruction::AddByte { x: x, byte: try!(self.byte()) }
+            }
+            ("-=", Some(y)) => Instruction::Sub { x: x, y: y },
+            ("-=", None) => {
+                self.position -= 1;
+                let byte = try!(self.byte());
+                Instruction::AddByte { x: x, byte: byte.wrapping_neg() }
+            }
+            ("=-", Some(y)) => Instruction::Subn { x: x, y: y },
+            ("|=", Some(y)) => Instruction::Or { x: x, y: y },
+            ("&=", Some(y)) => Instruction::And { x: x, y: y },
+            ("^=", Some(y)) => Instruction::Xor { x: x, y: y },
+            (">>=", Some(y)) => Instruction::Shr { x: x, y: y },
+            ("<<=", Some(y)) => Instruction::Shl { x: x, y: y },
+            _ => return self.error(format!("can't use {} with {}", operator, operand)),
+        };
+        self.emit(instruction)
+    }
+    fn i_statement(&mut self) -> Result<(), AssembleError> {
+        let operator = try!(self.next());
+        match &operator[..] {
+            ":=" => {}
+            "+=" => {
+                let x = try!(self.register());
+                return self.emit(Instruction::AddI(x));
+            }
+            _ => return self.error(format!("can't use {} with i", operator)),
+        }
+        match self.peek() {
+            Some("hex") => {
+                try!(self.next());
+                let x = try!(self.register());
+                self.emit(Instruction::LdF(x))
+            }
+            Some("bighex") => {
+                try!(self.next());
+                let x = try!(self.register());
+                self.emit(Instruction::LdHf(x))
+            }
+            Some("long") => {
+                try!(self.next());
+                let address = try!(self.address(Patch::Long, 2));
+                self.emit(Instruction::LdLongI(address))
+            }
+            _ => {
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::LdI(address))
+            }
+        }
+    }
+    /// Reads `{ expression }` and evaluates it Octo style, right to left without precedence
+    fn calc(&mut self) -> Result<f64, AssembleError> {
+        try!(self.expect("{"));
+        let mut expression = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = try!(self.next());
+            match &token[..] {
+                "}" if depth == 0 => break,
+                "(" => depth += 1,
+                ")" => depth -= 1,
+                _ => {}
+            }
+            expression.push(token);
+        }
+        let mut position = 0;
+        self.evaluate(&expression, &mut position)
+    }
+    fn evaluate(&self, expression: &[String], position: &mut usize) -> Result<f64, AssembleError> {
+        let left = try!(self.term(expression, position));
+        match expression.get(*position) {
+            None => Ok(left),
+            Some(operator) if operator == ")" => Ok(left),
+            Some(operator) => {
+                *position += 1;
+                let right = try!(self.evaluate(expression, position));
+                match binary_operator(operator, left, right) {
+                    Some(value) => Ok(value),
+                    None => self.error(format!("unknown operator {}", operator)),
+                }
+            }
+        }
+    }
+    fn term(&self, expression: &[String], position: &mut usize) -> Result<f64, AssembleError> {
+        let token = match expression.get(*position) {
+            Some(token) => token.clone(),
+            None => return self.error("incomplete expression".to_string()),
+        };
+        *position += 1;
+        match &token[..] {
+            "(" => {
+                let value = try!(self.evaluate(expression, position));
+                *position += 1;
+                Ok(value)
+            }
+            "-" => self.term(expression, position).map(|value| -value),
+            "~" => self.term(expression, position).map(|value| !(value as i64) as f64),
+            "!" => self.term(expression, position).map(|value| (value == 0.0) as i64 as f64),
+            "HERE" => Ok(self.here as f64),
+            _ => {
+                match self.constant_of(&token) {
+                    Some(value) => Ok(value),
+                    None => self.error(format!("undefined name {}", token)),
+                }
+            }
+        }
+    }
+    fn define_label(&mut self, name: String) -> Result<(), AssembleError> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return self.error(format!("{} is already defined", name));
+        }
+        self.labels.insert(name, self.here as u16);
+        Ok(())
+    }
+    fn define_constant(&mut self, name: String, value: f64) -> Result<(), AssembleError> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return self.error(format!("{} is already defined", name));
+        }
+        self.constants.insert(name, value);
+        Ok(())
+    }
+    fn expand_macro(&mut self, name: &str) -> Result<(), AssembleError> {
+        let (arguments, body) = {
+            let definition = &self.macros[name];
+            (definition.arguments.clone(), definition.body.clone())
+        };
+        let mut values = HashMap::new();
+        for argument in arguments {
+            values.insert(argument, try!(self.next()));
+        }
+        let expansion: Vec<Token> = body.into_iter()
+            .map(|token| {
+                Token {
+                    text: values.get(&token.text).cloned().unwrap_or(token.text),
+                    line: self.line,
+                }
+            })
+            .collect();
+        let rest = self.tokens.split_off(self.position);
+        self.tokens.extend(expansion);
+        self.tokens.extend(rest);
+        Ok(())
+    }
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        let token = try!(self.next());
+        if let Some(x) = self.register_of(&token) {
+            return self.register_statement(x);
+        }
+        match &token[..] {
+            ":" => {
+                let name = try!(self.next());
+                self.define_label(name)
+            }
+            ":const" => {
+                let name = try!(self.next());
+                let value = try!(self.next());
+                match self.constant_of(&value) {
+                    Some(value) => self.define_constant(name, value),
+                    None => self.error(format!("undefined name {}", value)),
+                }
+            }
+            ":calc" => {
+                let name = try!(self.next());
+                let value = try!(self.calc());
+                self.define_constant(name, value)
+            }
+            ":alias" => {
+                let name = try!(self.next());
+                let register = try!(self.register());
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":macro" => {
+                let name = try!(self.next());
+                let mut arguments = Vec::new();
+                loop {
+                    let argument = try!(self.next());
+                    if argument == "{" {
+                        break;
+                    }
+                    arguments.push(argument);
+                }
+                let mut body = Vec::new();
+                let mut depth = 0;
+                loop {
+                    let line = self.line;
+                    let text = try!(self.next());
+                    match &text[..] {
+                        "}" if depth == 0 => break,
+                        "{" => depth += 1,
+                        "}" => depth -= 1,
+                        _ => {}
+                    }
+                    body.push(Token {
+                        text: text,
+                        line: line,
+                    });
+                }
+                self.macros.insert(name,
+                                   Macro {
+                                       arguments: arguments,
+                                       body: body,
+                                   });
+                Ok(())
+            }
+            ":org" => {
+                let address = try!(self.value(0xFFFF as f64)) as usize;
+                if address < ORIGIN {
+                    return self.error(format!("can't place code below 0x{:X}", ORIGIN));
+                }
+                self.here = address;
+                Ok(())
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    (try!(self.calc()) as i64 & 0xFF) as u8
+                } else {
+                    try!(self.byte())
+                };
+                self.emit_byte(byte)
+            }
+            ":call" => {
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::Call(address))
+            }
+            "i" => self.i_statement(),
+            "delay" | "buzzer" | "pitch" => {
+                try!(self.expect(":="));
+                let x = try!(self.register());
+                self.emit(match &token[..] {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                })
+            }
+            "clear" => self.emit(Instruction::Cls),
+            "return" | ";" => self.emit(Instruction::Ret),
+            "hires" => self.emit(Instruction::Hires),
+            "lores" => self.emit(Instruction::Lores),
+            "exit" => self.emit(Instruction::Exit),
+            "scroll-left" => self.emit(Instruction::ScrollLeft),
+            "scroll-right" => self.emit(Instruction::ScrollRight),
+            "audio" => self.emit(Instruction::Audio),
+            "scroll-down" => {
+                let n = try!(self.value(15.0)) as u8;
+                self.emit(Instruction::ScrollDown(n))
+            }
+            "scroll-up" => {
+                let n = try!(self.value(15.0)) as u8;
+                self.emit(Instruction::ScrollUp(n))
+            }
+            "plane" => {
+                let n = try!(self.value(3.0)) as u8;
+                self.emit(Instruction::Plane(n))
+            }
+            "sprite" => {
+                let x = try!(self.register());
+                let y = try!(self.register());
+                let n = try!(self.value(15.0)) as u8;
+                self.emit(Instruction::Drw { x: x, y: y, n: n })
+            }
+            "bcd" => {
+                let x = try!(self.register());
+                self.emit(Instruction::LdB(x))
+            }
+            "save" | "load" => {
+                let x = try!(self.register());
+                if self.peek() == Some("-") {
+                    try!(self.next());
+                    let y = try!(self.register());
+                    return self.emit(if token == "save" {
+                        Instruction::SaveRange { x: x, y: y }
+                    } else {
+                        Instruction::LoadRange { x: x, y: y }
+                    });
+                }
+                self.emit(if token == "save" {
+                    Instruction::LdIVx(x)
+                } else {
+                    Instruction::LdVxI(x)
+                })
+            }
+            "saveflags" => {
+                let x = try!(self.register());
+                self.emit(Instruction::LdRVx(x))
+            }
+            "loadflags" => {
+                let x = try!(self.register());
+                self.emit(Instruction::LdVxR(x))
+            }
+            "jump" => {
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::Jp(address))
+            }
+            "jump0" => {
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::JpV0(address))
+            }
+            "native" => {
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::Sys(address))
+            }
+            "if" => {
+                // Look ahead to see if the condition guards one statement or a block
+                let start = self.position;
+                let mut end = start;
+                while end < self.tokens.len() && self.tokens[end].text != "then" &&
+                      self.tokens[end].text != "begin" {
+                    end += 1;
+                }
+                let begin = self.tokens.get(end).map_or(false, |token| token.text == "begin");
+                try!(self.condition(begin));
+                let then = try!(self.next());
+                match &then[..] {
+                    "then" => Ok(()),
+                    "begin" => {
+                        // The skip above jumps over this jump into the block
+                        self.blocks.push(Block::If { jump: self.here });
+                        self.emit(Instruction::Jp(0))
+                    }
+                    _ => self.error(format!("expected then or begin but found {}", then)),
+                }
+            }
+            "else" => {
+                match self.blocks.pop() {
+                    Some(Block::If { jump }) => {
+                        self.blocks.push(Block::Else { jump: self.here });
+                        try!(self.emit(Instruction::Jp(0)));
+                        let here = self.here as u16;
+                        self.patch_word(jump, here);
+                        Ok(())
+                    }
+                    _ => self.error("else without if ... begin".to_string()),
+                }
+            }
+            "end" => {
+                match self.blocks.pop() {
+                    Some(Block::If { jump }) |
+                    Some(Block::Else { jump }) => {
+                        let here = self.here as u16;
+                        self.patch_word(jump, here);
+                        Ok(())
+                    }
+                    _ => self.error("end without if ... begin".to_string()),
+                }
+            }
+            "loop" => {
+                self.blocks.push(Block::Loop {
+                    start: self.here as u16,
+                    breaks: Vec::new(),
+                });
+                Ok(())
+            }
+            "while" => {
+                // The jump out of the loop runs when the condition fails
+                try!(self.condition(true));
+                let here = self.here;
+                match self.blocks.iter_mut().rev().find(|block| match **block {
+                    Block::Loop { .. } => true,
+                    _ => false,
+                }) {
+                    Some(&mut Block::Loop { ref mut breaks, .. }) => breaks.push(here),
+                    _ => return self.error("while outside of a loop".to_string()),
+                }
+                self.emit(Instruction::Jp(0))
+            }
+            "again" => {
+                match self.blocks.pop() {
+                    Some(Block::Loop { start, breaks }) => {
+                        try!(self.emit(Instruction::Jp(start)));
+                        let here = self.here as u16;
+                        for position in breaks {
+                            self.patch_word(position, here);
+                        }
+                        Ok(())
+                    }
+                    _ => self.error("again without loop".to_string()),
+                }
+            }
+            _ if self.macros.contains_key(&token) => self.expand_macro(&token),
+            _ => {
+                if let Some(value) = parse_number(&token).or_else(|| self.constants.get(&token).cloned()) {
+                    if value < -128.0 || value > 255.0 {
+                        return self.error(format!("{} doesn't fit in a byte", value));
+                    }
+                    return self.emit_byte(value as i64 as u8);
+                }
+                // Anything else is a call to a label
+                self.position -= 1;
+                let address = try!(self.address(Patch::Address, 0));
+                self.emit(Instruction::Call(address))
+            }
+        }
+    }
+}
+
+/// Compiles Octo source into a program loaded at 0x200
+pub fn compile(source: &str) -> Result<Assembly, AssembleError> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source),
+        position: 0,
+        line: 1,
+        rom: Vec::new(),
+        here: ORIGIN,
+        labels: BTreeMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        patches: Vec::new(),
+        blocks: Vec::new(),
+        lines: Vec::new(),
+    };
+    while compiler.position < compiler.tokens.len() {
+        try!(compiler.statement());
+    }
+    if !compiler.blocks.is_empty() {
+        return compiler.error("a block is missing its end or again".to_string());
+    }
+    for (position, name, patch, line) in compiler.patches.clone() {
+        let address = match compiler.labels.get(&name) {
+            Some(address) => *address,
+            None => {
+                compiler.line = line;
+                return compiler.error(format!("undefined name {}", name));
+            }
+        };
+        match patch {
+            Patch::Address => {
+                if address > 0xFFF {
+                    compiler.line = line;
+                    return compiler.error(format!("{} is out of reach of 12 bit addresses", name));
+                }
+                compiler.patch_word(position, address);
+            }
+            Patch::Long => {
+                let index = position - ORIGIN;
+                compiler.rom[index] = (address >> 8) as u8;
+                compiler.rom[index + 1] = address as u8;
+            }
+        }
+    }
+    Ok(Assembly {
+        origin: ORIGIN as u16,
+        binary: compiler.rom,
+        labels: compiler.labels,
+        lines: compiler.lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+
+    fn binary(source: &str) -> Vec<u8> {
+        compile(source).unwrap().binary
+    }
+
+    #[test]
+    fn macro_expands_arguments() {
+        assert_eq!(binary(":macro set reg value { reg := value }\nset v3 0x12\nset v4 7"),
+                   vec![0x63, 0x12, 0x64, 0x07]);
+    }
+
+    #[test]
+    fn calc_and_const() {
+        assert_eq!(binary(":const width 8\n:calc half { width / 2 }\nv0 := half\nv1 := width"),
+                   vec![0x60, 0x04, 0x61, 0x08]);
+    }
+
+    #[test]
+    fn loop_jumps_back() {
+        assert_eq!(binary("clear\nloop\n  v0 += 1\nagain"),
+                   vec![0x00, 0xE0, 0x70, 0x01, 0x12, 0x02]);
+    }
+
+    #[test]
+    fn if_then_skips() {
+        // The condition is inverted into the skip that jumps over the statement
+        assert_eq!(binary("if v1 == 5 then v2 := 1"), vec![0x41, 0x05, 0x62, 0x01]);
+        assert_eq!(binary("if v1 != v2 then v2 := 1"), vec![0x51, 0x20, 0x62, 0x01]);
+    }
+
+    #[test]
+    fn redefinitions_fail() {
+        assert!(compile(":const a 1\n:const a 2").is_err());
+        assert!(compile(":const a 1\n:calc a { 2 }").is_err());
+        assert!(compile(": main\n:calc main { 1 }").is_err());
+    }
+}
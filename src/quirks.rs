@@ -0,0 +1,290 @@
+/// How FX55 and FX65 leave the address register once they are done
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LoadStoreQuirk {
+    /// I is left untouched (SUPER-CHIP 1.1)
+    Unchanged,
+    /// I is increased by X (CHIP-48)
+    IncrementX,
+    /// I is increased by X + 1 (COSMAC VIP)
+    IncrementXPlusOne,
+}
+
+impl LoadStoreQuirk {
+    /// How much I moves after loading or storing V0 through VX
+    pub fn increment(&self, x: u8) -> u16 {
+        match *self {
+            LoadStoreQuirk::Unchanged => 0,
+            LoadStoreQuirk::IncrementX => x as u16,
+            LoadStoreQuirk::IncrementXPlusOne => x as u16 + 1,
+        }
+    }
+}
+
+/// The interpretation of the optcodes that differ between chip8 interpreters
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    /// What FX55 and FX65 do to I
+    pub load_store: LoadStoreQuirk,
+    /// BNNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// 8XY1, 8XY2 and 8XY3 set VF to 0
+    pub logic_resets_vf: bool,
+    /// Sprites going off the left or right edge come back on the other side instead of being cut off
+    pub wrap_x: bool,
+    /// Sprites going off the top or bottom edge come back on the other side instead of being cut off
+    pub wrap_y: bool,
+    /// DXYN starts drawing at VY modulo the screen height instead of drawing nothing when VY is
+    /// past the bottom, as every interpreter does
+    pub wrap_start_y: bool,
+    /// How many calls can be nested before 2NNN overflows the stack
+    pub stack_depth: usize,
+    /// Return addresses are also stored in memory, downwards from 0xECF like the VIP interpreter
+    /// does, and 00EE returns to whatever is there
+    pub vip_stack: bool,
+    /// DXYN waits for the display interrupt, so the rest of the frame goes by without running
+    /// anything
+    pub display_wait: bool,
+    /// 0NNN runs the CDP1802 machine code at NNN, as on the VIP
+    pub machine_code: bool,
+    /// FX0A finishes when the key pressed comes back up instead of as soon as it goes down
+    pub key_release: bool,
+}
+
+impl Quirks {
+    /// The original interpreter of the COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: LoadStoreQuirk::IncrementXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            wrap_x: false,
+            wrap_y: false,
+            wrap_start_y: true,
+            stack_depth: 12,
+            vip_stack: true,
+            display_wait: true,
+            machine_code: true,
+            key_release: true,
+        }
+    }
+    /// CHIP-48 for the HP48
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::IncrementX,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            wrap_x: false,
+            wrap_y: false,
+            wrap_start_y: true,
+            stack_depth: 16,
+            vip_stack: false,
+            display_wait: false,
+            machine_code: false,
+            key_release: false,
+        }
+    }
+    /// SUPER-CHIP 1.1 for the HP48
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::Unchanged,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            wrap_x: false,
+            wrap_y: false,
+            wrap_start_y: true,
+            stack_depth: 16,
+            vip_stack: false,
+            display_wait: false,
+            machine_code: false,
+            key_release: false,
+        }
+    }
+    /// XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store: LoadStoreQuirk::IncrementXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            wrap_x: true,
+            wrap_y: true,
+            wrap_start_y: true,
+            stack_depth: 16,
+            vip_stack: false,
+            display_wait: false,
+            machine_code: false,
+            key_release: true,
+        }
+    }
+    /// The quirks as name=value lines, for files that store them
+    pub fn to_text(&self) -> String {
+        let load_store = match self.load_store {
+            LoadStoreQuirk::Unchanged => 0,
+            LoadStoreQuirk::IncrementX => 1,
+            LoadStoreQuirk::IncrementXPlusOne => 2,
+        };
+        format!("shift_uses_vy={}\nload_store={}\njump_uses_vx={}\nlogic_resets_vf={}\nwrap_x={}\n\
+                 wrap_y={}\nwrap_start_y={}\nstack_depth={}\nvip_stack={}\ndisplay_wait={}\n\
+                 machine_code={}\nkey_release={}\n",
+                self.shift_uses_vy as u8,
+                load_store,
+                self.jump_uses_vx as u8,
+                self.logic_resets_vf as u8,
+                self.wrap_x as u8,
+                self.wrap_y as u8,
+                self.wrap_start_y as u8,
+                self.stack_depth,
+                self.vip_stack as u8,
+                self.display_wait as u8,
+                self.machine_code as u8,
+                self.key_release as u8)
+    }
+    /// Reads the lines to_text writes, leaving quirks that aren't mentioned at their defaults and
+    /// skipping ones it doesn't know
+    pub fn from_text(text: &str) -> Option<Quirks> {
+        let mut quirks = Quirks::default();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value: u32 = match parts.next().and_then(|value| value.parse().ok()) {
+                Some(value) => value,
+                None => return None,
+            };
+            match name {
+                "shift_uses_vy" => quirks.shift_uses_vy = value != 0,
+                "load_store" => {
+                    quirks.load_store = match value {
+                        0 => LoadStoreQuirk::Unchanged,
+                        1 => LoadStoreQuirk::IncrementX,
+                        2 => LoadStoreQuirk::IncrementXPlusOne,
+                        _ => return None,
+                    }
+                }
+                "jump_uses_vx" => quirks.jump_uses_vx = value != 0,
+                "logic_resets_vf" => quirks.logic_resets_vf = value != 0,
+                "wrap_x" => quirks.wrap_x = value != 0,
+                "wrap_y" => quirks.wrap_y = value != 0,
+                "wrap_start_y" => quirks.wrap_start_y = value != 0,
+                "stack_depth" => quirks.stack_depth = value as usize,
+                "vip_stack" => quirks.vip_stack = value != 0,
+                "display_wait" => quirks.display_wait = value != 0,
+                "machine_code" => quirks.machine_code = value != 0,
+                "key_release" => quirks.key_release = value != 0,
+                _ => {}
+            }
+        }
+        Some(quirks)
+    }
+}
+
+/// The behaviour this crate has always had
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store: LoadStoreQuirk::Unchanged,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            wrap_x: true,
+            wrap_y: false,
+            wrap_start_y: false,
+            stack_depth: 16,
+            vip_stack: false,
+            display_wait: false,
+            machine_code: false,
+            key_release: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Chip8State;
+    use testing::{machine, run};
+    use super::{LoadStoreQuirk, Quirks};
+
+    /// The state after running the steps of program with quirks
+    fn after(program: &[u8], quirks: Quirks, steps: usize) -> Chip8State {
+        let mut chip8 = machine(program, quirks);
+        run(&mut chip8, steps);
+        chip8.state.state().unwrap().clone()
+    }
+
+    #[test]
+    fn shift_source() {
+        // V1 := 5, V0 := 6, V0 >>= V1
+        let program = [0x61, 0x05, 0x60, 0x06, 0x80, 0x16];
+        let vy = after(&program, Quirks { shift_uses_vy: true, ..Quirks::default() }, 3);
+        assert_eq!((vy.data_registers[0], vy.data_registers[0xF]), (2, 1));
+        let vx = after(&program, Quirks::default(), 3);
+        assert_eq!((vx.data_registers[0], vx.data_registers[0xF]), (3, 0));
+    }
+
+    #[test]
+    fn load_store_increment() {
+        // I := 0x300, save v2
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+        let increments = [(LoadStoreQuirk::Unchanged, 0x300),
+                          (LoadStoreQuirk::IncrementX, 0x302),
+                          (LoadStoreQuirk::IncrementXPlusOne, 0x303)];
+        for &(load_store, address) in &increments {
+            let state = after(&program, Quirks { load_store: load_store, ..Quirks::default() }, 2);
+            assert_eq!(state.address_register, address);
+        }
+    }
+
+    #[test]
+    fn jump_register() {
+        // V0 := 2, V1 := 0x10, jump0 0x120
+        let program = [0x60, 0x02, 0x61, 0x10, 0xB1, 0x20];
+        assert_eq!(after(&program, Quirks::default(), 3).program_counter, 0x122);
+        let vx = after(&program, Quirks { jump_uses_vx: true, ..Quirks::default() }, 3);
+        assert_eq!(vx.program_counter, 0x130);
+    }
+
+    #[test]
+    fn logic_resets_vf() {
+        // VF := 5, V0 := 1, V1 := 3, V0 |= V1
+        let program = [0x6F, 0x05, 0x60, 0x01, 0x61, 0x03, 0x80, 0x11];
+        assert_eq!(after(&program, Quirks::default(), 4).data_registers[0xF], 5);
+        let reset = after(&program, Quirks { logic_resets_vf: true, ..Quirks::default() }, 4);
+        assert_eq!(reset.data_registers[0xF], 0);
+    }
+
+    /// Draws the font's 0 at (x, y), whose top row is four pixels and whose others start with one
+    fn draw_zero(x: u8, y: u8, quirks: Quirks) -> Chip8State {
+        after(&[0x60, x, 0x61, y, 0xA0, 0x00, 0xD0, 0x15], quirks, 4)
+    }
+
+    #[test]
+    fn wrap_x() {
+        assert!(draw_zero(62, 0, Quirks::default()).pixel(0, 0, 0));
+        assert!(!draw_zero(62, 0, Quirks { wrap_x: false, ..Quirks::default() }).pixel(0, 0, 0));
+    }
+
+    #[test]
+    fn wrap_y() {
+        assert!(!draw_zero(0, 30, Quirks::default()).pixel(0, 0, 0));
+        assert!(draw_zero(0, 30, Quirks { wrap_y: true, ..Quirks::default() }).pixel(0, 0, 0));
+    }
+
+    #[test]
+    fn wrap_start_y() {
+        assert_eq!(draw_zero(0, 34, Quirks::default()).frame_iter().count(), 0);
+        let wrapped = draw_zero(0, 34, Quirks { wrap_start_y: true, ..Quirks::default() });
+        assert!(wrapped.pixel(0, 0, 2));
+    }
+
+    #[test]
+    fn text_round_trip() {
+        for quirks in &[Quirks::default(), Quirks::cosmac_vip(), Quirks::chip48(), Quirks::schip(),
+                        Quirks::xo_chip()] {
+            assert_eq!(Quirks::from_text(&quirks.to_text()), Some(*quirks));
+        }
+    }
+}
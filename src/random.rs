@@ -0,0 +1,108 @@
+//! Random number generators for CXNN
+
+use Chip8State;
+
+/// Where CXNN gets its random bytes from
+pub trait RandomSource {
+    /// The next random byte, before it is masked with NN
+    fn next_byte(&mut self, state: &Chip8State) -> u8;
+    /// The generator's state, for a save state
+    fn save(&self) -> Vec<u8>;
+    /// Puts the generator back into a saved state, returning false if it was saved by a
+    /// different kind of generator
+    fn restore(&mut self, saved: &[u8]) -> bool;
+    fn box_clone(&self) -> Box<RandomSource>;
+}
+
+/// A seedable xorshift64* generator, the default
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // Spread the seed out with a splitmix64 step so small seeds don't start out weak
+        let mut state = seed.wrapping_add(0x9E3779B97F4A7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D049BB133111EB);
+        state ^= state >> 31;
+        XorShift {
+            // The state must never be zero
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+}
+
+impl RandomSource for XorShift {
+    fn next_byte(&mut self, _state: &Chip8State) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+    }
+    fn save(&self) -> Vec<u8> {
+        let mut saved = vec![b'X'];
+        saved.extend((0..8).map(|byte| (self.state >> (byte * 8)) as u8));
+        saved
+    }
+    fn restore(&mut self, saved: &[u8]) -> bool {
+        if saved.len() != 9 || saved[0] != b'X' {
+            return false;
+        }
+        let state = saved[1..].iter().rev().fold(0, |state, &byte| state << 8 | byte as u64);
+        if state == 0 {
+            return false;
+        }
+        self.state = state;
+        true
+    }
+    fn box_clone(&self) -> Box<RandomSource> {
+        Box::new(*self)
+    }
+}
+
+/// The generator of the COSMAC VIP interpreter
+///
+/// The interpreter keeps a 16 bit seed in R9. For every CXNN it increments the low byte, reads the
+/// byte of its own code at 0x100 plus the low byte, adds the high byte to it and keeps the sum as
+/// the new high byte, which is also the random number. The interpreter isn't loaded into the
+/// machine's memory, so its second page has to be given.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VipRandom {
+    seed: u16,
+    /// The second page of the interpreter
+    table: Vec<u8>,
+}
+
+impl VipRandom {
+    /// A generator using a copy of the interpreter's 256 byte second page
+    pub fn with_table(seed: u16, table: &[u8; 256]) -> VipRandom {
+        VipRandom {
+            seed: seed,
+            table: table.to_vec(),
+        }
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self, _state: &Chip8State) -> u8 {
+        let low = (self.seed as u8).wrapping_add(1);
+        let high = self.table[low as usize].wrapping_add((self.seed >> 8) as u8);
+        self.seed = (high as u16) << 8 | low as u16;
+        high
+    }
+    fn save(&self) -> Vec<u8> {
+        vec![b'V', self.seed as u8, (self.seed >> 8) as u8]
+    }
+    fn restore(&mut self, saved: &[u8]) -> bool {
+        if saved.len() != 3 || saved[0] != b'V' {
+            return false;
+        }
+        self.seed = saved[1] as u16 | (saved[2] as u16) << 8;
+        true
+    }
+    fn box_clone(&self) -> Box<RandomSource> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,340 @@
+//! A history of machine states for stepping backwards
+//!
+//! Snapshots are grouped behind a keyframe holding the whole memory. The other snapshots of a
+//! group only hold the runs of memory that differ from their keyframe, since most of it doesn't
+//! change from frame to frame.
+
+use AudioWrapper;
+use Chip8;
+use Chip8Err;
+use Chip8State;
+use KeyWrapper;
+use RunState;
+use SeriableMemory;
+use idle::LoopWatch;
+use std::collections::VecDeque;
+use std::mem;
+
+/// Differing bytes closer together than this are stored as one run
+const RUN_GAP: usize = 8;
+
+struct Snapshot {
+    /// The state without its memory
+    state: Chip8State,
+    /// The runs of memory that differ from the keyframe, as (address, bytes)
+    changes: Vec<(usize, Vec<u8>)>,
+    rng: Vec<u8>,
+    /// The register FX0A was waiting to put a key in and the key it was waiting to come up
+    waiting: Option<(u8, Option<u8>)>,
+    /// Whether the machine was going round an idle loop
+    idle: bool,
+    idle_loop: LoopWatch,
+    history: VecDeque<u16>,
+    cycles: u64,
+    frames: u64,
+    frame_cycle: u32,
+    cycle_nanos: u64,
+    overrun: u64,
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        let changes = self.changes
+            .iter()
+            .map(|&(_, ref bytes)| mem::size_of::<(usize, Vec<u8>)>() + bytes.len())
+            .sum::<usize>();
+        mem::size_of::<Snapshot>() + self.state.stack.len() * 2 + self.rng.len() + self.history.len() * 2 +
+        changes
+    }
+}
+
+struct Group {
+    keyframe: Vec<u8>,
+    snapshots: Vec<Snapshot>,
+}
+
+/// The runs of current that differ from keyframe
+fn changes(keyframe: &[u8], current: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut changes: Vec<(usize, Vec<u8>)> = Vec::new();
+    for (address, (&old, &new)) in keyframe.iter().zip(current.iter()).enumerate() {
+        if old == new {
+            continue;
+        }
+        let extends = changes.last()
+            .map_or(false, |&(start, ref bytes)| address - (start + bytes.len()) < RUN_GAP);
+        if extends {
+            let run = changes.last_mut().unwrap();
+            let end = run.0 + run.1.len();
+            run.1.extend_from_slice(&current[end..address + 1]);
+        } else {
+            changes.push((address, vec![new]));
+        }
+    }
+    changes
+}
+
+/// A ring buffer of snapshots with bounded memory use
+///
+/// A frontend calls record once a frame. Stepping back restores an earlier snapshot and forgets
+/// every newer one. Stepping back by instructions runs forward from the snapshot before the
+/// target, so it depends on the keys being the same as the first time.
+pub struct Rewind {
+    /// Old snapshots are dropped once the history takes more bytes than this
+    pub max_bytes: usize,
+    /// How many snapshots share a keyframe
+    pub keyframe_interval: usize,
+    groups: VecDeque<Group>,
+    bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(max_bytes: usize) -> Rewind {
+        Rewind {
+            max_bytes: max_bytes,
+            keyframe_interval: 60,
+            groups: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+    /// The number of snapshots held
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| group.snapshots.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+    /// The bytes the history takes, roughly
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.bytes = 0;
+    }
+    /// Takes a snapshot of a running machine
+    pub fn record<T: KeyWrapper, A: AudioWrapper>(&mut self, chip8: &Chip8<T, A>) {
+        let state = match chip8.state.running() {
+            Some(state) => state,
+            None => return,
+        };
+        let new_group = self.groups.back().map_or(true, |group| {
+            group.snapshots.len() >= self.keyframe_interval || group.keyframe.len() != state.memory.len()
+        });
+        if new_group {
+            self.bytes += state.memory.len();
+            self.groups.push_back(Group {
+                keyframe: state.memory.0.clone(),
+                snapshots: Vec::new(),
+            });
+        }
+        let group = self.groups.back_mut().unwrap();
+        let snapshot = Snapshot {
+            state: state.without_memory(),
+            changes: changes(&group.keyframe, &state.memory),
+            rng: chip8.rng.save(),
+            waiting: chip8.state.waiting(),
+            idle: match chip8.state {
+                RunState::Idle(_) => true,
+                _ => false,
+            },
+            idle_loop: chip8.idle_loop.clone(),
+            history: chip8.history.clone(),
+            cycles: chip8.cycles,
+            frames: chip8.frames,
+            frame_cycle: chip8.frame_cycle,
+            cycle_nanos: chip8.cycle_nanos,
+            overrun: chip8.overrun,
+        };
+        self.bytes += snapshot.size();
+        group.snapshots.push(snapshot);
+        while self.bytes > self.max_bytes && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            let snapshots = group.snapshots.iter().map(Snapshot::size).sum::<usize>();
+            self.bytes -= group.keyframe.len() + snapshots;
+        }
+    }
+    /// Restores the newest snapshot passing test and forgets every newer one
+    ///
+    /// Leaves the history alone and returns false if no snapshot passes.
+    fn restore<T, A, F>(&mut self, chip8: &mut Chip8<T, A>, test: F) -> bool
+        where T: KeyWrapper,
+              A: AudioWrapper,
+              F: Fn(&Snapshot) -> bool
+    {
+        let found = self.groups
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(index, group)| {
+                group.snapshots.iter().rposition(|snapshot| test(snapshot)).map(|snapshot| (index, snapshot))
+            })
+            .next();
+        let (group_index, snapshot_index) = match found {
+            Some(found) => found,
+            None => return false,
+        };
+        while self.groups.len() > group_index + 1 {
+            let group = self.groups.pop_back().unwrap();
+            let snapshots = group.snapshots.iter().map(Snapshot::size).sum::<usize>();
+            self.bytes -= group.keyframe.len() + snapshots;
+        }
+        let group = self.groups.back_mut().unwrap();
+        // The restored snapshot stays so it can be returned to again
+        for snapshot in group.snapshots.drain(snapshot_index + 1..) {
+            self.bytes -= snapshot.size();
+        }
+        let snapshot = &group.snapshots[snapshot_index];
+        let mut state = snapshot.state.without_memory();
+        state.memory = SeriableMemory(group.keyframe.clone());
+        for &(address, ref bytes) in &snapshot.changes {
+            state.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        if state.sound_timer > 0 {
+            chip8.audio_wrapper.play();
+        } else {
+            chip8.audio_wrapper.stop();
+        }
+        // Everything the machine carries from one instruction to the next goes back, so running
+        // forward again does exactly what it did the first time
+        chip8.state = RunState::resume(state, snapshot.waiting);
+        chip8.state.set_idle(snapshot.idle);
+        chip8.rng.restore(&snapshot.rng);
+        chip8.idle_loop = snapshot.idle_loop.clone();
+        chip8.history = snapshot.history.clone();
+        chip8.fault = None;
+        chip8.cycles = snapshot.cycles;
+        chip8.frames = snapshot.frames;
+        chip8.frame_cycle = snapshot.frame_cycle;
+        chip8.cycle_nanos = snapshot.cycle_nanos;
+        chip8.overrun = snapshot.overrun;
+        true
+    }
+    /// Goes back to the newest snapshot at least frames frames old
+    ///
+    /// Returns false without changing anything if the history doesn't reach back that far.
+    pub fn step_back_frames<T, A>(&mut self, chip8: &mut Chip8<T, A>, frames: u64) -> bool
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let target = match chip8.frames.checked_sub(frames) {
+            Some(target) => target,
+            None => return false,
+        };
+        self.restore(chip8, |snapshot| snapshot.frames <= target)
+    }
+    /// Goes back cycles instructions
+    ///
+    /// Returns Ok(false) without changing anything if the history doesn't reach back that far, or
+    /// the fault the machine ran into while running forward from a snapshot.
+    pub fn step_back_cycles<T, A>(&mut self,
+                                  chip8: &mut Chip8<T, A>,
+                                  cycles: u64)
+                                  -> Result<bool, Chip8Err>
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let target = match chip8.cycles.checked_sub(cycles) {
+            Some(target) => target,
+            None => return Ok(false),
+        };
+        if !self.restore(chip8, |snapshot| snapshot.cycles <= target) {
+            return Ok(false);
+        }
+        // Running forward again shouldn't add to a trace
+        let tracer = chip8.tracer.take();
+        let mut result = Ok(true);
+        while chip8.cycles < target {
+            if let Err(err) = chip8.step() {
+                result = Err(err);
+                break;
+            }
+        }
+        chip8.tracer = tracer;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use AudioWrapper;
+    use Chip8;
+    use KeyWrapper;
+    use Quirks;
+    use RunState;
+    use super::{Rewind, changes};
+    use testing::{machine, run};
+
+    /// Waits five frames on the delay timer in an idle loop, counts in V2 and starts again
+    const WAIT_LOOP: [u8; 14] = [0x60, 0x05, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x12, 0x04, 0x72, 0x01,
+                                 0x12, 0x00];
+
+    /// What running on from a machine depends on
+    fn fingerprint<T: KeyWrapper, A: AudioWrapper>(chip8: &Chip8<T, A>)
+                                                   -> ([u8; 16], u16, u8, u64, u64, u32, bool) {
+        let idle = match chip8.state {
+            RunState::Idle(_) => true,
+            _ => false,
+        };
+        (chip8.data_registers,
+         chip8.program_counter,
+         chip8.delay_timer,
+         chip8.cycles(),
+         chip8.frames(),
+         chip8.frame_cycle(),
+         idle)
+    }
+
+    #[test]
+    fn close_changes_merge() {
+        let keyframe = [0; 32];
+        let mut current = [0; 32];
+        current[2] = 1;
+        current[5] = 2;
+        current[20] = 3;
+        assert_eq!(changes(&keyframe, &current), vec![(2, vec![1, 0, 0, 2]), (20, vec![3])]);
+        assert_eq!(changes(&keyframe, &keyframe), vec![]);
+    }
+
+    #[test]
+    fn old_groups_are_dropped() {
+        let mut chip8 = machine(&WAIT_LOOP, Quirks::default());
+        let mut rewind = Rewind::new(3 * 0x1000);
+        rewind.keyframe_interval = 2;
+        for _ in 0..20 {
+            rewind.record(&chip8);
+            chip8.run_vblank().unwrap();
+        }
+        assert!(rewind.bytes() <= rewind.max_bytes);
+        assert!(rewind.len() < 20);
+        assert!(!rewind.step_back_frames(&mut chip8, 19));
+        assert!(rewind.step_back_frames(&mut chip8, 1));
+        assert_eq!(chip8.frames(), 19);
+    }
+
+    #[test]
+    fn step_back_runs_forward_the_same() {
+        let mut chip8 = machine(&WAIT_LOOP, Quirks::default());
+        chip8.skip_idle = true;
+        let mut rewind = Rewind::new(1 << 20);
+        // Recording mid-frame catches the machine in and out of its idle loop
+        for _ in 0..100 {
+            rewind.record(&chip8);
+            run(&mut chip8, 3);
+        }
+        for &back in &[1, 7, 40] {
+            let target = chip8.cycles() - back;
+            let mut reference = machine(&WAIT_LOOP, Quirks::default());
+            reference.skip_idle = true;
+            while reference.cycles() < target {
+                reference.step().unwrap();
+            }
+            assert_eq!(rewind.step_back_cycles(&mut chip8, back), Ok(true));
+            assert_eq!(fingerprint(&chip8), fingerprint(&reference));
+            for _ in 0..100 {
+                chip8.step().unwrap();
+                reference.step().unwrap();
+                assert_eq!(fingerprint(&chip8), fingerprint(&reference));
+            }
+        }
+    }
+}
@@ -0,0 +1,557 @@
+//! A versioned save state file format
+//!
+//! A file is the magic "C8SV" and a little endian u16 format version, followed by chunks of a
+//! four byte tag, a little endian u32 length and the data. Readers skip chunks they don't know,
+//! and files of older versions are migrated to the current layout as they are read.
+
+use AudioWrapper;
+use Chip8;
+use Chip8State;
+use DEFAULT_INSTRUCTIONS_PER_FRAME;
+use DEFAULT_TIMER_HZ;
+use KeyWrapper;
+use Mode;
+use Quirks;
+use RunState;
+use random::{RandomSource, XorShift};
+use Seriable0x800Array;
+use SeriableMemory;
+use sha1::Sha1;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &'static [u8; 4] = b"C8SV";
+
+/// The version files are written as
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    /// The file isn't a save state
+    BadMagic,
+    /// The file is a Chip8State serialized with serde_json, from before save states had a format
+    /// of their own, which lacks the ROM, quirks and timing a save state needs
+    Legacy,
+    /// The file was written by a newer version of the format
+    UnsupportedVersion(u16),
+    /// A chunk every save state has is missing
+    MissingChunk(&'static str),
+    /// A chunk doesn't hold what it should
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::Io(ref err) => write!(f, "{}", err),
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::Legacy => {
+                write!(f, "serialized Chip8State from before versioned save states, which can't be loaded")
+            }
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "save state format version {} is newer than {}", version, FORMAT_VERSION)
+            }
+            SaveStateError::MissingChunk(tag) => write!(f, "save state has no {} chunk", tag),
+            SaveStateError::Corrupt(tag) => write!(f, "save state {} chunk is corrupt", tag),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {
+    fn description(&self) -> &str {
+        match *self {
+            SaveStateError::Io(ref err) => err.description(),
+            SaveStateError::BadMagic => "not a save state",
+            SaveStateError::Legacy => "unversioned serialized state",
+            SaveStateError::UnsupportedVersion(_) => "unsupported save state version",
+            SaveStateError::MissingChunk(_) => "missing save state chunk",
+            SaveStateError::Corrupt(_) => "corrupt save state chunk",
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::Io(err)
+    }
+}
+
+/// A picture of the screen, one byte of lit planes per pixel
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Everything needed to put a machine back the way it was
+#[derive(Clone)]
+pub struct SaveState {
+    /// When the state was saved, in seconds since the Unix epoch
+    pub created: u64,
+    /// The SHA-1 of the ROM the machine was running
+    pub rom_sha1: [u8; 20],
+    pub quirks: Quirks,
+    /// The random number generator's state, empty if none was saved
+    pub rng: Vec<u8>,
+    pub instructions_per_frame: u32,
+    pub vip_timing: bool,
+    pub timer_hz: u32,
+    pub skip_idle: bool,
+    /// The register FX0A was waiting to put a key in and the key it was waiting to come up
+    pub waiting: Option<(u8, Option<u8>)>,
+    pub state: Chip8State,
+    pub cycles: u64,
+    pub frames: u64,
+    pub frame_cycle: u32,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// The SHA-1 of a ROM
+pub fn rom_sha1(rom: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(rom);
+    sha1.digest().bytes()
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    push_u16(bytes, value as u16);
+    push_u16(bytes, (value >> 16) as u16);
+}
+
+fn push_u64(bytes: &mut Vec<u8>, value: u64) {
+    push_u32(bytes, value as u32);
+    push_u32(bytes, (value >> 32) as u32);
+}
+
+/// Reads little endian numbers out of a chunk
+struct Cursor<'a> {
+    tag: &'static str,
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.bytes.len() < len {
+            return Err(SaveStateError::Corrupt(self.tag));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(try!(self.take(1))[0])
+    }
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = try!(self.take(2));
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(try!(self.u16()) as u32 | (try!(self.u16()) as u32) << 16)
+    }
+    fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(try!(self.u32()) as u64 | (try!(self.u32()) as u64) << 32)
+    }
+}
+
+/// Rewrites the chunks of one format version into the layout of the next
+type Migration = fn(&mut BTreeMap<[u8; 4], Vec<u8>>) -> Result<(), SaveStateError>;
+
+/// The step from every older version, the one at index n taking version n + 1 to n + 2
+///
+/// To change the layout, bump FORMAT_VERSION and add a step at the end that turns the chunks of
+/// the previous version into the new ones, filling in whatever an older machine would have had.
+const MIGRATIONS: &'static [Migration] = &[add_timing];
+
+/// Version 2 dropped the keys held down from META, which nothing used, and added the settings
+/// that decide how fast the machine runs
+fn add_timing(chunks: &mut BTreeMap<[u8; 4], Vec<u8>>) -> Result<(), SaveStateError> {
+    if let Some(meta) = chunks.get_mut(b"META") {
+        if meta.len() < 11 {
+            return Err(SaveStateError::Corrupt("META"));
+        }
+        meta.truncate(9);
+        push_u32(meta, DEFAULT_INSTRUCTIONS_PER_FRAME);
+        push_u32(meta, DEFAULT_TIMER_HZ);
+        meta.push(0);
+    }
+    Ok(())
+}
+
+/// Brings the chunks of an older format version up to the current layout
+fn migrate(version: u16, chunks: &mut BTreeMap<[u8; 4], Vec<u8>>) -> Result<(), SaveStateError> {
+    if version == 0 {
+        return Err(SaveStateError::Corrupt("header"));
+    }
+    if version > FORMAT_VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    debug_assert_eq!(MIGRATIONS.len(), FORMAT_VERSION as usize - 1);
+    for step in &MIGRATIONS[version as usize - 1..] {
+        try!(step(chunks));
+    }
+    Ok(())
+}
+
+impl SaveState {
+    /// Saves a running machine, along with the ROM it is running
+    pub fn capture<T, A>(chip8: &Chip8<T, A>, rom: &[u8], thumbnail: bool) -> Option<SaveState>
+        where T: KeyWrapper,
+              A: AudioWrapper
+    {
+        let state = match chip8.state.running() {
+            Some(state) => state,
+            None => return None,
+        };
+        let thumbnail = if thumbnail {
+            let (width, height) = (state.width(), state.height());
+            let mut pixels = vec![0; width * height];
+            for (x, y, planes) in state.frame_planes_iter() {
+                pixels[y * width + x] = planes;
+            }
+            Some(Thumbnail {
+                width: width,
+                height: height,
+                pixels: pixels,
+            })
+        } else {
+            None
+        };
+        Some(SaveState {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            rom_sha1: rom_sha1(rom),
+            quirks: chip8.quirks,
+            rng: chip8.rng.save(),
+            instructions_per_frame: chip8.instructions_per_frame,
+            vip_timing: chip8.vip_timing,
+            timer_hz: chip8.timer_hz,
+            skip_idle: chip8.skip_idle,
+            waiting: chip8.state.waiting(),
+            state: state.clone(),
+            cycles: chip8.cycles,
+            frames: chip8.frames,
+            frame_cycle: chip8.frame_cycle,
+            thumbnail: thumbnail,
+        })
+    }
+    /// Whether the state was saved while running rom
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        rom_sha1(rom) == self.rom_sha1
+    }
+    /// Puts a machine back into the saved state, with the saved quirks, speed and random numbers
+    pub fn restore<T: KeyWrapper, A: AudioWrapper>(&self, chip8: &mut Chip8<T, A>) {
+        if !self.rng.is_empty() && !chip8.rng.restore(&self.rng) {
+            // The state was saved with the default generator while the machine uses another
+            let mut rng = XorShift::new(0);
+            if rng.restore(&self.rng) {
+                chip8.rng = Box::new(rng);
+            }
+        }
+        chip8.state = RunState::resume(self.state.clone(), self.waiting);
+        chip8.quirks = self.quirks;
+        chip8.cycles = self.cycles;
+        chip8.frames = self.frames;
+        chip8.frame_cycle = self.frame_cycle;
+        chip8.instructions_per_frame = self.instructions_per_frame;
+        chip8.vip_timing = self.vip_timing;
+        chip8.timer_hz = self.timer_hz;
+        chip8.skip_idle = self.skip_idle;
+        if self.state.sound_timer > 0 {
+            chip8.audio_wrapper.play();
+        } else {
+            chip8.audio_wrapper.stop();
+        }
+        chip8.audio_wrapper.set_pattern(&self.state.audio_pattern, self.state.pitch);
+    }
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let state = &self.state;
+        let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = Vec::new();
+        let mut meta = Vec::new();
+        push_u64(&mut meta, self.created);
+        meta.push(state.mode.to_byte());
+        push_u32(&mut meta, self.instructions_per_frame);
+        push_u32(&mut meta, self.timer_hz);
+        meta.push(self.vip_timing as u8 | (self.skip_idle as u8) << 1);
+        chunks.push((b"META", meta));
+        chunks.push((b"ROM ", self.rom_sha1.to_vec()));
+        chunks.push((b"QRKS", self.quirks.to_text().into_bytes()));
+        chunks.push((b"RNG ", self.rng.clone()));
+        let mut cpu = state.data_registers.to_vec();
+        push_u16(&mut cpu, state.address_register);
+        push_u16(&mut cpu, state.program_counter);
+        cpu.extend_from_slice(&[state.delay_timer, state.sound_timer, state.stack.len() as u8]);
+        for &address in &state.stack {
+            push_u16(&mut cpu, address);
+        }
+        push_u64(&mut cpu, self.cycles);
+        push_u64(&mut cpu, self.frames);
+        push_u32(&mut cpu, self.frame_cycle);
+        chunks.push((b"CPU ", cpu));
+        if let Some((register, key)) = self.waiting {
+            // Left out while running, which is what files without it are read as
+            chunks.push((b"WAIT", vec![register, key.is_some() as u8, key.unwrap_or(0)]));
+        }
+        chunks.push((b"MEM ", state.memory.to_vec()));
+        let mut display = vec![state.hires as u8, state.planes];
+        display.extend_from_slice(&state.frame_buffer[..]);
+        chunks.push((b"DISP", display));
+        let mut xo = state.rpl_flags.to_vec();
+        xo.extend_from_slice(&state.audio_pattern);
+        xo.push(state.pitch);
+        chunks.push((b"XO  ", xo));
+        if let Some(ref thumbnail) = self.thumbnail {
+            let mut bytes = Vec::new();
+            push_u16(&mut bytes, thumbnail.width as u16);
+            push_u16(&mut bytes, thumbnail.height as u16);
+            bytes.extend_from_slice(&thumbnail.pixels);
+            chunks.push((b"THMB", bytes));
+        }
+        try!(output.write_all(MAGIC));
+        let mut header = Vec::new();
+        push_u16(&mut header, FORMAT_VERSION);
+        try!(output.write_all(&header));
+        for (tag, data) in chunks {
+            let mut len = Vec::new();
+            push_u32(&mut len, data.len() as u32);
+            try!(output.write_all(tag));
+            try!(output.write_all(&len));
+            try!(output.write_all(&data));
+        }
+        Ok(())
+    }
+    pub fn read<R: Read>(input: &mut R) -> Result<SaveState, SaveStateError> {
+        let mut file = Vec::new();
+        try!(input.read_to_end(&mut file));
+        if file.iter().find(|byte| !(**byte as char).is_whitespace()) == Some(&b'{') {
+            return Err(SaveStateError::Legacy);
+        }
+        let mut cursor = Cursor {
+            tag: "header",
+            bytes: &file,
+        };
+        if try!(cursor.take(4).map_err(|_| SaveStateError::BadMagic)) != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let version = try!(cursor.u16());
+        let mut chunks = BTreeMap::new();
+        while !cursor.bytes.is_empty() {
+            let mut tag = [0; 4];
+            tag.copy_from_slice(try!(cursor.take(4)));
+            let len = try!(cursor.u32()) as usize;
+            chunks.insert(tag, try!(cursor.take(len)).to_vec());
+        }
+        try!(migrate(version, &mut chunks));
+        let chunk = |tag: &'static str| {
+            let mut key = [0; 4];
+            key.copy_from_slice(tag.as_bytes());
+            chunks.get(&key)
+                .map(|bytes| {
+                    Cursor {
+                        tag: tag,
+                        bytes: bytes,
+                    }
+                })
+                .ok_or(SaveStateError::MissingChunk(tag))
+        };
+
+        let mut meta = try!(chunk("META"));
+        let created = try!(meta.u64());
+        let mode = try!(Mode::from_byte(try!(meta.u8())).ok_or(SaveStateError::Corrupt("META")));
+        let instructions_per_frame = try!(meta.u32());
+        let timer_hz = try!(meta.u32());
+        let flags = try!(meta.u8());
+        if timer_hz == 0 {
+            return Err(SaveStateError::Corrupt("META"));
+        }
+        let mut rom = try!(chunk("ROM "));
+        let mut rom_sha1 = [0; 20];
+        rom_sha1.copy_from_slice(try!(rom.take(20)));
+        let quirks = try!(chunk("QRKS"));
+        let quirks = try!(Quirks::from_text(&String::from_utf8_lossy(quirks.bytes))
+            .ok_or(SaveStateError::Corrupt("QRKS")));
+        let rng = try!(chunk("RNG ")).bytes.to_vec();
+
+        let mut state = Chip8State::new(mode);
+        let mut cpu = try!(chunk("CPU "));
+        state.data_registers.copy_from_slice(try!(cpu.take(16)));
+        state.address_register = try!(cpu.u16());
+        state.program_counter = try!(cpu.u16());
+        state.delay_timer = try!(cpu.u8());
+        state.sound_timer = try!(cpu.u8());
+        for _ in 0..try!(cpu.u8()) {
+            state.stack.push(try!(cpu.u16()));
+        }
+        let cycles = try!(cpu.u64());
+        let frames = try!(cpu.u64());
+        let frame_cycle = try!(cpu.u32());
+        let waiting = match chunk("WAIT") {
+            Ok(mut wait) => {
+                let register = try!(wait.u8());
+                let key = match (try!(wait.u8()), try!(wait.u8())) {
+                    (0, _) => None,
+                    (_, key) => Some(key),
+                };
+                if register > 0xF || key.map_or(false, |key| key > 0xF) {
+                    return Err(SaveStateError::Corrupt("WAIT"));
+                }
+                Some((register, key))
+            }
+            Err(_) => None,
+        };
+        let memory = try!(chunk("MEM ")).bytes;
+        if memory.len() != state.memory.len() {
+            return Err(SaveStateError::Corrupt("MEM "));
+        }
+        state.memory = SeriableMemory(memory.to_vec());
+        let mut display = try!(chunk("DISP"));
+        state.hires = try!(display.u8()) != 0;
+        state.planes = try!(display.u8());
+        let mut frame_buffer = Seriable0x800Array([0; 0x800]);
+        frame_buffer.copy_from_slice(try!(display.take(0x800)));
+        state.frame_buffer = frame_buffer;
+        let mut xo = try!(chunk("XO  "));
+        state.rpl_flags.copy_from_slice(try!(xo.take(16)));
+        state.audio_pattern.copy_from_slice(try!(xo.take(16)));
+        state.pitch = try!(xo.u8());
+
+        let thumbnail = match chunk("THMB") {
+            Ok(mut thumbnail) => {
+                let width = try!(thumbnail.u16()) as usize;
+                let height = try!(thumbnail.u16()) as usize;
+                Some(Thumbnail {
+                    width: width,
+                    height: height,
+                    pixels: try!(thumbnail.take(width * height)).to_vec(),
+                })
+            }
+            Err(_) => None,
+        };
+        Ok(SaveState {
+            created: created,
+            rom_sha1: rom_sha1,
+            quirks: quirks,
+            rng: rng,
+            instructions_per_frame: instructions_per_frame,
+            vip_timing: flags & 1 != 0,
+            timer_hz: timer_hz,
+            skip_idle: flags & 2 != 0,
+            waiting: waiting,
+            state: state,
+            cycles: cycles,
+            frames: frames,
+            frame_cycle: frame_cycle,
+            thumbnail: thumbnail,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use DEFAULT_INSTRUCTIONS_PER_FRAME;
+    use DEFAULT_TIMER_HZ;
+    use Quirks;
+    use std::collections::BTreeMap;
+    use super::{FORMAT_VERSION, MIGRATIONS, SaveState, SaveStateError, migrate, push_u64};
+    use testing::{assert_same, machine, run};
+
+    /// Draws a box and calls a subroutine that counts in V1 before waiting for a key
+    const PROGRAM: [u8; 21] = [0xA2, 0x10, 0x60, 0x05, 0xD0, 0x05, 0x22, 0x0C, 0xFA, 0x0A, 0x12, 0x08,
+                               0x71, 0x01, 0x00, 0xEE, 0xFF, 0x81, 0x81, 0x81, 0xFF];
+
+    fn written(save: &SaveState) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        save.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn one_step_per_old_version() {
+        assert_eq!(MIGRATIONS.len(), FORMAT_VERSION as usize - 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut chip8 = machine(&PROGRAM, Quirks::cosmac_vip());
+        chip8.seed(7);
+        chip8.instructions_per_frame = 30;
+        chip8.vip_timing = true;
+        chip8.timer_hz = 50;
+        chip8.skip_idle = true;
+        run(&mut chip8, 5);
+        let save = SaveState::capture(&chip8, &PROGRAM, true).unwrap();
+        let read = SaveState::read(&mut &written(&save)[..]).unwrap();
+        assert!(read.matches_rom(&PROGRAM));
+        assert_eq!(read.created, save.created);
+        assert_eq!(read.quirks, Quirks::cosmac_vip());
+        assert_eq!(read.rng, save.rng);
+        assert_eq!((read.instructions_per_frame, read.vip_timing, read.timer_hz, read.skip_idle),
+                   (30, true, 50, true));
+        assert_eq!((read.cycles, read.frames, read.frame_cycle), (5, chip8.frames(), chip8.frame_cycle()));
+        assert_eq!(read.thumbnail, save.thumbnail);
+        assert_eq!(read.thumbnail.as_ref().unwrap().pixels[5 * 64 + 5], 1);
+        assert_same(&read.state, &chip8);
+
+        // A machine with the default settings runs on exactly like the saved one
+        let mut restored = machine(&[], Quirks::default());
+        read.restore(&mut restored);
+        assert_eq!(restored.quirks, chip8.quirks);
+        assert_eq!((restored.instructions_per_frame, restored.timer_hz), (30, 50));
+        assert!(restored.vip_timing && restored.skip_idle);
+        run(&mut chip8, 2);
+        run(&mut restored, 2);
+        assert_same(&restored, &chip8);
+        assert_eq!(restored.frame_cycle(), chip8.frame_cycle());
+    }
+
+    #[test]
+    fn version_one_migrates() {
+        let save = SaveState::capture(&machine(&PROGRAM, Quirks::default()), &PROGRAM, false).unwrap();
+        let current = written(&save);
+        // Rewrite the header and META, the first chunk, the way version 1 laid them out
+        let mut old = b"C8SV\x01\x00META\x0B\x00\x00\x00".to_vec();
+        push_u64(&mut old, 1234);
+        old.extend_from_slice(&[0, 0x20, 0]);
+        old.extend_from_slice(&current[6 + 8 + 18..]);
+        let read = SaveState::read(&mut &old[..]).unwrap();
+        assert_eq!(read.created, 1234);
+        assert_eq!((read.instructions_per_frame, read.vip_timing, read.timer_hz, read.skip_idle),
+                   (DEFAULT_INSTRUCTIONS_PER_FRAME, false, DEFAULT_TIMER_HZ, false));
+        assert_same(&read.state, &save.state);
+
+        let mut chunks = BTreeMap::new();
+        chunks.insert(*b"META", vec![0; 10]);
+        assert!(match migrate(1, &mut chunks) {
+            Err(SaveStateError::Corrupt("META")) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn unreadable_versions() {
+        let save = SaveState::capture(&machine(&PROGRAM, Quirks::default()), &PROGRAM, false).unwrap();
+        let mut bytes = written(&save);
+        bytes[4] = FORMAT_VERSION as u8 + 1;
+        assert!(match SaveState::read(&mut &bytes[..]) {
+            Err(SaveStateError::UnsupportedVersion(version)) => version == FORMAT_VERSION + 1,
+            _ => false,
+        });
+        bytes[4] = 0;
+        assert!(SaveState::read(&mut &bytes[..]).is_err());
+        assert!(match SaveState::read(&mut &b"{\"memory\": []}"[..]) {
+            Err(SaveStateError::Legacy) => true,
+            _ => false,
+        });
+    }
+}
@@ -1,12 +1,29 @@
+/// The instruction set a machine understands
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// The original chip8
+    Chip8,
+    /// SUPER-CHIP 1.1
+    SuperChip,
+    /// XO-CHIP, a SUPER-CHIP superset with 64 KiB of memory, two bitplanes and audio patterns
+    XoChip,
+}
+
 /// A representation of the chip8 machine's state
 #[derive(Serialize, Deserialize)]
 pub struct Chip8State {
     data_registers: [u8; 16],
     address_register: u16,
-    memory: Seriable0x1000Array,
+    memory: SeriableMemory,
     program_counter: u16,
     stack: Vec<u16>,
     delay_timer: u8,
     sound_timer: u8,
-    frame_buffer: [[u8; 8]; 32],
-}
\ No newline at end of file
+    frame_buffer: Seriable0x800Array,
+    mode: Mode,
+    hires: bool,
+    rpl_flags: [u8; 16],
+    planes: u8,
+    audio_pattern: [u8; 16],
+    pitch: u8,
+}
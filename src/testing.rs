@@ -0,0 +1,55 @@
+//! Machines for the unit tests
+
+use AudioWrapper;
+use Chip8;
+use Chip8State;
+use KeyWrapper;
+use Quirks;
+
+/// Input with no key ever held down
+pub struct NoKeys;
+
+impl KeyWrapper for NoKeys {
+    fn is_pushed(&self, _: u8) -> bool {
+        false
+    }
+    fn get_key(&self) -> Option<u8> {
+        None
+    }
+}
+
+/// Audio that plays nothing
+pub struct Silence;
+
+impl AudioWrapper for Silence {
+    fn play(&mut self) {}
+    fn stop(&mut self) {}
+}
+
+/// A machine with quirks that has loaded program
+pub fn machine(program: &[u8], quirks: Quirks) -> Chip8<NoKeys, Silence> {
+    let mut chip8 = Chip8::with_quirks(NoKeys, Silence, quirks);
+    chip8.load_prog(&mut &program[..]).unwrap();
+    chip8
+}
+
+/// Runs steps instructions, panicking on a fault
+pub fn run<T: KeyWrapper, A: AudioWrapper>(chip8: &mut Chip8<T, A>, steps: usize) {
+    for _ in 0..steps {
+        chip8.step().unwrap();
+    }
+}
+
+/// Asserts that two machine states are the same in every part
+pub fn assert_same(left: &Chip8State, right: &Chip8State) {
+    assert_eq!(left.data_registers, right.data_registers);
+    assert_eq!(left.address_register, right.address_register);
+    assert!(left.memory[..] == right.memory[..], "memory differs");
+    assert_eq!(left.program_counter, right.program_counter);
+    assert_eq!(left.stack, right.stack);
+    assert_eq!((left.delay_timer, left.sound_timer), (right.delay_timer, right.sound_timer));
+    assert!(left.frame_buffer[..] == right.frame_buffer[..], "frame buffer differs");
+    assert_eq!((left.mode, left.hires, left.planes), (right.mode, right.hires, right.planes));
+    assert_eq!(left.rpl_flags, right.rpl_flags);
+    assert_eq!((left.audio_pattern, left.pitch), (right.audio_pattern, right.pitch));
+}
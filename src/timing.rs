@@ -0,0 +1,66 @@
+//! What instructions cost on the COSMAC VIP
+//!
+//! The VIP's 1802 runs a machine cycle every 8 clocks of its 1.76 MHz crystal, which makes 3668
+//! machine cycles a frame. The display interrupt and its DMA take part of every frame, so the
+//! interpreter gets the rest. The costs are the usual figures for the original interpreter,
+//! with the 40 cycle fetch and decode loop included.
+
+use Chip8State;
+use Instruction;
+
+/// The machine cycles of a 60 Hz frame
+const FRAME_CYCLES: u32 = 3668;
+/// The machine cycles the display interrupt routine and the DMA of 128 lines of 8 bytes take
+const INTERRUPT_CYCLES: u32 = 1024 + 46;
+/// The machine cycles of a frame left for the interpreter
+pub const CYCLES_PER_FRAME: u32 = FRAME_CYCLES - INTERRUPT_CYCLES;
+
+/// The fetch and decode loop run before every instruction
+const FETCH_CYCLES: u32 = 40;
+/// What taking a skip adds
+pub const SKIP_CYCLES: u32 = 4;
+
+/// The machine cycles instruction takes to run on state, not counting a skip being taken
+pub fn instruction_cycles(instruction: &Instruction, state: &Chip8State) -> u32 {
+    use Instruction::*;
+    FETCH_CYCLES +
+    match *instruction {
+        Cls => 3078,
+        Ret => 10,
+        Jp(_) => 12,
+        Call(_) => 26,
+        SeByte { .. } | SneByte { .. } => 10,
+        SeReg { .. } | SneReg { .. } | Skp(_) | Sknp(_) => 14,
+        LdByte { .. } => 6,
+        AddByte { .. } | LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+        LdReg { .. } | Or { .. } | And { .. } | Xor { .. } | Add { .. } | Sub { .. } | Shr { .. } |
+        Subn { .. } | Shl { .. } => 44,
+        LdI(_) => 12,
+        JpV0(_) => 22,
+        Rnd { .. } => 36,
+        Drw { x, n, .. } => {
+            // Sprites that don't start on a byte boundary are shifted into two bytes a row
+            let row = if state.data_registers[x as usize] % 8 == 0 { 46 } else { 66 };
+            68 + n as u32 * row
+        }
+        LdVxK(_) => 18,
+        AddI(_) | LdF(_) => 16,
+        LdB(x) => {
+            // Each digit is counted out one at a time
+            let value = state.data_registers[x as usize] as u32;
+            84 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        LdIVx(x) | LdVxI(x) => 14 + 14 * (x as u32 + 1),
+        // The VIP can't run anything else, so it gets the cost of a simple instruction
+        _ => 10,
+    }
+}
+
+/// Whether instruction skips the next one when its test passes
+pub fn is_skip(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    match *instruction {
+        SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } | Skp(_) | Sknp(_) => true,
+        _ => false,
+    }
+}
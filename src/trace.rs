@@ -0,0 +1,412 @@
+//! Per-instruction execution traces
+//!
+//! A binary trace starts with the magic "C8TR" and a version byte. Every record is then the
+//! cycle (u64), program counter and I (u16), stack depth, delta count and error code (u8), all
+//! little endian, followed by the address for a memory error, the instruction bytes and a
+//! (register, old, new) triple for each changed register. Version 1 traces have no error code.
+
+use Chip8Err;
+use Chip8State;
+use Instruction;
+use Quirks;
+use disassembler::{self, Syntax};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::io::prelude::*;
+
+const MAGIC: &'static [u8; 4] = b"C8TR";
+const VERSION: u8 = 2;
+
+/// The code of the error a record stopped the machine with, 0 for none
+fn error_code(error: Option<Chip8Err>) -> u8 {
+    match error {
+        None => 0,
+        Some(Chip8Err::UnknownOptcode) => 1,
+        Some(Chip8Err::StackUnderFlow) => 2,
+        Some(Chip8Err::StackOverflow) => 3,
+        Some(Chip8Err::BadState) => 4,
+        Some(Chip8Err::Exit) => 5,
+        Some(Chip8Err::MemoryOutOfBounds { .. }) => 6,
+        Some(Chip8Err::PcOutOfBounds) => 7,
+        Some(Chip8Err::RoutineHung) => 8,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceFormat {
+    /// One line per instruction
+    Text,
+    Binary,
+}
+
+/// A group of instructions a trace can be filtered by
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpcodeClass {
+    /// Jumps, calls, returns and exits
+    Flow,
+    Skip,
+    /// Loads and arithmetic on the V registers
+    Arithmetic,
+    /// Instructions using I
+    Memory,
+    Display,
+    Timer,
+    Input,
+    Random,
+    Audio,
+}
+
+impl OpcodeClass {
+    pub fn of(instruction: &Instruction) -> OpcodeClass {
+        use Instruction::*;
+        match *instruction {
+            Sys(_) | Ret | Exit | Jp(_) | Call(_) | JpV0(_) => OpcodeClass::Flow,
+            SeByte { .. } | SneByte { .. } | SeReg { .. } | SneReg { .. } => OpcodeClass::Skip,
+            Cls | ScrollDown(_) | ScrollUp(_) | ScrollRight | ScrollLeft | Lores | Hires | Drw { .. } |
+            Plane(_) => OpcodeClass::Display,
+            Skp(_) | Sknp(_) | LdVxK(_) => OpcodeClass::Input,
+            LdByte { .. } | AddByte { .. } | LdReg { .. } | Or { .. } | And { .. } | Xor { .. } |
+            Add { .. } | Sub { .. } | Shr { .. } | Subn { .. } | Shl { .. } | LdRVx(_) | LdVxR(_) => {
+                OpcodeClass::Arithmetic
+            }
+            SaveRange { .. } | LoadRange { .. } | LdI(_) | LdLongI(_) | AddI(_) | LdF(_) | LdHf(_) |
+            LdB(_) | LdIVx(_) | LdVxI(_) => OpcodeClass::Memory,
+            LdVxDt(_) | LdDtVx(_) => OpcodeClass::Timer,
+            Rnd { .. } => OpcodeClass::Random,
+            LdStVx(_) | Audio | Pitch(_) => OpcodeClass::Audio,
+        }
+    }
+}
+
+/// Which records a tracer writes
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TraceFilter {
+    /// Only trace instructions at start..end
+    pub addresses: Option<(u16, u16)>,
+    /// Only trace these classes, or every class if empty
+    pub classes: Vec<OpcodeClass>,
+}
+
+impl TraceFilter {
+    pub fn accepts(&self, record: &TraceRecord) -> bool {
+        self.addresses.map_or(true, |(start, end)| {
+            start <= record.program_counter && record.program_counter < end
+        }) && (self.classes.is_empty() || self.classes.contains(&OpcodeClass::of(&record.instruction)))
+    }
+}
+
+/// One executed instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceRecord {
+    /// The number of instructions run before this one
+    pub cycle: u64,
+    pub program_counter: u16,
+    pub instruction: Instruction,
+    /// Every register the instruction changed, as (register, old, new)
+    pub deltas: Vec<(u8, u8, u8)>,
+    /// I after the instruction ran
+    pub address_register: u16,
+    /// The stack depth after the instruction ran
+    pub stack_depth: usize,
+    /// The fault the instruction caused, or Exit if it halted the machine
+    pub error: Option<Chip8Err>,
+}
+
+impl TraceRecord {
+    /// Starts a record for the instruction at the program counter, if there is a valid one
+    pub fn before(state: &Chip8State, quirks: &Quirks, cycle: u64) -> Option<TraceRecord> {
+        let program_counter = state.program_counter as usize;
+        let instruction = Instruction::fetch(&state.memory, program_counter, state.mode, quirks);
+        instruction.ok().map(|instruction| {
+            TraceRecord {
+                cycle: cycle,
+                program_counter: state.program_counter,
+                instruction: instruction,
+                // Holds the old registers until finish
+                deltas: state.data_registers
+                    .iter()
+                    .enumerate()
+                    .map(|(x, &old)| (x as u8, old, old))
+                    .collect(),
+                address_register: state.address_register,
+                stack_depth: state.stack.len(),
+                error: None,
+            }
+        })
+    }
+    /// Fills in the changes once the instruction has run, and the error it stopped the machine with
+    pub fn finish(&mut self, state: &Chip8State, error: Option<Chip8Err>) {
+        self.error = error;
+        for delta in &mut self.deltas {
+            delta.2 = state.data_registers[delta.0 as usize];
+        }
+        self.deltas.retain(|&(_, old, new)| old != new);
+        self.address_register = state.address_register;
+        self.stack_depth = state.stack.len();
+    }
+    pub fn write_text<W: Write + ?Sized>(&self, output: &mut W) -> io::Result<()> {
+        let raw: String = self.instruction
+            .to_bytes()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let mnemonic =
+            disassembler::format_instruction(&self.instruction, Syntax::Cowgod, &BTreeMap::new());
+        try!(write!(output,
+                    "{:>10} {:04X}: {:<8} {:<24} ; I={:04X} SP={}",
+                    self.cycle,
+                    self.program_counter,
+                    raw,
+                    mnemonic,
+                    self.address_register,
+                    self.stack_depth));
+        for &(x, old, new) in &self.deltas {
+            try!(write!(output, " V{:X}:{:02X}->{:02X}", x, old, new));
+        }
+        match self.error {
+            Some(Chip8Err::MemoryOutOfBounds { addr }) => {
+                try!(write!(output, " ! memory out of bounds at {:04X}", addr))
+            }
+            Some(error) => try!(write!(output, " ! {}", error.description())),
+            None => {}
+        }
+        writeln!(output, "")
+    }
+    pub fn write_binary<W: Write + ?Sized>(&self, output: &mut W) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(22 + self.deltas.len() * 3);
+        for shift in 0..8 {
+            bytes.push((self.cycle >> (shift * 8)) as u8);
+        }
+        bytes.extend_from_slice(&[self.program_counter as u8,
+                                  (self.program_counter >> 8) as u8,
+                                  self.address_register as u8,
+                                  (self.address_register >> 8) as u8,
+                                  self.stack_depth as u8,
+                                  self.deltas.len() as u8,
+                                  error_code(self.error)]);
+        if let Some(Chip8Err::MemoryOutOfBounds { addr }) = self.error {
+            bytes.extend((0..4).map(|byte| (addr >> (byte * 8)) as u8));
+        }
+        bytes.extend(self.instruction.to_bytes());
+        for &(x, old, new) in &self.deltas {
+            bytes.extend_from_slice(&[x, old, new]);
+        }
+        output.write_all(&bytes)
+    }
+}
+
+/// Writes trace records to an output
+pub struct Tracer {
+    output: Box<Write>,
+    pub format: TraceFormat,
+    pub filter: TraceFilter,
+    started: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(output: W, format: TraceFormat) -> Tracer {
+        Tracer {
+            output: Box::new(output),
+            format: format,
+            filter: TraceFilter::default(),
+            started: false,
+            error: None,
+        }
+    }
+    /// Writes a record if the filter accepts it
+    ///
+    /// After a write fails nothing more is written, and the error is kept for take_error.
+    pub fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_some() || !self.filter.accepts(record) {
+            return;
+        }
+        let mut result = Ok(());
+        if !self.started && self.format == TraceFormat::Binary {
+            result = self.output.write_all(MAGIC).and_then(|_| self.output.write_all(&[VERSION]));
+        }
+        self.started = true;
+        result = result.and_then(|_| match self.format {
+            TraceFormat::Text => record.write_text(&mut *self.output),
+            TraceFormat::Binary => record.write_binary(&mut *self.output),
+        });
+        if let Err(err) = result {
+            self.error = Some(err);
+        }
+    }
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+    /// The error that stopped the trace, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+/// Reads the records of a binary trace
+pub struct TraceReader<R: Read> {
+    input: R,
+    /// The version of the trace, once its header has been read
+    version: Option<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(input: R) -> TraceReader<R> {
+        TraceReader {
+            input: input,
+            version: None,
+        }
+    }
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let version = match self.version {
+            Some(version) => version,
+            None => {
+                let mut header = [0; 5];
+                try!(self.input.read_exact(&mut header));
+                if &header[..4] != MAGIC || header[4] == 0 || header[4] > VERSION {
+                    return Err(invalid("not a binary trace"));
+                }
+                self.version = Some(header[4]);
+                header[4]
+            }
+        };
+        let mut fixed = [0; 15];
+        let fixed_len = if version == 1 { 14 } else { 15 };
+        // A clean end of the trace is only allowed between records
+        let read = try!(self.input.read(&mut fixed[..fixed_len]));
+        if read == 0 {
+            return Ok(None);
+        }
+        try!(self.input.read_exact(&mut fixed[read..fixed_len]));
+        let error = match fixed[14] {
+            0 => None,
+            1 => Some(Chip8Err::UnknownOptcode),
+            2 => Some(Chip8Err::StackUnderFlow),
+            3 => Some(Chip8Err::StackOverflow),
+            4 => Some(Chip8Err::BadState),
+            5 => Some(Chip8Err::Exit),
+            6 => {
+                let mut addr = [0; 4];
+                try!(self.input.read_exact(&mut addr));
+                let addr = addr.iter().rev().fold(0, |addr, &byte| addr << 8 | byte as usize);
+                Some(Chip8Err::MemoryOutOfBounds { addr: addr })
+            }
+            7 => Some(Chip8Err::PcOutOfBounds),
+            8 => Some(Chip8Err::RoutineHung),
+            _ => return Err(invalid("bad error code in trace")),
+        };
+        let word = |index: usize| fixed[index] as u16 | (fixed[index + 1] as u16) << 8;
+        let mut raw = [0; 4];
+        try!(self.input.read_exact(&mut raw[..2]));
+        let optcode = (raw[0] as u16) << 8 | raw[1] as u16;
+        let instruction = if optcode == 0xF000 {
+            try!(self.input.read_exact(&mut raw[2..]));
+            Instruction::decode_long(optcode, (raw[2] as u16) << 8 | raw[3] as u16)
+        } else {
+            Instruction::decode(optcode)
+        };
+        let instruction = try!(instruction.map_err(|_| invalid("bad instruction in trace")));
+        let mut deltas = Vec::new();
+        for _ in 0..fixed[13] {
+            let mut delta = [0; 3];
+            try!(self.input.read_exact(&mut delta));
+            deltas.push((delta[0], delta[1], delta[2]));
+        }
+        Ok(Some(TraceRecord {
+            cycle: fixed[..8].iter().rev().fold(0, |cycle, &byte| cycle << 8 | byte as u64),
+            program_counter: word(8),
+            instruction: instruction,
+            deltas: deltas,
+            address_register: word(10),
+            stack_depth: fixed[12] as usize,
+            error: error,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+    fn next(&mut self) -> Option<io::Result<TraceRecord>> {
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Chip8Err;
+    use Quirks;
+    use std::cell::RefCell;
+    use std::io;
+    use std::io::prelude::*;
+    use std::rc::Rc;
+    use super::{TraceFormat, TraceReader, Tracer};
+    use testing::machine;
+
+    /// An output the test can still read once the tracer has it
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Traces program until it stops
+    fn trace(program: &[u8], format: TraceFormat) -> Vec<u8> {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut chip8 = machine(program, Quirks::default());
+        chip8.tracer = Some(Tracer::new(Shared(output.clone()), format));
+        while chip8.step().is_ok() {}
+        let trace = output.borrow().clone();
+        trace
+    }
+
+    #[test]
+    fn faulting_instruction_is_traced() {
+        // V0 := 1, I := 0xFFF, save v1
+        let program = [0x60, 0x01, 0xAF, 0xFF, 0xF1, 0x55];
+        let binary = trace(&program, TraceFormat::Binary);
+        let records = TraceReader::new(&binary[..]).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].error, None);
+        assert_eq!(records[0].deltas, vec![(0, 0, 1)]);
+        assert_eq!(records[2].program_counter, 0x204);
+        assert_eq!(records[2].error, Some(Chip8Err::MemoryOutOfBounds { addr: 0x1000 }));
+        let text = String::from_utf8(trace(&program, TraceFormat::Text)).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.lines().last().unwrap().ends_with(" ! memory out of bounds at 1000"));
+    }
+
+    #[test]
+    fn halt_is_traced() {
+        let binary = trace(&[0x12, 0x00], TraceFormat::Binary);
+        let records = TraceReader::new(&binary[..]).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].error, Some(Chip8Err::Exit));
+    }
+
+    #[test]
+    fn version_one_traces_read() {
+        let mut binary = trace(&[0x60, 0x01, 0x12, 0x02], TraceFormat::Binary);
+        // Drop the error codes, which end the fixed 15 bytes of each record, and the first record
+        // is 20 bytes long with its instruction and delta
+        binary[4] = 1;
+        binary.remove(5 + 20 + 14);
+        binary.remove(5 + 14);
+        let records = TraceReader::new(&binary[..]).collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].error, None);
+    }
+}
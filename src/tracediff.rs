@@ -0,0 +1,314 @@
+//! Finds the first cycle where two runs of a program disagree
+//!
+//! The two sides are either two machines run in lockstep, for example with different quirks or
+//! revisions of a ROM, or a recorded binary trace and a live machine.
+
+use AudioWrapper;
+use Chip8;
+use Chip8Err;
+use Chip8State;
+use Instruction;
+use KeyWrapper;
+use RunState;
+use std::io;
+use std::io::prelude::*;
+use trace::TraceRecord;
+
+/// Something the two sides disagree on after a cycle
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Difference {
+    /// The sides ran different instructions
+    Instruction(Option<Instruction>, Option<Instruction>),
+    ProgramCounter(u16, u16),
+    Register(u8, u8, u8),
+    AddressRegister(u16, u16),
+    StackDepth(usize, usize),
+    Stack(Vec<u16>, Vec<u16>),
+    DelayTimer(u8, u8),
+    SoundTimer(u8, u8),
+    /// The first differing byte of memory, as (address, left, right)
+    Memory(usize, u8, u8),
+    Display,
+    /// One side faulted, or they faulted differently
+    Fault(Option<Chip8Err>, Option<Chip8Err>),
+}
+
+/// The first cycle the sides disagreed on
+#[derive(Clone)]
+pub struct Divergence {
+    pub cycle: u64,
+    /// What each side ran on the cycle, if it ran a valid instruction
+    pub records: (Option<TraceRecord>, Option<TraceRecord>),
+    pub differences: Vec<Difference>,
+    /// Each side after the cycle, if it was a live machine
+    pub states: (Option<Chip8State>, Option<Chip8State>),
+}
+
+fn hex_list<T: ::std::fmt::UpperHex>(values: &[T], digits: usize) -> String {
+    values.iter().map(|value| format!("{:01$X}", value, digits)).collect::<Vec<_>>().join(" ")
+}
+
+fn write_state<W: Write>(output: &mut W, name: &str, state: &Chip8State) -> io::Result<()> {
+    try!(write!(output, "{}:", name));
+    for (x, value) in state.data_registers.iter().enumerate() {
+        try!(write!(output, " V{:X}={:02X}", x, value));
+    }
+    try!(writeln!(output,
+                  "\n    PC={:04X} I={:04X} DT={:02X} ST={:02X} stack=[{}]",
+                  state.program_counter,
+                  state.address_register,
+                  state.delay_timer,
+                  state.sound_timer,
+                  hex_list(&state.stack, 4)));
+    Ok(())
+}
+
+impl Divergence {
+    /// Writes a human readable report of the divergence
+    pub fn write_report<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        try!(writeln!(output, "Runs diverge at cycle {}", self.cycle));
+        for &(name, ref record) in &[("left ", &self.records.0), ("right", &self.records.1)] {
+            try!(write!(output, "{}: ", name));
+            match **record {
+                Some(ref record) => try!(record.write_text(output)),
+                None => try!(writeln!(output, "no instruction")),
+            }
+        }
+        for difference in &self.differences {
+            try!(match *difference {
+                Difference::Instruction(left, right) => {
+                    writeln!(output, "instruction: {:?} vs {:?}", left, right)
+                }
+                Difference::ProgramCounter(left, right) => {
+                    writeln!(output, "PC: {:04X} vs {:04X}", left, right)
+                }
+                Difference::Register(x, left, right) => {
+                    writeln!(output, "V{:X}: {:02X} vs {:02X}", x, left, right)
+                }
+                Difference::AddressRegister(left, right) => {
+                    writeln!(output, "I: {:04X} vs {:04X}", left, right)
+                }
+                Difference::StackDepth(left, right) => {
+                    writeln!(output, "stack depth: {} vs {}", left, right)
+                }
+                Difference::Stack(ref left, ref right) => {
+                    writeln!(output, "stack: [{}] vs [{}]", hex_list(left, 4), hex_list(right, 4))
+                }
+                Difference::DelayTimer(left, right) => {
+                    writeln!(output, "DT: {:02X} vs {:02X}", left, right)
+                }
+                Difference::SoundTimer(left, right) => {
+                    writeln!(output, "ST: {:02X} vs {:02X}", left, right)
+                }
+                Difference::Memory(address, left, right) => {
+                    writeln!(output, "memory at {:04X}: {:02X} vs {:02X}", address, left, right)
+                }
+                Difference::Display => writeln!(output, "display differs"),
+                Difference::Fault(left, right) => {
+                    writeln!(output, "fault: {:?} vs {:?}", left, right)
+                }
+            });
+        }
+        if let Some(ref state) = self.states.0 {
+            try!(write_state(output, "left ", state));
+        }
+        if let Some(ref state) = self.states.1 {
+            try!(write_state(output, "right", state));
+        }
+        if let (&Some(ref left), &Some(ref right)) = (&self.states.0, &self.states.1) {
+            // Every 16 byte row of memory that differs
+            let rows = left.memory.chunks(16).zip(right.memory.chunks(16)).enumerate();
+            for (row, (left_row, right_row)) in rows.filter(|&(_, (left, right))| left != right) {
+                try!(writeln!(output, "{:04X} left  {}", row * 16, hex_list(left_row, 2)));
+                try!(writeln!(output, "{:04X} right {}", row * 16, hex_list(right_row, 2)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The state of a machine, including a faulted one
+fn state_of<T: KeyWrapper, A: AudioWrapper>(chip8: &Chip8<T, A>) -> Option<&Chip8State> {
+    chip8.state.state()
+}
+
+/// Runs one instruction, returning a record of it and any fault
+fn step<T, A>(chip8: &mut Chip8<T, A>) -> (Option<TraceRecord>, Option<Chip8Err>)
+    where T: KeyWrapper,
+          A: AudioWrapper
+{
+    let cycles = chip8.cycles();
+    let mut record = match chip8.state {
+        RunState::Running(ref state) |
+        RunState::WaitingForKey { ref state, .. } |
+        RunState::Idle(ref state) => TraceRecord::before(state, &chip8.quirks, cycles),
+        RunState::Halted(_) => return (None, Some(Chip8Err::Exit)),
+        RunState::Faulted { err, .. } => return (None, Some(err)),
+        RunState::Empty => return (None, Some(Chip8Err::BadState)),
+    };
+    let fault = match chip8.step() {
+        Err(err) => Some(err),
+        Ok(()) if chip8.state.is_halted() => Some(Chip8Err::Exit),
+        Ok(()) => None,
+    };
+    if let (Some(ref mut record), Some(state)) = (record.as_mut(), state_of(chip8)) {
+        record.finish(state, fault);
+    }
+    (record, fault)
+}
+
+/// The differences in what two records say about their cycle
+fn compare_records(left: &Option<TraceRecord>, right: &Option<TraceRecord>) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let (left, right) = match (left.as_ref(), right.as_ref()) {
+        (Some(left), Some(right)) => (left, right),
+        (None, None) => return differences,
+        (left, right) => {
+            differences.push(Difference::Instruction(left.map(|record| record.instruction),
+                                                     right.map(|record| record.instruction)));
+            return differences;
+        }
+    };
+    if left.program_counter != right.program_counter {
+        differences.push(Difference::ProgramCounter(left.program_counter, right.program_counter));
+    }
+    if left.instruction != right.instruction {
+        differences.push(Difference::Instruction(Some(left.instruction), Some(right.instruction)));
+    }
+    for x in 0..16 {
+        let new = |record: &TraceRecord| {
+            record.deltas.iter().find(|&&(register, _, _)| register == x).map(|&(_, _, new)| new)
+        };
+        let (left_new, right_new) = (new(left), new(right));
+        if left_new != right_new {
+            // A register one side didn't change is only known from the other side's old value
+            let old = |record: &TraceRecord| {
+                record.deltas.iter().find(|&&(register, _, _)| register == x).map(|&(_, old, _)| old)
+            };
+            let left_value = left_new.or_else(|| old(right)).unwrap_or(0);
+            let right_value = right_new.or_else(|| old(left)).unwrap_or(0);
+            if left_value != right_value {
+                differences.push(Difference::Register(x, left_value, right_value));
+            }
+        }
+    }
+    if left.address_register != right.address_register {
+        differences.push(Difference::AddressRegister(left.address_register, right.address_register));
+    }
+    if left.stack_depth != right.stack_depth {
+        differences.push(Difference::StackDepth(left.stack_depth, right.stack_depth));
+    }
+    differences
+}
+
+/// The differences between two machine states
+fn compare_states(left: &Chip8State, right: &Chip8State) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if left.program_counter != right.program_counter {
+        differences.push(Difference::ProgramCounter(left.program_counter, right.program_counter));
+    }
+    for x in 0..16 {
+        let (left_value, right_value) = (left.data_registers[x], right.data_registers[x]);
+        if left_value != right_value {
+            differences.push(Difference::Register(x as u8, left_value, right_value));
+        }
+    }
+    if left.address_register != right.address_register {
+        differences.push(Difference::AddressRegister(left.address_register, right.address_register));
+    }
+    if left.stack != right.stack {
+        differences.push(Difference::Stack(left.stack.clone(), right.stack.clone()));
+    }
+    if left.delay_timer != right.delay_timer {
+        differences.push(Difference::DelayTimer(left.delay_timer, right.delay_timer));
+    }
+    if left.sound_timer != right.sound_timer {
+        differences.push(Difference::SoundTimer(left.sound_timer, right.sound_timer));
+    }
+    let memory = left.memory.iter().zip(right.memory.iter()).position(|(left, right)| left != right);
+    if let Some(address) = memory {
+        differences.push(Difference::Memory(address, left.memory[address], right.memory[address]));
+    }
+    if left.frame_buffer[..] != right.frame_buffer[..] || left.hires != right.hires {
+        differences.push(Difference::Display);
+    }
+    differences
+}
+
+/// Runs two machines in lockstep for at most limit cycles, returning the first cycle where they
+/// disagree
+///
+/// Returns None if they agree until the limit, or until both fault the same way. Both machines
+/// should be seeded the same, or a ROM using CXNN diverges right away.
+pub fn diff_machines<T, A, U, B>(left: &mut Chip8<T, A>,
+                                 right: &mut Chip8<U, B>,
+                                 limit: u64)
+                                 -> Option<Divergence>
+    where T: KeyWrapper,
+          A: AudioWrapper,
+          U: KeyWrapper,
+          B: AudioWrapper
+{
+    for cycle in 0..limit {
+        let (left_record, left_fault) = step(left);
+        let (right_record, right_fault) = step(right);
+        let records = (left_record, right_record);
+        let mut differences = match (state_of(left), state_of(right)) {
+            (Some(left_state), Some(right_state)) => compare_states(left_state, right_state),
+            _ => Vec::new(),
+        };
+        let instructions = (records.0.as_ref().map(|record| record.instruction),
+                            records.1.as_ref().map(|record| record.instruction));
+        if instructions.0 != instructions.1 {
+            differences.insert(0, Difference::Instruction(instructions.0, instructions.1));
+        }
+        if left_fault != right_fault {
+            differences.push(Difference::Fault(left_fault, right_fault));
+        }
+        if !differences.is_empty() {
+            return Some(Divergence {
+                cycle: cycle,
+                records: records,
+                differences: differences,
+                states: (state_of(left).cloned(), state_of(right).cloned()),
+            });
+        }
+        if left_fault.is_some() {
+            return None;
+        }
+    }
+    None
+}
+
+/// Runs a machine against a recorded trace for at most limit cycles, returning the first cycle
+/// where they disagree
+///
+/// The trace is the left side. Records the trace's filter left out can't be compared, so the
+/// trace should be unfiltered and start from the same state and seed as the machine.
+pub fn diff_trace<I, T, A>(trace: I, live: &mut Chip8<T, A>, limit: u64) -> io::Result<Option<Divergence>>
+    where I: IntoIterator<Item = io::Result<TraceRecord>>,
+          T: KeyWrapper,
+          A: AudioWrapper
+{
+    let mut trace = trace.into_iter();
+    for cycle in 0..limit {
+        let recorded = match trace.next() {
+            Some(record) => Some(try!(record)),
+            None => return Ok(None),
+        };
+        let (live_record, fault) = step(live);
+        let mut differences = compare_records(&recorded, &live_record);
+        if let Some(fault) = fault {
+            differences.push(Difference::Fault(None, Some(fault)));
+        }
+        if !differences.is_empty() {
+            return Ok(Some(Divergence {
+                cycle: cycle,
+                records: (recorded, live_record),
+                differences: differences,
+                states: (None, state_of(live).cloned()),
+            }));
+        }
+    }
+    Ok(None)
+}